@@ -11,7 +11,7 @@ fn bench(c: &mut Criterion) {
                 TempDir::new().expect("Failed created temp directory in sled set benchmark");
 
             {
-                let mut sled = SledKvsEngine::open(temp_dir.path())
+                let sled = SledKvsEngine::open(temp_dir.path())
                     .expect("Failed creating kv store for kvs set benchmark");
                 for i in 0..2_000 {
                     sled.set(format!("key{}", i), format!("value{}", i))
@@ -19,7 +19,7 @@ fn bench(c: &mut Criterion) {
                 }
             }
 
-            let mut sled = SledKvsEngine::open(temp_dir.path())
+            let sled = SledKvsEngine::open(temp_dir.path())
                 .expect("Failed creating kv store after setting values");
 
             for i in 0..2_000 {
@@ -39,7 +39,7 @@ fn bench(c: &mut Criterion) {
                 TempDir::new().expect("Failed created temp directory in kvstore set benchmark");
 
             {
-                let mut kvstore = KvStore::open(temp_dir.path())
+                let kvstore = KvStore::open(temp_dir.path())
                     .expect("Failed creating kv store for kvs set benchmark");
                 for i in 0..2_000 {
                     kvstore
@@ -48,7 +48,7 @@ fn bench(c: &mut Criterion) {
                 }
             }
 
-            let mut kvstore = KvStore::open(temp_dir.path())
+            let kvstore = KvStore::open(temp_dir.path())
                 .expect("Failed creating kv store after setting values");
 
             for i in 0..2_000 {
@@ -0,0 +1,107 @@
+//! running a KvsServer on a thread of its own, to embed it in a program or start one per test
+
+use crate::thread_pool::{NaiveThreadPool, ThreadPool};
+use crate::{
+    EngineType, KvStore, KvsClient, KvsEngine, KvsError, KvsServer, Protocol, Result,
+    ShutdownHandle, SledKvsEngine,
+};
+use log::error;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::thread::{self, JoinHandle};
+
+/// a KvsServer running on a background thread, shut down and waited for when dropped
+pub struct BackgroundServer {
+    addr: SocketAddr,
+    shutdown: ShutdownHandle,
+    thread: Option<JoinHandle<Result<()>>>, // taken once the server has been waited for
+}
+
+impl BackgroundServer {
+    /// starts a server for `engine` keeping its data in `dir`, listening on a port of 127.0.0.1
+    /// the system picks
+    ///
+    /// every connection gets a thread of its own, so clients never wait for each other
+    pub fn start(engine: EngineType, dir: impl AsRef<Path>) -> Result<Self> {
+        Self::start_with_protocol(engine, dir, Protocol::Json)
+    }
+
+    /// like `start`, but serving clients that speak `protocol`
+    pub fn start_with_protocol(
+        engine: EngineType,
+        dir: impl AsRef<Path>,
+        protocol: Protocol,
+    ) -> Result<Self> {
+        let dir = dir.as_ref();
+        let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        let pool = NaiveThreadPool::new(0)?;
+        match EngineType::resolve(Some(engine), dir)? {
+            EngineType::Kvs => KvsServer::new(addr, KvStore::open(dir)?, pool)?
+                .with_protocol(protocol)
+                .spawn(),
+            EngineType::Sled => KvsServer::new(addr, SledKvsEngine::open(dir)?, pool)?
+                .with_protocol(protocol)
+                .spawn(),
+        }
+    }
+
+    pub(crate) fn spawn<E, P>(server: KvsServer<E, P>) -> Result<Self>
+    where
+        E: KvsEngine,
+        P: ThreadPool + Send + 'static,
+    {
+        let addr = server.local_addr()?;
+        let shutdown = server.shutdown_handle();
+        let thread = thread::Builder::new()
+            .name(format!("kvs-server {}", addr))
+            .spawn(move || server.run())?;
+
+        Ok(Self {
+            addr,
+            shutdown,
+            thread: Some(thread),
+        })
+    }
+
+    /// the address the server listens on
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// a client for the server
+    pub fn client(&self) -> KvsClient {
+        KvsClient::with_addr(self.addr)
+    }
+
+    /// a handle that shuts the server down without waiting for it
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// shuts the server down and waits for it, returning how it ended
+    pub fn stop(mut self) -> Result<()> {
+        self.stop_and_wait()
+    }
+
+    fn stop_and_wait(&mut self) -> Result<()> {
+        let thread = match self.thread.take() {
+            Some(thread) => thread,
+            None => return Ok(()),
+        };
+
+        self.shutdown.shutdown();
+        thread.join().unwrap_or_else(|_| {
+            Err(KvsError::Engine(String::from(
+                "server thread panicked while running",
+            )))
+        })
+    }
+}
+
+impl Drop for BackgroundServer {
+    fn drop(&mut self) {
+        if let Err(e) = self.stop_and_wait() {
+            error!("Failed stopping background server on {}: {}", self.addr, e);
+        }
+    }
+}
@@ -1,5 +1,6 @@
 use kvs::Command;
-use kvs::{KvsClient, Result};
+use kvs::{KeyTtl, KvsClient, KvsError, Result};
+use std::io::{self, Write};
 use std::net::SocketAddr;
 use structopt::StructOpt;
 
@@ -21,15 +22,46 @@ fn main() -> Result<()> {
             let get_result = kvs_client.send_command(command)?;
 
             if let Some(existing_get_result) = get_result {
-                println!("{}", existing_get_result);
+                // values may be arbitrary bytes, so they're written out as is
+                let mut stdout = io::stdout();
+                stdout.write_all(&existing_get_result)?;
+                stdout.write_all(b"\n")?;
             } else {
                 println!("Key not found");
             }
         }
         KvsClientCommand::Rm { key: _, addr } => {
             let kvs_client = KvsClient::with_addr(addr);
-            if let Err(_) = kvs_client.send_command(command) {
-                eprintln!("Key not found");
+            match kvs_client.send_command(command) {
+                Ok(_) => {}
+                Err(KvsError::KeyNotFound) => {
+                    eprintln!("Key not found");
+                    std::process::exit(1);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        KvsClientCommand::Setex {
+            key: _,
+            seconds: _,
+            value: _,
+            addr,
+        } => {
+            let kvs_client = KvsClient::with_addr(addr);
+            kvs_client.send_command(command)?;
+        }
+        KvsClientCommand::Ttl { key, addr } => {
+            let kvs_client = KvsClient::with_addr(addr);
+            match kvs_client.ttl(key.into_bytes())? {
+                KeyTtl::Missing => println!("Key not found"),
+                KeyTtl::NoExpiry => println!("No expiry"),
+                KeyTtl::ExpiresIn(ttl) => println!("{}", ttl.as_secs()),
+            }
+        }
+        KvsClientCommand::Persist { key, addr } => {
+            let kvs_client = KvsClient::with_addr(addr);
+            if !kvs_client.persist(key.into_bytes())? {
+                eprintln!("Key not found or has no expiry");
                 std::process::exit(1);
             }
         }
@@ -56,6 +88,26 @@ pub enum KvsClientCommand {
     Rm {
         key: String,
 
+        #[structopt(long, default_value = "127.0.0.1:4000")]
+        addr: SocketAddr,
+    },
+    Setex {
+        key: String,
+        seconds: u64,
+        value: String,
+
+        #[structopt(long, default_value = "127.0.0.1:4000")]
+        addr: SocketAddr,
+    },
+    Ttl {
+        key: String,
+
+        #[structopt(long, default_value = "127.0.0.1:4000")]
+        addr: SocketAddr,
+    },
+    Persist {
+        key: String,
+
         #[structopt(long, default_value = "127.0.0.1:4000")]
         addr: SocketAddr,
     },
@@ -69,14 +121,30 @@ impl From<&KvsClientCommand> for Command {
                 value,
                 addr: _,
             } => Command::Set {
-                key: key.to_owned(),
-                value: value.to_owned(),
+                key: key.as_bytes().to_vec(),
+                value: value.as_bytes().to_vec(),
             },
             KvsClientCommand::Get { key, addr: _ } => Command::Get {
-                key: key.to_owned(),
+                key: key.as_bytes().to_vec(),
             },
             KvsClientCommand::Rm { key, addr: _ } => Command::Remove {
-                key: key.to_owned(),
+                key: key.as_bytes().to_vec(),
+            },
+            KvsClientCommand::Setex {
+                key,
+                seconds,
+                value,
+                addr: _,
+            } => Command::SetEx {
+                key: key.as_bytes().to_vec(),
+                value: value.as_bytes().to_vec(),
+                ttl_secs: *seconds,
+            },
+            KvsClientCommand::Ttl { key, addr: _ } => Command::Ttl {
+                key: key.as_bytes().to_vec(),
+            },
+            KvsClientCommand::Persist { key, addr: _ } => Command::Persist {
+                key: key.as_bytes().to_vec(),
             },
         }
     }
@@ -1,31 +1,215 @@
-use kvs::{EngineType, KvsServer, Result};
+use kvs::thread_pool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    Durability, EngineType, KvStore, KvStoreOptions, KvsEngine, KvsServer, PoolType, Protocol,
+    Result, ServerConfig, ShutdownHandle, SledKvsEngine, SledOptions,
+};
 use log::info;
 use simplelog::{Config, LevelFilter, TermLogger, TerminalMode};
+use std::fs;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use structopt::StructOpt;
 
 fn main() -> Result<()> {
-    TermLogger::init(LevelFilter::Info, Config::default(), TerminalMode::Stderr)?;
-
     let server_command = KvsServerCommand::from_args();
+    let config = server_command.resolve_config()?;
+    if server_command.print_config {
+        print!("{}", config.to_toml()?);
+        return Ok(());
+    }
 
-    let kvs_server = KvsServer::new(server_command.addr, &server_command.engine)?;
+    // only fails if a logger is already set, and nothing else sets one
+    TermLogger::init(config.log_level, Config::default(), TerminalMode::Stderr)
+        .expect("logger already set");
 
+    let engine = config.engine.expect("engine resolved with the config");
+    let durability = config.sync.expect("sync resolved with the config");
     info!("version: {}", env!("CARGO_PKG_VERSION"));
     info!(
         "Engine {:?} running on {:?}",
-        server_command.engine.unwrap_or(EngineType::Kvs).to_string(), // TODO default shouldn't be hard coded here
-        server_command.addr
+        engine.to_string(),
+        config.addr
+    );
+    info!("Data directory: {}", config.data_dir.display());
+    info!("Sync policy: {}", durability);
+    info!("Protocol: {}", config.protocol);
+    info!(
+        "Thread pool: {} with {} threads",
+        config.pool,
+        config.threads.expect("threads resolved with the config")
     );
 
-    kvs_server.run()
+    fs::create_dir_all(&config.data_dir)?;
+    match engine {
+        EngineType::Kvs => run_with_pool(
+            &config,
+            KvStore::open_with_options(
+                &config.data_dir,
+                KvStoreOptions {
+                    durability,
+                    compaction_threshold: config.compaction_threshold,
+                    ..KvStoreOptions::default()
+                },
+            )?,
+        ),
+        EngineType::Sled => run_with_pool(
+            &config,
+            SledKvsEngine::open_with_durability(&config.data_dir, durability)?,
+        ),
+    }
+}
+
+fn run_with_pool(config: &ServerConfig, engine: impl KvsEngine) -> Result<()> {
+    let threads = config.threads.expect("threads resolved with the config");
+    match config.pool {
+        PoolType::Naive => run(config, engine, NaiveThreadPool::new(threads)?),
+        PoolType::SharedQueue => run(config, engine, SharedQueueThreadPool::new(threads)?),
+        PoolType::Rayon => run(config, engine, RayonThreadPool::new(threads)?),
+    }
+}
+
+fn run(config: &ServerConfig, engine: impl KvsEngine, pool: impl ThreadPool) -> Result<()> {
+    let mut server = KvsServer::new(config.addr, engine, pool)?.with_protocol(config.protocol);
+    if let Some(max) = config.max_connections {
+        server = server.with_max_connections(max);
+    }
+    handle_signals(server.shutdown_handle())?;
+    // the port bound, which is only known now when the system picked it
+    info!("Listening on {}", server.local_addr()?);
+    server.run()
+}
+
+// shuts the server down on SIGINT or SIGTERM, a second one exits without waiting
+#[cfg(unix)]
+fn handle_signals(shutdown: ShutdownHandle) -> Result<()> {
+    use log::warn;
+    use signal_hook::consts::{SIGINT, SIGTERM};
+    use signal_hook::iterator::Signals;
+    use std::{process, thread};
+
+    let mut signals = Signals::new([SIGINT, SIGTERM])?;
+    thread::Builder::new()
+        .name(String::from("signals"))
+        .spawn(move || {
+            for signal in signals.forever() {
+                if shutdown.is_shutdown() {
+                    warn!("Received signal {} again, exiting straight away", signal);
+                    process::exit(1);
+                }
+                info!("Received signal {}, shutting down", signal);
+                shutdown.shutdown();
+            }
+        })?;
+
+    Ok(())
+}
+
+// elsewhere the signals end the process as they always have
+#[cfg(not(unix))]
+fn handle_signals(_shutdown: ShutdownHandle) -> Result<()> {
+    Ok(())
 }
 
 #[derive(Debug, StructOpt)]
 struct KvsServerCommand {
-    #[structopt(long = "addr", default_value = "127.0.0.1:4000")]
-    addr: SocketAddr,
+    /// a TOML file holding the settings, the flags below override it
+    #[structopt(long = "config", parse(from_os_str))]
+    config: Option<PathBuf>,
+
+    /// print the settings the server would run with, as a config file, and exit
+    #[structopt(long = "print-config")]
+    print_config: bool,
+
+    /// the address to listen on, defaults to 127.0.0.1:4000
+    #[structopt(long = "addr")]
+    addr: Option<SocketAddr>,
 
     #[structopt(long = "engine")]
     engine: Option<EngineType>,
+
+    /// where the engine keeps its files, defaults to the current directory
+    #[structopt(long = "data-dir", parse(from_os_str))]
+    data_dir: Option<PathBuf>,
+
+    /// the share of unnecessary entries per live key past which kvs compacts its log
+    #[structopt(long = "compaction-threshold")]
+    compaction_threshold: Option<f32>,
+
+    /// when writes get fsynced: never, every-write, every-<n>ms or group-commit
+    #[structopt(long = "sync")]
+    sync: Option<Durability>,
+
+    /// the most verbose messages logged: off, error, warn, info, debug or trace
+    #[structopt(long = "log-level")]
+    log_level: Option<LevelFilter>,
+
+    /// the most connections served at once, more are closed as soon as they're accepted
+    #[structopt(long = "max-connections")]
+    max_connections: Option<usize>,
+
+    /// the thread pool requests run on: naive, shared-queue or rayon
+    #[structopt(long = "pool")]
+    pool: Option<PoolType>,
+
+    /// how many threads the pool runs, defaults to the number of CPUs
+    ///
+    /// a connection with a transaction open keeps one of them until the transaction ends
+    #[structopt(long = "threads")]
+    threads: Option<u32>,
+
+    /// the protocol clients speak: json, resp for Redis clients, memcached or http
+    #[structopt(long = "protocol")]
+    protocol: Option<Protocol>,
+}
+
+impl KvsServerCommand {
+    // the config file's settings with the flags given on top, and whatever is still unset worked
+    // out from the data directory and the machine
+    fn resolve_config(&self) -> Result<ServerConfig> {
+        let mut config = match &self.config {
+            Some(path) => ServerConfig::load(path)?,
+            None => ServerConfig::default(),
+        };
+
+        if let Some(addr) = self.addr {
+            config.addr = addr;
+        }
+        if self.engine.is_some() {
+            config.engine = self.engine;
+        }
+        if let Some(data_dir) = &self.data_dir {
+            config.data_dir = data_dir.clone();
+        }
+        if let Some(threshold) = self.compaction_threshold {
+            config.compaction_threshold = threshold;
+        }
+        if self.sync.is_some() {
+            config.sync = self.sync;
+        }
+        if let Some(log_level) = self.log_level {
+            config.log_level = log_level;
+        }
+        if self.max_connections.is_some() {
+            config.max_connections = self.max_connections;
+        }
+        if let Some(pool) = self.pool {
+            config.pool = pool;
+        }
+        if self.threads.is_some() {
+            config.threads = self.threads;
+        }
+        if let Some(protocol) = self.protocol {
+            config.protocol = protocol;
+        }
+        config.validate()?;
+
+        let engine = EngineType::resolve(config.engine, &config.data_dir)?;
+        config.engine = Some(engine);
+        config.sync = config.sync.or(Some(match engine {
+            EngineType::Kvs => KvStoreOptions::default().durability,
+            EngineType::Sled => SledOptions::default().durability,
+        }));
+        config.threads = config.threads.or(Some(num_cpus::get() as u32));
+        Ok(config)
+    }
 }
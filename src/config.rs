@@ -0,0 +1,184 @@
+//! the settings kvs-server runs with, as read from its TOML config file
+
+use crate::{Durability, EngineType, KvsError, PoolType, Protocol, Result, COMPACTION_THRESHOLD};
+use log::LevelFilter;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+/// how kvs-server is set up, settings a config file leaves out keep their defaults
+///
+/// values are spelled the way the matching command-line flags take them, `sync = "every-100ms"`
+/// or `engine = "sled"`. settings that are left unset are worked out when the server starts.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// the address to listen on
+    pub addr: SocketAddr,
+
+    /// the engine to run, the one already in `data_dir` or kvs when unset
+    #[serde(
+        with = "display_from_str::option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub engine: Option<EngineType>,
+
+    /// where the engine keeps its files
+    pub data_dir: PathBuf,
+
+    /// the share of unnecessary entries per live key past which kvs compacts its log
+    pub compaction_threshold: f32,
+
+    /// when writes get fsynced, the engine's own default when unset
+    #[serde(
+        with = "display_from_str::option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub sync: Option<Durability>,
+
+    /// the most verbose messages that get logged
+    #[serde(with = "log_level")]
+    pub log_level: LevelFilter,
+
+    /// the most connections served at once, more are closed as soon as they're accepted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_connections: Option<usize>,
+
+    /// the thread pool requests run on
+    #[serde(with = "display_from_str")]
+    pub pool: PoolType,
+
+    /// how many threads the pool runs, the number of CPUs when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub threads: Option<u32>,
+
+    /// the protocol clients speak
+    #[serde(with = "display_from_str")]
+    pub protocol: Protocol,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            addr: SocketAddr::from(([127, 0, 0, 1], 4000)),
+            engine: None,
+            data_dir: PathBuf::from("."),
+            compaction_threshold: COMPACTION_THRESHOLD,
+            sync: None,
+            log_level: LevelFilter::Info,
+            max_connections: None,
+            pool: PoolType::default(),
+            threads: None,
+            protocol: Protocol::default(),
+        }
+    }
+}
+
+impl ServerConfig {
+    /// reads a config file, a relative `data_dir` in it is taken to be relative to the file
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        let mut config: Self = toml::from_str(&contents).map_err(|e| {
+            KvsError::InvalidArgument(format!("config file {}: {}", path.display(), e))
+        })?;
+
+        if config.data_dir.is_relative() {
+            if let Some(config_dir) = path.parent() {
+                config.data_dir = config_dir.join(&config.data_dir);
+            }
+        }
+        Ok(config)
+    }
+
+    /// fails on settings no server could run with
+    pub fn validate(&self) -> Result<()> {
+        if !(self.compaction_threshold > 0.0 && self.compaction_threshold.is_finite()) {
+            return Err(KvsError::InvalidArgument(String::from(
+                "compaction_threshold must be a positive number",
+            )));
+        }
+        if self.max_connections == Some(0) || self.threads == Some(0) {
+            return Err(KvsError::InvalidArgument(String::from(
+                "max_connections and threads must be at least 1",
+            )));
+        }
+        Ok(())
+    }
+
+    /// the config as a TOML file `load` reads back
+    pub fn to_toml(&self) -> Result<String> {
+        toml::to_string(self).map_err(|e| KvsError::Serialization(e.to_string()))
+    }
+}
+
+// settings that have a `FromStr` for their flag are written the same way in the file
+pub(crate) mod display_from_str {
+    use serde::de::{Deserialize, Deserializer, Error};
+    use serde::ser::Serializer;
+    use std::fmt::Display;
+    use std::str::FromStr;
+
+    pub fn serialize<T: Display, S: Serializer>(
+        value: &T,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(value)
+    }
+
+    pub fn deserialize<'de, T, D>(deserializer: D) -> Result<T, D::Error>
+    where
+        T: FromStr,
+        T::Err: Display,
+        D: Deserializer<'de>,
+    {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(D::Error::custom)
+    }
+
+    pub mod option {
+        use serde::de::Deserializer;
+        use serde::ser::Serializer;
+        use std::fmt::Display;
+        use std::str::FromStr;
+
+        pub fn serialize<T, S>(value: &Option<T>, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            T: Display,
+            S: Serializer,
+        {
+            match value {
+                Some(value) => serializer.collect_str(value),
+                None => serializer.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+        where
+            T: FromStr,
+            T::Err: Display,
+            D: Deserializer<'de>,
+        {
+            super::deserialize(deserializer).map(Some)
+        }
+    }
+}
+
+// levels are written in lowercase, like the flag takes them
+mod log_level {
+    use log::LevelFilter;
+    use serde::de::{Deserialize, Deserializer, Error};
+    use serde::ser::Serializer;
+
+    pub fn serialize<S: Serializer>(level: &LevelFilter, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&level.to_string().to_lowercase())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<LevelFilter, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(D::Error::custom)
+    }
+}
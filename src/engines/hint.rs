@@ -0,0 +1,216 @@
+//! hint files let KvStore rebuild its index without replaying the whole log
+//!
+//! a hint holds the index as it stood at some point in the log, everything written after that
+//! point still has to be replayed:
+//!
+//! | magic (4) | version (1) | replay gen (8) | replay pos (8) | unnecessary entries (8) |
+//! | last seq (8) | entry count (8) | entries... | history count (8) | histories... | crc32 (4) |
+//!
+//! where every entry is | key len (4) | key | position |, every history is
+//! | key len (4) | key | version count (4) | versions... | with every version | position | removed (1) |
+//! and a position is | gen (8) | pos (8) | len (8) | expires at (8) | seq (8) | written at (8) |.
+//! integers are little endian, expires at is 0 for keys that never expire and the checksum covers
+//! everything before it.
+
+use super::kvs::{Index, PastVersion};
+use crate::{CommandPos, KvsError, Result};
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+const HINT_MAGIC: [u8; 4] = *b"KVHT";
+const HINT_VERSION: u8 = 3;
+
+/// the index as of a position in the log
+pub(crate) struct Hint {
+    /// segment replay has to resume in
+    pub(crate) replay_gen: u64,
+
+    /// where in `replay_gen` replay has to resume
+    pub(crate) replay_pos: u64,
+
+    /// how many commands in the covered part of the log have since been overwritten or removed
+    pub(crate) num_unnecessary_entries: usize,
+
+    /// sequence number of the last write in the covered part of the log
+    pub(crate) last_seq: u64,
+
+    pub(crate) index: Index,
+}
+
+pub(crate) fn hint_path(dir: &Path) -> PathBuf {
+    dir.join("index.hint")
+}
+
+/// atomically replaces the hint file
+pub(crate) fn write_hint(
+    dir: &Path,
+    replay_gen: u64,
+    replay_pos: u64,
+    num_unnecessary_entries: usize,
+    last_seq: u64,
+    index: &Index,
+) -> Result<()> {
+    let temp_path = dir.join("index.hint.tmp");
+    let mut writer = BufWriter::new(File::create(&temp_path)?);
+    let mut hasher = crc32fast::Hasher::new();
+
+    let mut put = |bytes: &[u8]| -> Result<()> {
+        hasher.update(bytes);
+        writer.write_all(bytes)?;
+        Ok(())
+    };
+
+    put(&HINT_MAGIC)?;
+    put(&[HINT_VERSION])?;
+    put(&replay_gen.to_le_bytes())?;
+    put(&replay_pos.to_le_bytes())?;
+    put(&(num_unnecessary_entries as u64).to_le_bytes())?;
+    put(&last_seq.to_le_bytes())?;
+
+    let put_position = |put: &mut dyn FnMut(&[u8]) -> Result<()>, command_pos: &CommandPos| {
+        put(&command_pos.gen.to_le_bytes())?;
+        put(&command_pos.pos.to_le_bytes())?;
+        put(&command_pos.len.to_le_bytes())?;
+        put(&command_pos.expires_at.unwrap_or(0).to_le_bytes())?;
+        put(&command_pos.seq.to_le_bytes())?;
+        put(&command_pos.written_at.to_le_bytes())
+    };
+
+    put(&(index.latest.len() as u64).to_le_bytes())?;
+    for (key, command_pos) in &index.latest {
+        put(&(key.len() as u32).to_le_bytes())?;
+        put(key)?;
+        put_position(&mut put, command_pos)?;
+    }
+
+    put(&(index.history.len() as u64).to_le_bytes())?;
+    for (key, versions) in &index.history {
+        put(&(key.len() as u32).to_le_bytes())?;
+        put(key)?;
+        put(&(versions.len() as u32).to_le_bytes())?;
+        for version in versions {
+            put_position(&mut put, &version.pos)?;
+            put(&[version.removed as u8])?;
+        }
+    }
+
+    writer.write_all(&hasher.finalize().to_le_bytes())?;
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    drop(writer);
+
+    fs::rename(temp_path, hint_path(dir))?;
+    Ok(())
+}
+
+/// reads the hint file, `None` if there isn't one
+pub(crate) fn read_hint(dir: &Path) -> Result<Option<Hint>> {
+    let path = hint_path(dir);
+    if !path.is_file() {
+        return Ok(None);
+    }
+
+    let bytes = fs::read(path)?;
+    if bytes.len() < 4 {
+        return Err(KvsError::CorruptLog(String::from("hint file is truncated")));
+    }
+
+    let (body, crc) = bytes.split_at(bytes.len() - 4);
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(body);
+    if hasher.finalize().to_le_bytes() != crc {
+        return Err(KvsError::CorruptLog(String::from(
+            "hint file checksum mismatch",
+        )));
+    }
+
+    let mut cursor = Cursor(body);
+    if cursor.take(4)? != HINT_MAGIC {
+        return Err(KvsError::CorruptLog(String::from(
+            "hint file has bad magic",
+        )));
+    }
+    let version = cursor.take(1)?[0];
+    if version != HINT_VERSION {
+        return Err(KvsError::CorruptLog(format!(
+            "unsupported hint file version {}",
+            version
+        )));
+    }
+
+    let replay_gen = cursor.u64()?;
+    let replay_pos = cursor.u64()?;
+    let num_unnecessary_entries = cursor.u64()? as usize;
+    let last_seq = cursor.u64()?;
+
+    let mut index = Index::default();
+    for _ in 0..cursor.u64()? {
+        let key = cursor.key()?;
+        index.latest.insert(key, cursor.position()?);
+    }
+
+    for _ in 0..cursor.u64()? {
+        let key = cursor.key()?;
+        let num_versions = cursor.u32()? as usize;
+        let mut versions = VecDeque::with_capacity(num_versions);
+        for _ in 0..num_versions {
+            versions.push_back(PastVersion {
+                pos: cursor.position()?,
+                removed: cursor.take(1)?[0] != 0,
+            });
+        }
+        index.history.insert(key, versions);
+    }
+
+    Ok(Some(Hint {
+        replay_gen,
+        replay_pos,
+        num_unnecessary_entries,
+        last_seq,
+        index,
+    }))
+}
+
+struct Cursor<'a>(&'a [u8]);
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.0.len() < n {
+            return Err(KvsError::CorruptLog(String::from("hint file is truncated")));
+        }
+
+        let (taken, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let mut buf = [0; 4];
+        buf.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        let mut buf = [0; 8];
+        buf.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    fn key(&mut self) -> Result<Vec<u8>> {
+        let key_len = self.u32()? as usize;
+        Ok(self.take(key_len)?.to_vec())
+    }
+
+    fn position(&mut self) -> Result<CommandPos> {
+        Ok(CommandPos {
+            gen: self.u64()?,
+            pos: self.u64()?,
+            len: self.u64()?,
+            expires_at: Some(self.u64()?).filter(|&at| at != 0),
+            seq: self.u64()?,
+            written_at: self.u64()?,
+        })
+    }
+}
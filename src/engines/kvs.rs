@@ -1,187 +1,1373 @@
-use crate::{Command, CommandPos, KvsEngine, Result, COMPACTION_THRESHOLD};
-use failure::format_err;
-use std::collections::HashMap;
+use super::hint::{self, Hint};
+use super::manifest;
+use super::record::{self, ReadRecord, Record};
+use super::syncer::LogSyncer;
+use super::{now_millis, num_expendable, system_time, unix_millis, PeriodicTask};
+use crate::{
+    BatchOp, CommandPos, Durability, EngineType, KeyRange, KeyTtl, Keys, KvPairs, KvsEngine,
+    KvsError, KvsSnapshot, ReadAt, Result, Retention, SetCondition, SetOutcome, Transaction,
+    Version, WriteBatch, COMPACTION_THRESHOLD, MAX_SEGMENT_SIZE,
+};
+use log::{error, warn};
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::ffi::OsStr;
 use std::fs;
 use std::fs::{File, OpenOptions};
 use std::io;
-use std::io::{BufRead, BufReader, BufWriter, Read, Seek, Write};
-use std::path::PathBuf;
+use std::io::{BufReader, BufWriter, Read, Seek, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// the version of the directory layout KvStore writes, recorded in its MANIFEST. bumped whenever
+/// an older build would misread what's written
+pub(crate) const FORMAT_VERSION: u32 = 1;
 
 /// holds the key value pairings
+///
+/// the log is split into segments named `<gen>.log`, a new segment is started once the active one
+/// grows past `MAX_SEGMENT_SIZE`. compaction runs on a background thread while new commands keep
+/// being appended to the active segment. compaction and a clean shutdown leave behind a hint file
+/// so the next open only has to replay the log written after it.
+///
+/// keys set with a time to live have their expiry written to the log along with the value. expired
+/// keys read as absent straight away and are physically dropped by the next compaction.
+///
+/// a snapshot holds a copy of the index and pins the segments it points to, a compaction that
+/// replaces them leaves them on disk until the last snapshot reading from them is dropped.
+///
+/// every write is stamped with the next sequence number and the time it was made. older versions
+/// of a key stay in the log, and in the index, for as long as the retention policy keeps them.
+///
+/// clones share the same store. reads only take the index's read lock and read the segments at
+/// an offset, so any number of them run at once; writes are appended one at a time.
+#[derive(Clone)]
 pub struct KvStore {
-    log_writer: BufWriterWithPosition<File>,
-    log_reader: BufReader<File>,
-    index: HashMap<String, CommandPos>,
-    num_unnecessary_entries: usize,
+    shared: Arc<Shared>,
+    writer: Arc<Mutex<KvStoreWriter>>, // the last clone to go writes the hint
+}
+
+// what readers need, shared by every clone, snapshot and the compaction thread
+struct Shared {
     path: PathBuf, // the path it was initially opened with
+    index: RwLock<Index>,
+    segments: RwLock<HashMap<u64, Arc<File>>>, // gen -> handle to read that segment at an offset
+    num_unnecessary_entries: AtomicUsize,
+    pins: Mutex<Pins>,
+    retention: Retention,
+    syncer: Arc<LogSyncer>,
+}
+
+// segments open snapshots still read from
+#[derive(Default)]
+struct Pins {
+    counts: HashMap<u64, usize>, // gen -> how many snapshots point into it
+    obsolete: BTreeSet<u64>,     // compacted away, deleted once no snapshot points into them
+}
+
+// what only writers touch, behind the store's writer lock
+struct KvStoreWriter {
+    log_writer: BufWriterWithPosition<File>,
+    last_seq: u64,    // sequence number of the latest write
+    current_gen: u64, // the segment new commands are appended to
+    compaction_threshold: f32,
+    compaction: Option<JoinHandle<Result<()>>>, // the running (or last) compaction
+    shared: Arc<Shared>,
+    _interval_syncer: Option<PeriodicTask>, // only running for `Durability::EveryN`
+}
+
+/// options controlling how a KvStore is opened
+#[derive(Debug, Clone)]
+pub struct KvStoreOptions {
+    /// refuse to open when the log ends in a torn or corrupt record instead of cutting the log
+    /// back to the last good record
+    pub strict: bool,
+
+    /// when writes get fsynced
+    pub durability: Durability,
+
+    /// how many old versions of every key are kept
+    pub retention: Retention,
+
+    /// the share of unnecessary entries per live key past which the log is compacted
+    pub compaction_threshold: f32,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        Self {
+            strict: false,
+            durability: Durability::default(),
+            retention: Retention::default(),
+            compaction_threshold: COMPACTION_THRESHOLD,
+        }
+    }
+}
+
+impl KvStoreOptions {
+    // the options as written to the MANIFEST
+    fn describe(&self) -> BTreeMap<String, String> {
+        let mut options = BTreeMap::new();
+        options.insert(String::from("strict"), self.strict.to_string());
+        options.insert(String::from("durability"), self.durability.to_string());
+        options.insert(String::from("retention"), format!("{:?}", self.retention));
+        options.insert(
+            String::from("compaction_threshold"),
+            self.compaction_threshold.to_string(),
+        );
+        options
+    }
+}
+
+/// where the current version of every key is, along with the older versions still kept
+#[derive(Clone, Default)]
+pub(crate) struct Index {
+    pub(crate) latest: BTreeMap<Vec<u8>, CommandPos>,
+    pub(crate) history: BTreeMap<Vec<u8>, VecDeque<PastVersion>>, // oldest first
+}
+
+/// a version of a key that's no longer current
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct PastVersion {
+    pub(crate) pos: CommandPos, // the set or remove that made the version
+    pub(crate) removed: bool,
+}
+
+impl Index {
+    // every kept version of a key, oldest first and the current one last
+    fn versions(&self, key: &[u8]) -> Vec<PastVersion> {
+        let mut versions = self
+            .history
+            .get(key)
+            .into_iter()
+            .flatten()
+            .copied()
+            .collect::<Vec<_>>();
+        versions.extend(self.latest.get(key).map(|&pos| PastVersion {
+            pos,
+            removed: false,
+        }));
+        versions
+    }
+
+    // makes `version` the current version of `key`, returns how many versions no longer need
+    // keeping because of it
+    fn apply(
+        &mut self,
+        key: Vec<u8>,
+        version: PastVersion,
+        retention: Retention,
+        now: u64,
+    ) -> usize {
+        let history = self.history.entry(key.clone()).or_default();
+        if let Some(pos) = self.latest.remove(&key) {
+            history.push_back(PastVersion {
+                pos,
+                removed: false,
+            });
+        }
+
+        if version.removed {
+            history.push_back(version);
+        } else {
+            self.latest.insert(key.clone(), version.pos);
+        }
+
+        self.trim(&key, retention, now)
+    }
+
+    // points the index at a set or remove found at `pos`, returns how many versions no longer
+    // need keeping because of it
+    fn apply_record(
+        &mut self,
+        record: Record,
+        gen: u64,
+        pos: u64,
+        len: u64,
+        retention: Retention,
+        now: u64,
+    ) -> usize {
+        let (key, version) = match record {
+            Record::Set {
+                key,
+                expires_at,
+                seq,
+                written_at,
+                ..
+            } => {
+                let pos = CommandPos {
+                    gen,
+                    pos,
+                    len,
+                    expires_at,
+                    seq,
+                    written_at,
+                };
+                // when only the current value is kept, one that has expired since is as good as
+                // removed
+                let removed = retention == Retention::Latest && is_expired(&pos, now);
+                (key, PastVersion { pos, removed })
+            }
+            Record::Remove {
+                key,
+                seq,
+                written_at,
+            } => {
+                let pos = CommandPos {
+                    gen,
+                    pos,
+                    len,
+                    expires_at: None,
+                    seq,
+                    written_at,
+                };
+                (key, PastVersion { pos, removed: true })
+            }
+            Record::Batch(_) => {
+                unreachable!("batches are split into their records before indexing")
+            }
+        };
+
+        self.apply(key, version, retention, now)
+    }
+
+    // lets go of the old versions of a key the retention policy doesn't keep, returns how many
+    fn trim(&mut self, key: &[u8], retention: Retention, now: u64) -> usize {
+        let history = match self.history.get_mut(key) {
+            Some(history) => history,
+            None => return 0,
+        };
+
+        let mut written_at = history
+            .iter()
+            .map(|version| version.pos.written_at)
+            .collect::<Vec<_>>();
+        written_at.extend(self.latest.get(key).map(|pos| pos.written_at));
+
+        let mut num_dropped = num_expendable(retention, &written_at, now).min(history.len());
+        history.drain(..num_dropped);
+
+        // a removal with nothing kept before it reads the same as no version at all
+        while history.front().is_some_and(|version| version.removed) {
+            history.pop_front();
+            num_dropped += 1;
+        }
+
+        if history.is_empty() {
+            self.history.remove(key);
+        }
+
+        num_dropped
+    }
+
+    fn trim_all(&mut self, retention: Retention, now: u64) {
+        let keys = self.history.keys().cloned().collect::<Vec<_>>();
+        for key in keys {
+            self.trim(&key, retention, now);
+        }
+    }
 }
 
 impl KvStore {
-    /// create a kv store at a certain path (kvs.log will be created here)
+    /// create a kv store at a certain path (log segments will be created here)
     pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        Self::open_with_options(path, KvStoreOptions::default())
+    }
+
+    /// create a kv store at a certain path with non default options
+    pub fn open_with_options(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<KvStore> {
         let path: PathBuf = path.into();
 
         fs::create_dir_all(&path)?;
+        manifest::check_or_create(&path, EngineType::Kvs, FORMAT_VERSION, options.describe())?;
 
-        let log_file = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .read(true)
-            .open(path.join("kvs.log"))?;
+        remove_unfinished_compactions(&path)?;
+        let mut gens = sorted_gen_list(&path)?;
 
-        let mut index = HashMap::new();
+        // stores written before segmenting kept everything in a single kvs.log
+        let legacy_log = path.join("kvs.log");
+        if gens.is_empty() && legacy_log.is_file() {
+            fs::rename(&legacy_log, log_path(&path, 1))?;
+            gens.push(1);
+        }
 
+        let mut index = Index::default();
         let mut num_unnecessary_entries = 0;
+        let mut last_seq = 0;
+        let (mut replay_gen, mut replay_pos) = (0, 0);
 
-        let log_reader = BufReader::new(log_file.try_clone()?);
-        let mut bytes_read = 0;
-        for line in log_reader.lines() {
-            let line = line?;
-
-            let cmd: Command = serde_json::from_str(&line)?;
-            let cmd_len = line.len() as u64;
+        match hint::read_hint(&path) {
+            Ok(Some(hint)) if is_hint_current(&path, &gens, &hint)? => {
+                // segments older than anything the hint needs were compacted away while a
+                // snapshot still read from them, and the process exited before it was dropped
+                let keep_from = hint_gens(&hint).fold(hint.replay_gen, u64::min);
+                for gen in gens.iter().filter(|&&gen| gen < keep_from) {
+                    fs::remove_file(log_path(&path, *gen))?;
+                }
+                gens.retain(|&gen| gen >= keep_from);
 
-            match cmd {
-                Command::Set { key, value: _ } => {
-                    if index.contains_key(&key) {
-                        num_unnecessary_entries += 1;
-                    }
+                index = hint.index;
+                num_unnecessary_entries = hint.num_unnecessary_entries;
+                last_seq = hint.last_seq;
+                replay_gen = hint.replay_gen;
+                replay_pos = hint.replay_pos;
+            }
+            Ok(Some(_)) => warn!("Hint file in {:?} is stale, replaying the whole log", path),
+            Ok(None) => {}
+            Err(e) => warn!(
+                "Ignoring hint file in {:?} ({}), replaying the whole log",
+                path, e
+            ),
+        }
 
-                    index.insert(
-                        key,
-                        CommandPos {
-                            pos: bytes_read,
-                            len: cmd_len,
-                        },
-                    )
-                }
-                Command::Remove { key } => index.remove(&key),
-                Command::Get { key: _ } => unreachable!(),
-            };
+        for &gen in gens.iter().filter(|&&gen| gen >= replay_gen) {
+            // only the segment being appended to when the process died can end in a torn record
+            let recover_tail = !options.strict && Some(&gen) == gens.last();
+            let start = if gen == replay_gen { replay_pos } else { 0 };
 
-            bytes_read += cmd_len + 1; // because of the newline separating commands
+            let mut log_reader = BufReader::new(File::open(log_path(&path, gen))?);
+            let (num_unnecessary, max_seq) = load(
+                &path,
+                gen,
+                &mut log_reader,
+                start,
+                &mut index,
+                options.retention,
+                recover_tail,
+            )?;
+            num_unnecessary_entries += num_unnecessary;
+            last_seq = last_seq.max(max_seq);
         }
 
-        Ok(Self {
-            log_writer: BufWriterWithPosition::new(log_file.try_clone()?, bytes_read),
-            log_reader: BufReader::new(log_file),
-            index,
-            num_unnecessary_entries,
+        // keep appending to the newest segment, it gets rolled over once it's full
+        let current_gen = gens.last().cloned().unwrap_or(1);
+        let log_writer = new_log_file(&path, current_gen)?;
+
+        let syncer = Arc::new(LogSyncer::new(
+            options.durability,
+            log_writer.get_ref().try_clone()?,
+        ));
+        let interval_syncer = LogSyncer::spawn_interval_syncer(&syncer);
+
+        let shared = Arc::new(Shared {
             path,
+            index: RwLock::new(index),
+            segments: RwLock::new(HashMap::new()),
+            num_unnecessary_entries: AtomicUsize::new(num_unnecessary_entries),
+            pins: Mutex::new(Pins::default()),
+            retention: options.retention,
+            syncer,
+        });
+
+        let writer = KvStoreWriter {
+            log_writer,
+            last_seq,
+            current_gen,
+            compaction_threshold: options.compaction_threshold,
+            compaction: None,
+            shared: Arc::clone(&shared),
+            _interval_syncer: interval_syncer,
+        };
+
+        Ok(Self {
+            shared,
+            writer: Arc::new(Mutex::new(writer)),
         })
     }
 
-    fn should_compact(&self) -> bool {
-        self.num_unnecessary_entries as f32 / self.index.len() as f32 > COMPACTION_THRESHOLD
+    fn writer(&self) -> MutexGuard<'_, KvStoreWriter> {
+        self.writer.lock().expect("KvStore writer lock poisoned")
     }
 
-    fn compact(&mut self) -> Result<()> {
-        let mut new_log_file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .open(self.path.join("kvs_temp.log"))?;
+    // the writer lock is let go of before waiting, so writes made meanwhile can share an fsync
+    fn wait_durable(&self, ticket: u64) -> Result<()> {
+        self.shared.syncer.wait_durable(ticket)
+    }
+}
+
+impl Shared {
+    fn index(&self) -> std::sync::RwLockReadGuard<'_, Index> {
+        self.index.read().expect("KvStore index lock poisoned")
+    }
 
-        for (_, command_pos) in self.index.iter() {
-            let local_reader = &mut self.log_reader;
+    fn index_mut(&self) -> std::sync::RwLockWriteGuard<'_, Index> {
+        self.index.write().expect("KvStore index lock poisoned")
+    }
+
+    // the handle for a segment, opening it if this is the first read from it
+    fn segment(&self, gen: u64) -> Result<Arc<File>> {
+        if let Some(file) = self
+            .segments
+            .read()
+            .expect("KvStore segments lock poisoned")
+            .get(&gen)
+        {
+            return Ok(Arc::clone(file));
+        }
 
-            local_reader.seek(io::SeekFrom::Start(command_pos.pos))?; // offset reader's cursor to start of the desired command
-            let mut cmd_reader = local_reader.take(command_pos.len);
+        let mut segments = self
+            .segments
+            .write()
+            .expect("KvStore segments lock poisoned");
+        if let Some(file) = segments.get(&gen) {
+            return Ok(Arc::clone(file));
+        }
+        let file = Arc::new(File::open(log_path(&self.path, gen))?);
+        segments.insert(gen, Arc::clone(&file));
+        Ok(file)
+    }
 
-            let mut command = String::new();
-            cmd_reader.read_to_string(&mut command)?;
+    // drops the handles of segments older than `gen`
+    fn close_before(&self, gen: u64) {
+        self.segments
+            .write()
+            .expect("KvStore segments lock poisoned")
+            .retain(|&segment_gen, _| segment_gen >= gen);
+    }
+
+    fn pins(&self) -> MutexGuard<'_, Pins> {
+        self.pins.lock().expect("KvStore pins lock poisoned")
+    }
+
+    fn pin(&self, gens: &BTreeSet<u64>) {
+        let mut pins = self.pins();
+        for &gen in gens {
+            *pins.counts.entry(gen).or_insert(0) += 1;
+        }
+    }
 
-            if let command @ Command::Set { key: _, value: _ } = serde_json::from_str(&command)? {
-                // write to temp log file
-                serde_json::to_writer(&mut new_log_file, &command)?;
-                writeln!(&mut new_log_file)?;
-            } else {
-                panic!(
-                    "When compacting, index did of a key did not point to a SET command in the log"
-                )
+    // lets go of segments a snapshot pinned, deleting the compacted away ones nothing else pins
+    fn unpin(&self, gens: &BTreeSet<u64>) -> Result<()> {
+        let mut pins = self.pins();
+        for &gen in gens {
+            let count = pins.counts.entry(gen).or_insert(1);
+            *count -= 1;
+            if *count > 0 {
+                continue;
+            }
+            pins.counts.remove(&gen);
+            if pins.obsolete.remove(&gen) {
+                self.segments
+                    .write()
+                    .expect("KvStore segments lock poisoned")
+                    .remove(&gen);
+                fs::remove_file(log_path(&self.path, gen))?;
             }
         }
+        Ok(())
+    }
 
-        new_log_file.flush()?;
+    // deletes segments a compaction replaced, leaving the pinned ones to the last snapshot
+    // reading from them
+    fn remove_compacted(&self, gens: impl IntoIterator<Item = u64>) -> Result<()> {
+        let mut pins = self.pins();
+        for gen in gens {
+            if pins.counts.contains_key(&gen) {
+                pins.obsolete.insert(gen);
+                continue;
+            }
+            match fs::remove_file(log_path(&self.path, gen)) {
+                // one an earlier compaction left to a snapshot that has just let go of it
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                result => result?,
+            }
+        }
+        Ok(())
+    }
 
-        // don't need the old log file now, rename to kvs.log thereby replacing the old log file
-        fs::rename(self.path.join("kvs_temp.log"), self.path.join("kvs.log"))?;
+    // reads and checks the record the index points to
+    //
+    // callers hold the index lock while reading so a compaction can't delete the segment
+    // underneath them
+    fn read_record(&self, command_pos: &CommandPos) -> Result<Record> {
+        let mut buf = vec![0; command_pos.len as usize];
+        read_exact_at(&*self.segment(command_pos.gen)?, &mut buf, command_pos.pos)?;
 
-        let mut new_store = Self::open(&self.path)?;
+        Record::read_from(&buf)?
+            .map(|(record, _)| record)
+            .ok_or_else(|| {
+                KvsError::CorruptLog(String::from("Index pointed past the end of log segment"))
+            })
+    }
 
-        std::mem::swap(self, &mut new_store);
+    // reads the value written by the set the index points to
+    fn read_value(&self, command_pos: &CommandPos) -> Result<Vec<u8>> {
+        match self.read_record(command_pos)? {
+            Record::Set { value, .. } => Ok(value),
+            _ => Err(KvsError::CorruptLog(String::from(
+                "Index pointed to a command without a value",
+            ))),
+        }
+    }
+
+    // where the live value of a key is
+    fn live_command_pos(&self, key: &[u8]) -> Option<CommandPos> {
+        let command_pos = *self.index().latest.get(key)?;
+
+        if is_expired(&command_pos, now_millis()) {
+            self.forget_expired(key, command_pos);
+            return None;
+        }
+
+        Some(command_pos)
+    }
+
+    // when only the current value is kept an expired key is dropped from the index once it's
+    // noticed, unless it was written again in the meantime
+    fn forget_expired(&self, key: &[u8], command_pos: CommandPos) {
+        if self.retention != Retention::Latest {
+            return;
+        }
+
+        let mut index = self.index_mut();
+        if index.latest.get(key) == Some(&command_pos) {
+            index.latest.remove(key);
+            self.num_unnecessary_entries.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+}
+
+impl KvStoreWriter {
+    fn should_compact(&self, num_live_entries: usize) -> bool {
+        let num_unnecessary_entries = self.shared.num_unnecessary_entries.load(Ordering::SeqCst);
+        num_live_entries > 0
+            && num_unnecessary_entries as f32 / num_live_entries as f32 > self.compaction_threshold
+    }
+
+    fn roll_over_if_full(&mut self) -> Result<()> {
+        if self.log_writer.num_bytes_written >= MAX_SEGMENT_SIZE {
+            self.log_writer.flush()?;
+            self.current_gen += 1;
+            self.log_writer = new_log_file(&self.shared.path, self.current_gen)?;
+            self.shared
+                .syncer
+                .roll_over(self.log_writer.get_ref().try_clone()?)?;
+        }
 
         Ok(())
     }
+
+    // whether a compaction thread is still working
+    fn is_compacting(&mut self) -> bool {
+        match &self.compaction {
+            Some(handle) if !handle.is_finished() => true,
+            Some(_) => {
+                self.finish_compaction();
+                false
+            }
+            None => false,
+        }
+    }
+
+    // waits for the compaction thread, a failed compaction leaves the old segments in place so
+    // it's only reported
+    fn finish_compaction(&mut self) {
+        if let Some(handle) = self.compaction.take() {
+            match handle.join() {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => error!("Compaction of {:?} failed: {}", self.shared.path, e),
+                Err(_) => error!("Compaction thread of {:?} panicked", self.shared.path),
+            }
+        }
+    }
+
+    // seals the active segment and hands every older segment to a compaction thread
+    fn start_compaction(&mut self) -> Result<()> {
+        // the compacted segment sits between the old segments and the new active one so replaying
+        // segments in order still ends up with the latest value of every key
+        let compaction_gen = self.current_gen + 1;
+        self.log_writer.flush()?;
+        self.current_gen += 2;
+        self.log_writer = new_log_file(&self.shared.path, self.current_gen)?;
+        self.shared
+            .syncer
+            .roll_over(self.log_writer.get_ref().try_clone()?)?;
+
+        self.shared
+            .num_unnecessary_entries
+            .store(0, Ordering::SeqCst);
+
+        let shared = Arc::clone(&self.shared);
+        let last_seq = self.last_seq;
+        self.compaction = Some(thread::spawn(move || {
+            compact(&shared, compaction_gen, last_seq)
+        }));
+
+        Ok(())
+    }
+
+    fn next_seq(&mut self) -> u64 {
+        self.last_seq += 1;
+        self.last_seq
+    }
+
+    fn write_set(&mut self, key: &[u8], value: &[u8], expires_at: Option<u64>) -> Result<u64> {
+        let record = Record::Set {
+            key: key.to_vec(),
+            value: value.to_vec(),
+            expires_at,
+            seq: self.next_seq(),
+            written_at: now_millis(),
+        };
+        self.append(record)
+    }
+
+    // writes a non-empty batch as one record
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<u64> {
+        let written_at = now_millis();
+        let records = batch
+            .ops()
+            .iter()
+            .map(|op| match op {
+                BatchOp::Set { key, value } => Record::Set {
+                    key: key.clone(),
+                    value: value.clone(),
+                    expires_at: None,
+                    seq: self.next_seq(),
+                    written_at,
+                },
+                BatchOp::SetWithTtl { key, value, ttl } => Record::Set {
+                    key: key.clone(),
+                    value: value.clone(),
+                    expires_at: Some(written_at.saturating_add(ttl.as_millis() as u64)),
+                    seq: self.next_seq(),
+                    written_at,
+                },
+                BatchOp::Remove { key } => Record::Remove {
+                    key: key.clone(),
+                    seq: self.next_seq(),
+                    written_at,
+                },
+            })
+            .collect::<Vec<_>>();
+
+        // one record for the whole batch, a crash part way through writing it tears the record
+        // and the whole batch gets cut off on the next open
+        self.append(Record::Batch(records))
+    }
+
+    // writes a set, remove or batch to the log and points the index at it, returns the ticket to
+    // wait on for it to be durable
+    fn append(&mut self, record: Record) -> Result<u64> {
+        let pos = self.log_writer.num_bytes_written;
+        let positions = match &record {
+            Record::Batch(records) => record::batch_positions(records, pos),
+            _ => Vec::new(),
+        };
+
+        let len = record.write_to(&mut self.log_writer)?;
+        let ticket = self.shared.syncer.written();
+
+        let num_live_entries = {
+            let (gen, retention, now) = (self.current_gen, self.shared.retention, now_millis());
+            let mut index = self.shared.index_mut();
+            let mut num_unnecessary = 0;
+            match record {
+                Record::Batch(records) => {
+                    for (record, (pos, len)) in records.into_iter().zip(positions) {
+                        num_unnecessary +=
+                            index.apply_record(record, gen, pos, len, retention, now);
+                    }
+                }
+                record => {
+                    num_unnecessary += index.apply_record(record, gen, pos, len, retention, now);
+                }
+            }
+            self.shared
+                .num_unnecessary_entries
+                .fetch_add(num_unnecessary, Ordering::SeqCst);
+            index.latest.len()
+        };
+
+        self.roll_over_if_full()?;
+
+        if self.should_compact(num_live_entries) && !self.is_compacting() {
+            self.start_compaction()?;
+        }
+
+        Ok(ticket)
+    }
 }
 
 impl KvsEngine for KvStore {
-    fn get(&mut self, key: String) -> Result<Option<String>> {
-        let command_pos = self.index.get(&key);
+    fn scan(&self, range: KeyRange) -> Result<KvPairs<'_>> {
+        let (start, end) = range;
+        Ok(Box::new(KvStoreScan {
+            shared: &self.shared,
+            next: start,
+            end,
+        }))
+    }
+
+    fn scan_keys(&self, range: KeyRange) -> Result<Keys<'_>> {
+        let (start, end) = range;
+        Ok(Box::new(KvStoreKeys {
+            shared: &self.shared,
+            next: start,
+            end,
+        }))
+    }
 
-        if let Some(command_pos) = command_pos {
-            let local_reader = &mut self.log_reader;
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.get_with_seq(key)?.map(|(value, _)| value))
+    }
 
-            local_reader.seek(io::SeekFrom::Start(command_pos.pos))?; // offset reader's cursor to start of the desired command
-            let mut cmd_reader = local_reader.take(command_pos.len);
+    fn get_with_seq(&self, key: &[u8]) -> Result<Option<(Vec<u8>, u64)>> {
+        let index = self.shared.index();
+        let command_pos = match index.latest.get(key) {
+            Some(command_pos) => *command_pos,
+            None => return Ok(None),
+        };
 
-            let mut command = String::new();
-            cmd_reader.read_to_string(&mut command)?;
+        if is_expired(&command_pos, now_millis()) {
+            drop(index);
+            self.shared.forget_expired(key, command_pos);
+            return Ok(None);
+        }
+
+        let value = self.shared.read_value(&command_pos)?;
+        Ok(Some((value, command_pos.seq)))
+    }
 
-            if let Command::Set { key: _, value } = serde_json::from_str(&command)? {
-                return Ok(Some(value));
+    fn set_bytes(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        let ticket = self.writer().write_set(key, value, None)?;
+        self.wait_durable(ticket)
+    }
+
+    fn remove_bytes(&self, key: &[u8]) -> Result<()> {
+        let ticket = {
+            let mut writer = self.writer();
+            if self.shared.live_command_pos(key).is_none() {
+                return Err(KvsError::KeyNotFound);
             }
+
+            let record = Record::Remove {
+                key: key.to_vec(),
+                seq: writer.next_seq(),
+                written_at: now_millis(),
+            };
+            writer.append(record)?
+        };
+        self.wait_durable(ticket)
+    }
+
+    fn apply_batch(&self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
         }
 
-        Ok(None)
+        let ticket = self.writer().write_batch(batch)?;
+        self.wait_durable(ticket)
+    }
+
+    fn set_if(&self, key: &[u8], value: &[u8], condition: SetCondition) -> Result<SetOutcome> {
+        let ticket = {
+            // the writer lock is held from the check to the write so nothing can slip in between
+            let mut writer = self.writer();
+            let current = self.get_bytes(key)?;
+            if !condition.is_met_by(current.as_deref()) {
+                return Ok(SetOutcome::Conflict(current));
+            }
+
+            writer.write_set(key, value, None)?
+        };
+        self.wait_durable(ticket)?;
+        Ok(SetOutcome::Written)
+    }
+
+    fn commit(&self, transaction: Transaction) -> Result<()> {
+        let ticket = {
+            // the writer lock is held from checking the reads to making the writes so nothing can
+            // slip in between
+            let mut writer = self.writer();
+            for (key, seq) in transaction.reads() {
+                if self
+                    .shared
+                    .live_command_pos(key)
+                    .map(|command_pos| command_pos.seq)
+                    != seq
+                {
+                    return Err(KvsError::TransactionConflict { key: key.to_vec() });
+                }
+            }
+
+            let writes = transaction.writes();
+            if writes.is_empty() {
+                return Ok(());
+            }
+            writer.write_batch(writes)?
+        };
+        self.wait_durable(ticket)
+    }
+
+    fn key_count(&self) -> Result<u64> {
+        Ok(self.shared.index().latest.len() as u64)
     }
 
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        if self.index.contains_key(&key) {
-            self.num_unnecessary_entries += 1;
+    fn current_seq(&self) -> Result<u64> {
+        Ok(self.writer().last_seq)
+    }
+
+    fn get_at(&self, key: &[u8], at: ReadAt) -> Result<Option<Vec<u8>>> {
+        let index = self.shared.index();
+        let versions = index.versions(key);
+
+        let version = match at {
+            ReadAt::Seq(seq) => versions.iter().rev().find(|version| version.pos.seq <= seq),
+            ReadAt::Time(time) => {
+                let at = unix_millis(time);
+                versions
+                    .iter()
+                    .rev()
+                    .find(|version| version.pos.written_at <= at)
+                    .filter(|version| !is_expired(&version.pos, at))
+            }
+        };
+
+        match version {
+            Some(version) if !version.removed => self.shared.read_value(&version.pos).map(Some),
+            _ => Ok(None),
         }
+    }
+
+    fn history(&self, key: &[u8]) -> Result<Vec<Version>> {
+        let index = self.shared.index();
+        index
+            .versions(key)
+            .into_iter()
+            .map(|version| {
+                let value = if version.removed {
+                    None
+                } else {
+                    Some(self.shared.read_value(&version.pos)?)
+                };
+
+                Ok(Version {
+                    seq: version.pos.seq,
+                    written_at: system_time(version.pos.written_at),
+                    value,
+                    expires_at: version.pos.expires_at.map(system_time),
+                })
+            })
+            .collect()
+    }
 
-        let command = Command::Set {
-            key: key.clone(),
-            value,
+    fn flush(&self) -> Result<()> {
+        self.writer().log_writer.flush()?;
+        self.shared.syncer.sync_pending()
+    }
+
+    fn snapshot(&self) -> Result<Box<dyn KvsSnapshot + Send>> {
+        self.writer().log_writer.flush()?;
+
+        // pinned before the index lock is let go of, so a compaction can't move the copied
+        // entries and delete their segments in between
+        let index = self.shared.index();
+        let gens = index
+            .latest
+            .values()
+            .map(|command_pos| command_pos.gen)
+            .collect::<BTreeSet<_>>();
+        self.shared.pin(&gens);
+        let snapshot = KvStoreSnapshot {
+            shared: Arc::clone(&self.shared),
+            index: index.latest.clone(),
+            gens,
+            taken_at: now_millis(),
         };
 
-        let num_bytes_written_before_write = self.log_writer.num_bytes_written;
+        Ok(Box::new(snapshot))
+    }
+
+    fn set_bytes_with_ttl(&self, key: &[u8], value: &[u8], ttl: Duration) -> Result<()> {
+        let expires_at = now_millis().saturating_add(ttl.as_millis() as u64);
+        let ticket = self.writer().write_set(key, value, Some(expires_at))?;
+        self.wait_durable(ticket)
+    }
 
-        serde_json::to_writer(&mut self.log_writer, &command)?;
-        self.log_writer.write_all(b"\n")?;
+    fn ttl(&self, key: &[u8]) -> Result<KeyTtl> {
+        Ok(match self.shared.live_command_pos(key) {
+            None => KeyTtl::Missing,
+            Some(CommandPos {
+                expires_at: None, ..
+            }) => KeyTtl::NoExpiry,
+            Some(CommandPos {
+                expires_at: Some(at),
+                ..
+            }) => KeyTtl::ExpiresIn(Duration::from_millis(at.saturating_sub(now_millis()))),
+        })
+    }
 
-        let num_bytes_written_after_write = self.log_writer.num_bytes_written;
+    fn persist(&self, key: &[u8]) -> Result<bool> {
+        let ticket = {
+            let mut writer = self.writer();
+            match self.shared.live_command_pos(key) {
+                Some(CommandPos {
+                    expires_at: Some(_),
+                    ..
+                }) => {}
+                _ => return Ok(false),
+            }
 
-        let command_pos = CommandPos {
-            pos: num_bytes_written_before_write,
-            len: num_bytes_written_after_write - num_bytes_written_before_write,
+            // the value is written again without an expiry so the change makes it into the log
+            match self.get_bytes(key)? {
+                Some(value) => writer.write_set(key, &value, None)?,
+                None => return Ok(false),
+            }
         };
+        self.wait_durable(ticket)?;
+        Ok(true)
+    }
+}
+
+// walks the index in key order, looking up the next key afresh on every step so writes and
+// compactions can carry on in between
+struct KvStoreScan<'a> {
+    shared: &'a Shared,
+    next: Bound<Vec<u8>>, // lower bound of the keys not yet returned
+    end: Bound<Vec<u8>>,
+}
+
+impl<'a> KvStoreScan<'a> {
+    fn read_next(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let index = self.shared.index();
 
-        self.index.insert(key, command_pos);
+        let now = now_millis();
+        let (key, command_pos) = match index
+            .latest
+            .range((self.next.clone(), self.end.clone()))
+            .find(|(_, command_pos)| !is_expired(command_pos, now))
+        {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        self.next = Bound::Excluded(key.clone());
 
-        if self.should_compact() {
-            self.compact()?;
+        match self.shared.read_record(command_pos)? {
+            Record::Set { key, value, .. } => Ok(Some((key, value))),
+            Record::Remove { .. } | Record::Batch(_) => Err(KvsError::CorruptLog(String::from(
+                "Index pointed to a command without a value",
+            ))),
         }
+    }
+}
 
-        Ok(())
+impl<'a> Iterator for KvStoreScan<'a> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_next().transpose()
     }
+}
 
-    fn remove(&mut self, key: String) -> Result<()> {
-        if self.get(key.clone())?.is_some() {
-            let command = Command::Remove { key: key.clone() };
-            serde_json::to_writer(&mut self.log_writer, &command)?;
-            // writeln!(&mut self.log_writer)?;
-            self.log_writer.write_all(b"\n")?;
+// the keys of a scan, which only need the index
+struct KvStoreKeys<'a> {
+    shared: &'a Shared,
+    next: Bound<Vec<u8>>, // lower bound of the keys not yet returned
+    end: Bound<Vec<u8>>,
+}
 
-            self.index.remove(&key);
+impl<'a> Iterator for KvStoreKeys<'a> {
+    type Item = Result<Vec<u8>>;
 
-            Ok(())
-        } else {
-            Err(format_err!("Key not found"))
+    fn next(&mut self) -> Option<Self::Item> {
+        let index = self.shared.index();
+
+        let now = now_millis();
+        let (key, _) = index
+            .latest
+            .range((self.next.clone(), self.end.clone()))
+            .find(|(_, command_pos)| !is_expired(command_pos, now))?;
+        self.next = Bound::Excluded(key.clone());
+        Some(Ok(key.clone()))
+    }
+}
+
+/// a point-in-time view of a KvStore
+///
+/// holds the index as it was when taken, keys are read from the segments it points to and expire
+/// as of that moment. those segments stay on disk until it's dropped.
+pub struct KvStoreSnapshot {
+    shared: Arc<Shared>,
+    index: BTreeMap<Vec<u8>, CommandPos>,
+    gens: BTreeSet<u64>, // the segments pinned for it
+    taken_at: u64,
+}
+
+impl KvsSnapshot for KvStoreSnapshot {
+    fn get_bytes(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.index.get(key) {
+            Some(command_pos) if !is_expired(command_pos, self.taken_at) => {
+                self.shared.read_value(command_pos).map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn scan(&mut self, range: KeyRange) -> Result<KvPairs<'_>> {
+        let (shared, taken_at) = (&self.shared, self.taken_at);
+        Ok(Box::new(
+            self.index
+                .range(range)
+                .filter(move |(_, command_pos)| !is_expired(command_pos, taken_at))
+                .map(
+                    move |(_, command_pos)| match shared.read_record(command_pos)? {
+                        Record::Set { key, value, .. } => Ok((key, value)),
+                        _ => Err(KvsError::CorruptLog(String::from(
+                            "Index pointed to a command without a value",
+                        ))),
+                    },
+                ),
+        ))
+    }
+}
+
+impl Drop for KvStoreSnapshot {
+    fn drop(&mut self) {
+        if let Err(e) = self.shared.unpin(&self.gens) {
+            error!(
+                "Failed removing compacted segments of {:?}: {}",
+                self.shared.path, e
+            );
         }
     }
 }
 
+// copies every kept version out of the segments older than `compaction_gen` into a new segment,
+// points the index at the copies and deletes the old segments. keys that expired long enough ago
+// for the retention policy to let go of them aren't copied and leave the index.
+//
+// runs on its own thread. commands written meanwhile go to segments newer than `compaction_gen`,
+// and an index entry is only moved over (or dropped) if nothing replaced it while copying.
+fn compact(shared: &Shared, compaction_gen: u64, last_seq: u64) -> Result<()> {
+    let (dir, retention, now) = (&shared.path, shared.retention, now_millis());
+    let mut expired = Vec::new();
+    let mut kept = Vec::new();
+    {
+        let mut index = shared.index_mut();
+        index.trim_all(retention, now);
+
+        let keys = index
+            .latest
+            .keys()
+            .chain(index.history.keys())
+            .cloned()
+            .collect::<BTreeSet<_>>();
+        for key in keys {
+            let latest = index.latest.get(&key).copied();
+            match latest {
+                Some(pos) if pos.gen < compaction_gen && is_expendable(retention, &pos, now) => {
+                    expired.push((key, pos));
+                }
+                _ => kept.extend(
+                    index
+                        .versions(&key)
+                        .into_iter()
+                        .filter(|version| version.pos.gen < compaction_gen)
+                        .map(|version| (key.clone(), version)),
+                ),
+            }
+        }
+    }
+
+    // written under a temporary name so a crash mid compaction never leaves a half written segment
+    let temp_path = compaction_path(dir, compaction_gen);
+    let mut compaction_writer = BufWriterWithPosition::new(
+        OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .read(true)
+            .open(&temp_path)?,
+        0,
+    );
+
+    // only this thread deletes segments older than `compaction_gen`, so they can be read without
+    // holding the index lock
+    let mut moved = Vec::with_capacity(kept.len());
+    for (key, old_version) in kept {
+        let record = shared.read_record(&old_version.pos)?;
+
+        // re-encoding rather than copying bytes verifies the checksum and converts records
+        // from the old JSON format
+        let pos = compaction_writer.num_bytes_written;
+        let len = record.write_to(&mut compaction_writer)?;
+
+        let new_pos = CommandPos {
+            gen: compaction_gen,
+            pos,
+            len,
+            ..old_version.pos
+        };
+        moved.push((key, old_version, new_pos));
+    }
+
+    compaction_writer.flush()?;
+    compaction_writer.get_ref().sync_all()?;
+    drop(compaction_writer);
+    fs::rename(&temp_path, log_path(dir, compaction_gen))?;
+
+    {
+        let mut index = shared.index_mut();
+        for (key, old_version, new_pos) in &moved {
+            if let Some(command_pos) = index.latest.get_mut(key) {
+                if *command_pos == old_version.pos {
+                    *command_pos = *new_pos;
+                    continue;
+                }
+            }
+            if let Some(version) = index
+                .history
+                .get_mut(key)
+                .and_then(|history| history.iter_mut().find(|v| v.pos == old_version.pos))
+            {
+                version.pos = *new_pos;
+            }
+        }
+        for (key, old_pos) in &expired {
+            if index.latest.get(key) == Some(old_pos) {
+                index.latest.remove(key);
+                index.history.remove(key);
+            } else if let Some(history) = index.history.get_mut(key) {
+                // written to while copying, which pushed the expired version into its history.
+                // it wasn't copied, so it goes along with the old segments
+                history.retain(|version| version.pos.gen >= compaction_gen);
+                if history.is_empty() {
+                    index.history.remove(key);
+                }
+            }
+        }
+    }
+
+    // the compacted segment on its own is a valid starting point for the next open, commands
+    // written since then are replayed on top of it
+    let mut compacted_index = Index::default();
+    for (key, old_version, new_pos) in moved {
+        let version = PastVersion {
+            pos: new_pos,
+            removed: old_version.removed,
+        };
+        compacted_index.apply(key, version, retention, now);
+    }
+    hint::write_hint(dir, compaction_gen + 1, 0, 0, last_seq, &compacted_index)?;
+
+    // every kept version now lives in the compacted segment and the index no longer points into
+    // the old ones, so they can go once no snapshot reads from them
+    shared.close_before(compaction_gen);
+    let old_gens = sorted_gen_list(dir)?
+        .into_iter()
+        .filter(|&gen| gen < compaction_gen);
+    shared.remove_compacted(old_gens)
+}
+
+/// whether a file in the store's directory is one of its log segments
+pub(crate) fn is_log_segment(path: &Path) -> bool {
+    path.is_file()
+        && path.extension() == Some("log".as_ref())
+        && path
+            .file_stem()
+            .and_then(OsStr::to_str)
+            .map(|stem| stem.parse::<u64>().is_ok())
+            .unwrap_or(false)
+}
+
+fn log_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.log", gen))
+}
+
+fn compaction_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.compacting", gen))
+}
+
+// a hint can only be trusted if the log it was written against is still there
+fn is_hint_current(dir: &Path, gens: &[u64], hint: &Hint) -> Result<bool> {
+    if hint.replay_pos > 0 {
+        let replay_segment = log_path(dir, hint.replay_gen);
+        if !replay_segment.is_file() || fs::metadata(replay_segment)?.len() < hint.replay_pos {
+            return Ok(false);
+        }
+    }
+
+    Ok(hint_gens(hint).all(|gen| gens.binary_search(&gen).is_ok()))
+}
+
+// the segments every version in a hint's index is in
+fn hint_gens(hint: &Hint) -> impl Iterator<Item = u64> + '_ {
+    let history = hint
+        .index
+        .history
+        .values()
+        .flatten()
+        .map(|version| &version.pos);
+    hint.index
+        .latest
+        .values()
+        .chain(history)
+        .map(|command_pos| command_pos.gen)
+}
+
+// a compaction that was interrupted never got to replace any segment, so its output is useless
+fn remove_unfinished_compactions(dir: &Path) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() && path.extension() == Some("compacting".as_ref()) {
+            fs::remove_file(path)?;
+        }
+    }
+
+    Ok(())
+}
+
+// generations of all log segments in the directory, oldest first
+fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
+    let mut gens = Vec::new();
+
+    for entry in fs::read_dir(path)? {
+        let path = entry?.path();
+        if is_log_segment(&path) {
+            if let Some(gen) = path
+                .file_stem()
+                .and_then(OsStr::to_str)
+                .and_then(|stem| stem.parse::<u64>().ok())
+            {
+                gens.push(gen);
+            }
+        }
+    }
+
+    gens.sort_unstable();
+    Ok(gens)
+}
+
+// opens (creating if needed) the segment for `gen` for appending
+fn new_log_file(path: &Path, gen: u64) -> Result<BufWriterWithPosition<File>> {
+    let log_file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .read(true)
+        .open(log_path(path, gen))?;
+
+    let num_bytes_written = log_file.metadata()?.len();
+    Ok(BufWriterWithPosition::new(log_file, num_bytes_written))
+}
+
+// replays one segment from `start` onwards into the index, returns how many of its commands made
+// older ones unnecessary and the highest sequence number in it
+//
+// a torn or corrupt record at the very end of the segment is cut off when `recover_tail` is set,
+// anywhere else it's an error
+fn load(
+    dir: &Path,
+    gen: u64,
+    log_reader: &mut BufReader<File>,
+    start: u64,
+    index: &mut Index,
+    retention: Retention,
+    recover_tail: bool,
+) -> Result<(usize, u64)> {
+    let mut num_unnecessary_entries = 0;
+    let mut max_seq = 0;
+    let mut bytes_read = log_reader.seek(io::SeekFrom::Start(start))?;
+    let segment_len = log_reader.get_ref().metadata()?.len();
+    let now = now_millis();
+
+    loop {
+        let (is_trailing, reason) =
+            match record::read_next(log_reader, segment_len.saturating_sub(bytes_read))? {
+                ReadRecord::Batch(records, len) => {
+                    for (record, offset, len) in records {
+                        max_seq = max_seq.max(record.seq());
+                        num_unnecessary_entries += index.apply_record(
+                            record,
+                            gen,
+                            bytes_read + offset,
+                            len,
+                            retention,
+                            now,
+                        );
+                    }
+
+                    bytes_read += len;
+                    continue;
+                }
+                ReadRecord::Record(record, len) => {
+                    max_seq = max_seq.max(record.seq());
+                    num_unnecessary_entries +=
+                        index.apply_record(record, gen, bytes_read, len, retention, now);
+
+                    bytes_read += len;
+                    continue;
+                }
+                ReadRecord::End => break,
+                ReadRecord::Torn => (true, String::from("record is incomplete")),
+                ReadRecord::Corrupt { len, reason } => {
+                    (len.map(|len| bytes_read + len) == Some(segment_len), reason)
+                }
+            };
+
+        if !(recover_tail && is_trailing) {
+            return Err(KvsError::CorruptLog(format!(
+                "record in segment {} at byte {}: {}",
+                gen, bytes_read, reason
+            )));
+        }
+
+        warn!(
+            "Discarding damaged record at the end of segment {} ({}), truncating it from {} to {} bytes",
+            gen, reason, segment_len, bytes_read
+        );
+        OpenOptions::new()
+            .write(true)
+            .open(log_path(dir, gen))?
+            .set_len(bytes_read)?;
+        break;
+    }
+
+    Ok((num_unnecessary_entries, max_seq))
+}
+
+fn is_expired(command_pos: &CommandPos, now: u64) -> bool {
+    command_pos.expires_at.is_some_and(|at| at <= now)
+}
+
+// whether a key whose current value is at `command_pos` can go with all its history, that is if
+// it expired before anything the retention policy still keeps
+fn is_expendable(retention: Retention, command_pos: &CommandPos, now: u64) -> bool {
+    match retention {
+        Retention::Latest => is_expired(command_pos, now),
+        Retention::Versions(_) => false,
+        Retention::Window(window) => {
+            is_expired(command_pos, now.saturating_sub(window.as_millis() as u64))
+        }
+    }
+}
+
+// reads exactly `buf.len()` bytes at `offset` without touching the handle's cursor, so any
+// number of threads can read through the same handle at once
+#[cfg(unix)]
+fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.read_exact_at(buf, offset)
+}
+
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset)? {
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
 struct BufWriterWithPosition<T>
 where
     T: Write + Read,
@@ -200,13 +1386,36 @@ where
             num_bytes_written,
         }
     }
+
+    fn get_ref(&self) -> &T {
+        self.writer.get_ref()
+    }
 }
 
-impl Drop for KvStore {
+impl Drop for KvStoreWriter {
     fn drop(&mut self) {
+        self.finish_compaction();
+
         self.log_writer
             .flush()
             .expect("Failed flushing log_writer when dropping KvStore");
+        // a clean shutdown leaves the log on disk whatever the policy
+        if let Err(e) = self.shared.syncer.sync_pending() {
+            error!("Failed syncing log when dropping KvStore: {}", e);
+        }
+
+        // a hint covering the whole log lets the next open skip replaying it
+        let index = self.shared.index();
+        if let Err(e) = hint::write_hint(
+            &self.shared.path,
+            self.current_gen,
+            self.log_writer.num_bytes_written,
+            self.shared.num_unnecessary_entries.load(Ordering::SeqCst),
+            self.last_seq,
+            &index,
+        ) {
+            error!("Failed writing hint file in {:?}: {}", self.shared.path, e);
+        }
     }
 }
 
@@ -0,0 +1,148 @@
+//! the MANIFEST file that says which engine a directory belongs to
+//!
+//! it's written when a store is created and checked every time one is opened, so neither engine
+//! opens the other's data or data laid out by a newer build than it understands. it's a small JSON
+//! object:
+//!
+//! `{"engine":"kvs","format_version":1,"created_at":1700000000000,"options":{"durability":"never"}}`
+//!
+//! where created_at is unix time in milliseconds and options are what the store was created with,
+//! kept only to tell how it was set up.
+
+use super::{kvs, now_millis};
+use crate::{EngineType, KvsError, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// what a store's directory holds
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Manifest {
+    /// the engine whose data is in the directory
+    #[serde(with = "crate::config::display_from_str")]
+    pub(crate) engine: EngineType,
+
+    /// the version of the engine's on-disk layout the data was written in
+    pub(crate) format_version: u32,
+
+    /// when the store was created, in unix time milliseconds
+    pub(crate) created_at: u64,
+
+    /// the options the store was created with, only there to tell how it was set up
+    pub(crate) options: BTreeMap<String, String>,
+}
+
+fn manifest_path(dir: &Path) -> PathBuf {
+    dir.join("MANIFEST")
+}
+
+/// reads the manifest in `dir`, `None` if there isn't one
+pub(crate) fn read(dir: &Path) -> Result<Option<Manifest>> {
+    let contents = match fs::read(manifest_path(dir)) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        contents => contents?,
+    };
+    serde_json::from_slice(&contents)
+        .map(Some)
+        .map_err(|e| KvsError::CorruptLog(format!("MANIFEST in {}: {}", dir.display(), e)))
+}
+
+/// the engine whose data is in `dir`, `None` if it holds no store
+pub(crate) fn existing_engine(dir: &Path) -> Result<Option<EngineType>> {
+    match read(dir)? {
+        Some(manifest) => Ok(Some(manifest.engine)),
+        None => sniff_engine(dir),
+    }
+}
+
+/// checks that `dir` can be opened as `engine` in `format_version`, writing a manifest for it if
+/// it has none yet
+///
+/// fails if the directory holds the other engine's data, or data in a newer format version.
+pub(crate) fn check_or_create(
+    dir: &Path,
+    engine: EngineType,
+    format_version: u32,
+    options: BTreeMap<String, String>,
+) -> Result<()> {
+    if let Some(manifest) = read(dir)? {
+        if manifest.engine != engine {
+            return Err(KvsError::IncompatibleEngine {
+                requested: engine,
+                existing: manifest.engine,
+            });
+        }
+        if manifest.format_version > format_version {
+            return Err(KvsError::UnsupportedFormat {
+                engine,
+                version: manifest.format_version,
+                supported: format_version,
+            });
+        }
+        return Ok(());
+    }
+
+    // stores created before manifests existed get one, as long as they're the engine asked for
+    if let Some(existing) = sniff_engine(dir)? {
+        if existing != engine {
+            return Err(KvsError::IncompatibleEngine {
+                requested: engine,
+                existing,
+            });
+        }
+    }
+
+    write(
+        dir,
+        &Manifest {
+            engine,
+            format_version,
+            created_at: now_millis(),
+            options,
+        },
+    )
+}
+
+// atomically replaces the manifest
+fn write(dir: &Path, manifest: &Manifest) -> Result<()> {
+    let temp_path = dir.join("MANIFEST.tmp");
+    let mut file = File::create(&temp_path)?;
+    serde_json::to_writer(&mut file, manifest)?;
+    file.write_all(b"\n")?;
+    file.sync_all()?;
+    drop(file);
+
+    fs::rename(temp_path, manifest_path(dir))?;
+    Ok(())
+}
+
+// tells the engine of a directory with no manifest by the files in it
+fn sniff_engine(dir: &Path) -> Result<Option<EngineType>> {
+    let entries = match fs::read_dir(dir) {
+        // a directory that isn't there yet holds no engine's files
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        entries => entries?,
+    };
+
+    let (mut kvs_files, mut sled_files) = (false, false);
+    for entry in entries {
+        let path = entry?.path();
+        if path.ends_with("kvs.log") || kvs::is_log_segment(&path) {
+            kvs_files = true;
+        } else if path.ends_with("sled_db.log") {
+            sled_files = true;
+        }
+    }
+
+    match (kvs_files, sled_files) {
+        (true, true) => Err(KvsError::Engine(format!(
+            "{} holds files of both kvs and sled and no MANIFEST saying which engine it belongs to",
+            dir.display()
+        ))),
+        (true, false) => Ok(Some(EngineType::Kvs)),
+        (false, true) => Ok(Some(EngineType::Sled)),
+        (false, false) => Ok(None),
+    }
+}
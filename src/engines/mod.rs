@@ -1,2 +1,87 @@
+use crate::Retention;
+use log::error;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+mod hint;
 pub mod kvs;
+pub(crate) mod manifest;
+mod record;
 pub mod sled;
+mod syncer;
+
+/// runs a task on its own thread every so often, until dropped
+pub(crate) struct PeriodicTask {
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl PeriodicTask {
+    pub(crate) fn spawn<F>(name: &'static str, interval: Duration, mut task: F) -> Self
+    where
+        F: FnMut() -> crate::Result<()> + Send + 'static,
+    {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let handle = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                if let Err(e) = task() {
+                    error!("Background {} failed: {}", name, e);
+                }
+            }
+        });
+
+        Self {
+            stop: Some(stop),
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for PeriodicTask {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// the current unix time in milliseconds, which is how expiry and write times are stored
+pub(crate) fn now_millis() -> u64 {
+    unix_millis(SystemTime::now())
+}
+
+/// the time a unix time in milliseconds stands for
+pub(crate) fn system_time(millis: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(millis)
+}
+
+/// a time as unix time in milliseconds, times before 1970 come out as 0
+pub(crate) fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// how many of the oldest versions of a key `retention` lets go of
+///
+/// `written_at` holds when each version was written, oldest first and the current one last. the
+/// current version is always kept.
+pub(crate) fn num_expendable(retention: Retention, written_at: &[u64], now: u64) -> usize {
+    let len = written_at.len();
+    match retention {
+        Retention::Latest => len.saturating_sub(1),
+        Retention::Versions(n) => len.saturating_sub(n.max(1)),
+        // a version is still needed as long as it was current at some point in the window, that
+        // is until the one after it was written
+        Retention::Window(window) => {
+            let cutoff = now.saturating_sub(window.as_millis() as u64);
+            written_at
+                .iter()
+                .skip(1)
+                .take_while(|&&superseded_at| superseded_at <= cutoff)
+                .count()
+        }
+    }
+}
@@ -0,0 +1,476 @@
+//! on-disk encoding of the commands stored in KvStore's log segments
+//!
+//! every record is a fixed size header followed by the raw key and value bytes:
+//!
+//! | magic (2) | version (1) | kind (1) | key len (4) | value len (4) | crc32 (4) | expires at (8) |
+//! | seq (8) | written at (8) | key | value |
+//!
+//! integers are little endian and times are unix time in milliseconds, an expires at of 0 means
+//! never. seq is the write's sequence number. version 2 records stop after the expiry and version 1
+//! records before it, they're read back with a seq and write time of 0. the checksum is taken over
+//! the whole record minus the magic, with the checksum field itself zeroed.
+//!
+//! a batch has no key, its value is the sets and removes it's made of, each encoded as a record of
+//! its own, so a batch is only ever replayed as a whole.
+//!
+//! logs written before this format hold one JSON command per line, those are still understood
+//! when reading and get rewritten in the binary format on compaction.
+
+use crate::{KvsError, Result};
+use serde::Deserialize;
+use std::io::{self, BufRead, Read, Write};
+use std::iter;
+
+const RECORD_MAGIC: [u8; 2] = [0xC5, 0x4B];
+const RECORD_VERSION: u8 = 3;
+const V1_HEADER_LEN: usize = 16;
+const V2_HEADER_LEN: usize = 24;
+const HEADER_LEN: usize = 40;
+
+const KIND_SET: u8 = 1;
+const KIND_REMOVE: u8 = 2;
+const KIND_BATCH: u8 = 3;
+
+// the shape of the commands in logs written before the binary format
+#[derive(Deserialize)]
+enum JsonCommand {
+    Set { key: String, value: String },
+    Get {},
+    Remove { key: String },
+}
+
+/// a single command as stored in the log
+#[derive(Debug)]
+pub(crate) enum Record {
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expires_at: Option<u64>, // unix time in milliseconds
+        seq: u64,
+        written_at: u64, // unix time in milliseconds
+    },
+    Remove {
+        key: Vec<u8>,
+        seq: u64,
+        written_at: u64,
+    },
+    /// sets and removes that are applied together, never holds another batch
+    Batch(Vec<Record>),
+}
+
+impl Record {
+    /// encodes the record into the binary format
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let batch;
+        let (kind, key, value, expires_at, seq, written_at): (u8, &[u8], &[u8], _, _, _) =
+            match self {
+                Record::Set {
+                    key,
+                    value,
+                    expires_at,
+                    seq,
+                    written_at,
+                } => (
+                    KIND_SET,
+                    key,
+                    value,
+                    expires_at.unwrap_or(0),
+                    *seq,
+                    *written_at,
+                ),
+                Record::Remove {
+                    key,
+                    seq,
+                    written_at,
+                } => (KIND_REMOVE, key, &[], 0, *seq, *written_at),
+                Record::Batch(records) => {
+                    batch = records.iter().flat_map(Record::encode).collect::<Vec<_>>();
+                    (KIND_BATCH, &[], &batch, 0, 0, 0)
+                }
+            };
+
+        let mut buf = Vec::with_capacity(HEADER_LEN + key.len() + value.len());
+        buf.extend_from_slice(&RECORD_MAGIC);
+        buf.push(RECORD_VERSION);
+        buf.push(kind);
+        buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&[0; 4]); // checksum, filled in below
+        buf.extend_from_slice(&expires_at.to_le_bytes());
+        buf.extend_from_slice(&seq.to_le_bytes());
+        buf.extend_from_slice(&written_at.to_le_bytes());
+        buf.extend_from_slice(key);
+        buf.extend_from_slice(value);
+
+        let crc = checksum(&buf);
+        buf[12..16].copy_from_slice(&crc.to_le_bytes());
+
+        buf
+    }
+
+    /// sequence number of the write, the highest one in it for a batch
+    pub(crate) fn seq(&self) -> u64 {
+        match self {
+            Record::Set { seq, .. } | Record::Remove { seq, .. } => *seq,
+            Record::Batch(records) => records.iter().map(Record::seq).max().unwrap_or(0),
+        }
+    }
+
+    /// writes the record in one go and returns how many bytes it took up
+    pub(crate) fn write_to(&self, writer: &mut impl Write) -> Result<u64> {
+        let buf = self.encode();
+        writer.write_all(&buf)?;
+        Ok(buf.len() as u64)
+    }
+
+    /// reads the record at the start of `buf`, `None` if it's empty
+    ///
+    /// returns the record along with how many bytes it took up
+    pub(crate) fn read_from(mut buf: &[u8]) -> Result<Option<(Record, u64)>> {
+        let remaining = buf.len() as u64;
+        match read_next(&mut buf, remaining)? {
+            ReadRecord::Record(record, len) => Ok(Some((record, len))),
+            ReadRecord::Batch(..) => Err(KvsError::CorruptLog(String::from("unexpected batch"))),
+            ReadRecord::End => Ok(None),
+            ReadRecord::Torn => Err(KvsError::CorruptLog(String::from("record is incomplete"))),
+            ReadRecord::Corrupt { reason, .. } => Err(KvsError::CorruptLog(reason)),
+        }
+    }
+}
+
+/// where every record of a batch about to be written at `pos` will sit and how long it is
+pub(crate) fn batch_positions(records: &[Record], pos: u64) -> Vec<(u64, u64)> {
+    let mut pos = pos + HEADER_LEN as u64;
+    records
+        .iter()
+        .map(|record| {
+            let len = match record {
+                Record::Set { key, value, .. } => HEADER_LEN + key.len() + value.len(),
+                Record::Remove { key, .. } => HEADER_LEN + key.len(),
+                Record::Batch(_) => unreachable!("batches don't nest"),
+            } as u64;
+            pos += len;
+            (pos - len, len)
+        })
+        .collect()
+}
+
+/// outcome of trying to read the next record of a log segment
+pub(crate) enum ReadRecord {
+    /// a complete record that passed its checksum, along with how many bytes it took up
+    Record(Record, u64),
+
+    /// a complete batch, with every record in it along with where it starts relative to the
+    /// start of the batch and how long it is, then how many bytes the whole batch took up
+    Batch(Vec<(Record, u64, u64)>, u64),
+
+    /// there are no more bytes in the segment
+    End,
+
+    /// the segment ends partway through a record, what a crash in the middle of a write leaves
+    Torn,
+
+    /// the record is complete but damaged, `len` is how many bytes it claims to take up if that
+    /// could be worked out
+    Corrupt { len: Option<u64>, reason: String },
+}
+
+/// reads the record starting at the reader's position without treating damage as an error
+///
+/// `remaining` is how many bytes the segment has left from there. a record that claims to run
+/// past them is only taken to be torn if nothing intact follows it, nothing longer than the
+/// segment is ever allocated.
+pub(crate) fn read_next(reader: &mut impl BufRead, remaining: u64) -> Result<ReadRecord> {
+    let first_byte = match reader.fill_buf()?.first() {
+        Some(&byte) => byte,
+        None => return Ok(ReadRecord::End),
+    };
+
+    if first_byte == b'{' {
+        return read_json_line(reader);
+    }
+
+    let mut header = [0; HEADER_LEN];
+    if !read_exact_or_eof(reader, &mut header[..V1_HEADER_LEN])? {
+        return Ok(ReadRecord::Torn);
+    }
+
+    if header[0..2] != RECORD_MAGIC {
+        return Ok(ReadRecord::Corrupt {
+            len: None,
+            reason: String::from("bad magic"),
+        });
+    }
+    let header_len = match header[2] {
+        1 => V1_HEADER_LEN,
+        2 => V2_HEADER_LEN,
+        RECORD_VERSION => HEADER_LEN,
+        version => {
+            return Ok(ReadRecord::Corrupt {
+                len: None,
+                reason: format!("unsupported version {}", version),
+            })
+        }
+    };
+    if !read_exact_or_eof(reader, &mut header[V1_HEADER_LEN..header_len])? {
+        return Ok(ReadRecord::Torn);
+    }
+    let header = &mut header[..header_len];
+
+    let key_len = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+    let value_len = u32::from_le_bytes([header[8], header[9], header[10], header[11]]) as usize;
+    let crc = u32::from_le_bytes([header[12], header[13], header[14], header[15]]);
+    let len = (header_len + key_len + value_len) as u64;
+
+    let body_left = remaining.saturating_sub(header_len as u64);
+    if (key_len + value_len) as u64 > body_left {
+        let mut rest = Vec::new();
+        reader.take(body_left).read_to_end(&mut rest)?;
+        return Ok(read_overrun(header, crc, &rest));
+    }
+
+    let mut body = vec![0; key_len + value_len];
+    if !read_exact_or_eof(reader, &mut body)? {
+        return Ok(ReadRecord::Torn);
+    }
+
+    header[12..16].copy_from_slice(&[0; 4]);
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&header[2..]);
+    hasher.update(&body);
+    if hasher.finalize() != crc {
+        return Ok(ReadRecord::Corrupt {
+            len: Some(len),
+            reason: String::from("checksum mismatch"),
+        });
+    }
+
+    let value = body.split_off(key_len);
+    let key = body;
+
+    // fields older versions don't have read as 0
+    let field = |at: usize| {
+        let mut buf = [0; 8];
+        if let Some(bytes) = header.get(at..at + 8) {
+            buf.copy_from_slice(bytes);
+        }
+        u64::from_le_bytes(buf)
+    };
+    let expires_at = Some(field(16)).filter(|&at| at != 0);
+    let (seq, written_at) = (field(24), field(32));
+
+    let record = match header[3] {
+        KIND_SET => Record::Set {
+            key,
+            value,
+            expires_at,
+            seq,
+            written_at,
+        },
+        KIND_REMOVE => Record::Remove {
+            key,
+            seq,
+            written_at,
+        },
+        KIND_BATCH => {
+            return Ok(match read_batch(&value, header_len as u64) {
+                Ok(records) => ReadRecord::Batch(records, len),
+                Err(reason) => ReadRecord::Corrupt {
+                    len: Some(len),
+                    reason,
+                },
+            })
+        }
+        kind => {
+            return Ok(ReadRecord::Corrupt {
+                len: Some(len),
+                reason: format!("unknown kind {}", kind),
+            })
+        }
+    };
+
+    Ok(ReadRecord::Record(record, len))
+}
+
+// the sets and removes a batch is made of, along with where each starts relative to the batch
+fn read_batch(
+    mut body: &[u8],
+    header_len: u64,
+) -> std::result::Result<Vec<(Record, u64, u64)>, String> {
+    let mut records = Vec::new();
+    let mut pos = header_len;
+    loop {
+        let remaining = body.len() as u64;
+        match read_next(&mut body, remaining) {
+            Ok(ReadRecord::Record(record, len)) => {
+                records.push((record, pos, len));
+                pos += len;
+            }
+            Ok(ReadRecord::End) => return Ok(records),
+            Ok(ReadRecord::Batch(..)) => return Err(String::from("batch holds another batch")),
+            Ok(_) => return Err(String::from("batch holds a damaged record")),
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+}
+
+// tells a record whose lengths run past the end of its segment, with `rest` the bytes the segment
+// holds after its header, apart from one cut short by a crash
+//
+// a crash only ever cuts off the end of a segment, so the record is taken to be torn only if
+// there's no way to make it fit and nothing intact follows it. otherwise its length is damaged,
+// and cutting the segment off there would lose every record after it
+fn read_overrun(header: &[u8], crc: u32, rest: &[u8]) -> ReadRecord {
+    if let Some(len) = repaired_len(header, crc, rest) {
+        return ReadRecord::Corrupt {
+            len: Some(len),
+            reason: String::from("damaged length field"),
+        };
+    }
+
+    // a torn batch holds intact records of its own, only what follows them counts
+    let start = if header[3] == KIND_BATCH {
+        intact_run_len(rest)
+    } else {
+        0
+    };
+    if (start..rest.len()).any(|at| intact_len(&rest[at..]).is_some()) {
+        return ReadRecord::Corrupt {
+            len: None,
+            reason: String::from("damaged length field with intact records after it"),
+        };
+    }
+
+    ReadRecord::Torn
+}
+
+// the length a record whose lengths run past the end of its segment really has, if one can be
+// found that fits in `rest` and passes its checksum. that's any single bit flipped in either
+// length, or for a batch its value running up to the end of any of the intact records it starts
+// with. a record cut short by a crash never passes, a complete one with a damaged length does
+fn repaired_len(header: &[u8], crc: u32, rest: &[u8]) -> Option<u64> {
+    let key_len = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    let value_len = u32::from_le_bytes([header[8], header[9], header[10], header[11]]);
+
+    let flips = (0..32).flat_map(|bit| {
+        iter::once((key_len ^ 1 << bit, value_len))
+            .chain(iter::once((key_len, value_len ^ 1 << bit)))
+    });
+    let mut batch_ends = Vec::new();
+    if header[3] == KIND_BATCH {
+        let mut end = 0;
+        while let Some(len) = intact_len(&rest[end..]) {
+            end += len;
+            batch_ends.push((0, end as u32));
+        }
+    }
+
+    flips
+        .chain(batch_ends)
+        .find(|&(key_len, value_len)| {
+            let body_len = key_len as usize + value_len as usize;
+            body_len <= rest.len()
+                && passes_checksum(header, key_len, value_len, &rest[..body_len], crc)
+        })
+        .map(|(key_len, value_len)| (header.len() + key_len as usize + value_len as usize) as u64)
+}
+
+// how many bytes the intact records `bytes` starts with take up, one after the other
+fn intact_run_len(bytes: &[u8]) -> usize {
+    let mut end = 0;
+    while let Some(len) = intact_len(&bytes[end..]) {
+        end += len;
+    }
+    end
+}
+
+// the length of the record at the start of `bytes` if its magic, version, lengths and checksum
+// all hold up
+fn intact_len(bytes: &[u8]) -> Option<usize> {
+    if bytes.get(..2)? != RECORD_MAGIC {
+        return None;
+    }
+    let header_len = match *bytes.get(2)? {
+        1 => V1_HEADER_LEN,
+        2 => V2_HEADER_LEN,
+        RECORD_VERSION => HEADER_LEN,
+        _ => return None,
+    };
+    let header = bytes.get(..header_len)?;
+
+    let key_len = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    let value_len = u32::from_le_bytes([header[8], header[9], header[10], header[11]]);
+    let crc = u32::from_le_bytes([header[12], header[13], header[14], header[15]]);
+    let len = header_len + key_len as usize + value_len as usize;
+    let body = bytes.get(header_len..len)?;
+    Some(len).filter(|_| passes_checksum(header, key_len, value_len, body, crc))
+}
+
+// whether a record with `header`, its lengths replaced by the ones given, and `body` has the
+// checksum `crc`
+fn passes_checksum(header: &[u8], key_len: u32, value_len: u32, body: &[u8], crc: u32) -> bool {
+    let mut header = header.to_vec();
+    header[4..8].copy_from_slice(&key_len.to_le_bytes());
+    header[8..12].copy_from_slice(&value_len.to_le_bytes());
+    header[12..16].copy_from_slice(&[0; 4]);
+
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&header[2..]);
+    hasher.update(body);
+    hasher.finalize() == crc
+}
+
+// like `read_exact` but returns false instead of failing when the reader runs out of bytes
+fn read_exact_or_eof(reader: &mut impl BufRead, buf: &mut [u8]) -> Result<bool> {
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// checksum of an encoded record whose checksum field is still zeroed, the magic is left out
+fn checksum(buf: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&buf[2..]);
+    hasher.finalize()
+}
+
+// logs written before the binary format stored one JSON command per line
+fn read_json_line(reader: &mut impl BufRead) -> Result<ReadRecord> {
+    let mut line = Vec::new();
+    let len = reader.read_until(b'\n', &mut line)? as u64; // includes the newline separating commands
+
+    if line.last() != Some(&b'\n') {
+        return Ok(ReadRecord::Torn);
+    }
+
+    let record = match serde_json::from_slice(&line) {
+        Ok(JsonCommand::Set { key, value }) => Record::Set {
+            key: key.into_bytes(),
+            value: value.into_bytes(),
+            expires_at: None,
+            seq: 0,
+            written_at: 0,
+        },
+        Ok(JsonCommand::Remove { key }) => Record::Remove {
+            key: key.into_bytes(),
+            seq: 0,
+            written_at: 0,
+        },
+        Ok(JsonCommand::Get {}) => {
+            return Ok(ReadRecord::Corrupt {
+                len: Some(len),
+                reason: String::from("GET in log"),
+            })
+        }
+        Err(e) => {
+            return Ok(ReadRecord::Corrupt {
+                len: Some(len),
+                reason: e.to_string(),
+            })
+        }
+    };
+
+    Ok(ReadRecord::Record(record, len))
+}
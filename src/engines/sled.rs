@@ -1,47 +1,912 @@
-use crate::KvsEngine;
+use super::manifest;
+use super::{now_millis, num_expendable, system_time, unix_millis, PeriodicTask};
 use crate::Result;
-use failure::format_err;
+use crate::{
+    BatchOp, Durability, EngineType, KeyRange, KeyTtl, Keys, KvPairs, KvsEngine, KvsError,
+    KvsSnapshot, ReadAt, Retention, SetCondition, SetOutcome, Transaction, Version, WriteBatch,
+};
+use sled::transaction::{ConflictableTransactionResult, TransactionalTree};
+use sled::Transactional;
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::fs;
+use std::iter::{self, Peekable};
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::thread;
+use std::time::Duration;
+
+// how often expired keys get swept out of the db
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+// how long to keep retrying when the db is still locked by a handle that was just dropped
+const LOCK_RETRIES: u32 = 50;
+const LOCK_RETRY_DELAY: Duration = Duration::from_millis(20);
+
+/// the version of the directory layout SledKvsEngine writes, recorded in its MANIFEST. bumped
+/// whenever the trees change in a way an older build would misread
+pub(crate) const FORMAT_VERSION: u32 = 1;
 
 // sled storage stuff starts here
 /// thin wrapper around the sled db
+///
+/// expiry times live in a tree of their own, keyed like the values and holding the big endian unix
+/// time in milliseconds the key expires at. expired keys read as absent straight away and are
+/// removed by a background sweeper.
+///
+/// every key also has its kept versions in a "versions" tree, the current value stays in the data
+/// tree and older ones are copied in alongside their sequence number and write time. old versions
+/// the retention policy lets go of are dropped whenever the key is written.
+#[derive(Clone)]
 pub struct SledKvsEngine {
-    inner: sled::Db,
+    trees: Trees,
+    durability: Durability,
+    retention: Retention,
+    _sweeper: Arc<PeriodicTask>, // stops once the last clone is dropped
+}
+
+/// options controlling how a SledKvsEngine is opened
+#[derive(Debug, Clone)]
+pub struct SledOptions {
+    /// when writes get flushed
+    pub durability: Durability,
+
+    /// how many old versions of every key are kept
+    pub retention: Retention,
+}
+
+impl Default for SledOptions {
+    fn default() -> Self {
+        Self {
+            durability: Durability::EveryWrite,
+            retention: Retention::default(),
+        }
+    }
+}
+
+impl SledOptions {
+    // the options as written to the MANIFEST
+    fn describe(&self) -> BTreeMap<String, String> {
+        let mut options = BTreeMap::new();
+        options.insert(String::from("durability"), self.durability.to_string());
+        options.insert(String::from("retention"), format!("{:?}", self.retention));
+        options
+    }
+}
+
+// every tree the engine keeps, written together in one transaction
+#[derive(Clone)]
+struct Trees {
+    data: sled::Db,
+    expiries: sled::Tree,
+    versions: sled::Tree,
+    last_seq: Arc<AtomicU64>, // sequence number of the latest committed write
+    keys: Arc<AtomicI64>,     // how many keys the data tree holds, counted once at open
+    snapshot_lock: Arc<RwLock<()>>, // taken for writing while a snapshot picks its sequence number
+    snapshots: Arc<Mutex<BTreeMap<(u64, u64), usize>>>, // (seq, taken at) of open snapshots
+}
+
+impl Trees {
+    fn transaction<T>(
+        &self,
+        f: impl Fn(&TxTrees<'_>) -> ConflictableTransactionResult<T, sled::Error>,
+    ) -> Result<T> {
+        let _guard = self
+            .snapshot_lock
+            .read()
+            .expect("SledKvsEngine snapshot lock poisoned");
+        // no snapshot is taken while the guard is held, so the oldest one can't change
+        let oldest_snapshot = self.oldest_snapshot().map(|(seq, _)| seq);
+        let max_seq = Cell::new(0);
+        let key_delta = Cell::new(0);
+        let result = (&*self.data, &self.expiries, &self.versions).transaction(
+            |(data, expiries, versions)| {
+                // a retry starts over with new sequence numbers and from the keys as they are
+                max_seq.set(0);
+                key_delta.set(0);
+                f(&TxTrees {
+                    data,
+                    expiries,
+                    versions,
+                    max_seq: &max_seq,
+                    key_delta: &key_delta,
+                    oldest_snapshot,
+                })
+            },
+        )?;
+
+        // still under the guard, so a snapshot sees every write up to its sequence number
+        self.last_seq.fetch_max(max_seq.get(), Ordering::SeqCst);
+        self.keys.fetch_add(key_delta.get(), Ordering::SeqCst);
+        Ok(result)
+    }
+
+    fn snapshots(&self) -> MutexGuard<'_, BTreeMap<(u64, u64), usize>> {
+        self.snapshots
+            .lock()
+            .expect("SledKvsEngine snapshots lock poisoned")
+    }
+
+    // the sequence number and time of the oldest snapshot still open
+    fn oldest_snapshot(&self) -> Option<(u64, u64)> {
+        self.snapshots().keys().next().copied()
+    }
+}
+
+// the trees as seen from inside a transaction
+struct TxTrees<'a> {
+    data: &'a TransactionalTree,
+    expiries: &'a TransactionalTree,
+    versions: &'a TransactionalTree,
+    max_seq: &'a Cell<u64>,       // the highest sequence number written so far
+    key_delta: &'a Cell<i64>,     // keys added so far, less the ones removed
+    oldest_snapshot: Option<u64>, // versions the snapshot taken at this seq may read are kept
+}
+
+impl TxTrees<'_> {
+    // the value of a key unless it has expired by `now`
+    fn current(
+        &self,
+        key: &[u8],
+        now: u64,
+    ) -> ConflictableTransactionResult<Option<Vec<u8>>, sled::Error> {
+        let expired = self
+            .expiries
+            .get(key)?
+            .is_some_and(|at| decode_u64(&at) <= now);
+        if expired {
+            return Ok(None);
+        }
+
+        Ok(self.data.get(key)?.map(|value| value.to_vec()))
+    }
+
+    // the value of a key along with the sequence number of the write that set it, unless it has
+    // expired by `now`
+    fn current_with_seq(
+        &self,
+        key: &[u8],
+        now: u64,
+    ) -> ConflictableTransactionResult<Option<(Vec<u8>, u64)>, sled::Error> {
+        Ok(match self.current(key, now)? {
+            Some(value) => {
+                let seq = self.versions(key)?.last().map_or(0, |version| version.seq);
+                Some((value, seq))
+            }
+            None => None,
+        })
+    }
+
+    // every kept version of a key, oldest first. a key written before versions were kept only has
+    // its current one, without a sequence number or write time
+    fn versions(
+        &self,
+        key: &[u8],
+    ) -> ConflictableTransactionResult<Vec<StoredVersion>, sled::Error> {
+        if let Some(bytes) = self.versions.get(key)? {
+            return Ok(decode_versions(&bytes)?);
+        }
+
+        let expires_at = self.expiries.get(key)?.map(|at| decode_u64(&at));
+        Ok(self
+            .data
+            .get(key)?
+            .map(|_| StoredVersion {
+                seq: 0,
+                written_at: 0,
+                expires_at,
+                value: StoredValue::Current,
+            })
+            .into_iter()
+            .collect())
+    }
+
+    // the value a key had as of the write with sequence number `seq`, unless it had expired by
+    // `now`
+    fn value_at(
+        &self,
+        key: &[u8],
+        seq: u64,
+        now: u64,
+    ) -> ConflictableTransactionResult<Option<Vec<u8>>, sled::Error> {
+        let versions = self.versions(key)?;
+        let version = versions
+            .iter()
+            .rev()
+            .find(|version| version.seq <= seq)
+            .filter(|version| version.expires_at.is_none_or(|at| at > now));
+
+        Ok(match version.map(|version| &version.value) {
+            Some(StoredValue::Inline(value)) => Some(value.clone()),
+            Some(StoredValue::Current) => self.data.get(key)?.map(|value| value.to_vec()),
+            Some(StoredValue::Removed) | None => None,
+        })
+    }
+
+    // makes `value` the current value of a key, `None` removes it
+    fn write(
+        &self,
+        key: &[u8],
+        value: Option<&[u8]>,
+        expires_at: Option<u64>,
+        retention: Retention,
+    ) -> ConflictableTransactionResult<(), sled::Error> {
+        let now = now_millis();
+        // sled hands out ids without them being part of the transaction, so writes to different
+        // keys don't conflict over the next sequence number
+        let seq = self.data.generate_id()?;
+        self.max_seq.set(self.max_seq.get().max(seq));
+
+        // the value that's current so far is about to leave the data tree
+        let mut versions = self.versions(key)?;
+        if let Some(last) = versions.last_mut() {
+            if let StoredValue::Current = last.value {
+                last.value = match self.data.get(key)? {
+                    Some(value) => StoredValue::Inline(value.to_vec()),
+                    None => StoredValue::Removed,
+                };
+            }
+        }
+        versions.push(StoredVersion {
+            seq,
+            written_at: now,
+            expires_at,
+            value: match value {
+                Some(_) => StoredValue::Current,
+                None => StoredValue::Removed,
+            },
+        });
+        self.put_versions(key, versions, retention, now)?;
+
+        match value {
+            Some(value) => {
+                if self.data.insert(key, value)?.is_none() {
+                    self.key_delta.set(self.key_delta.get() + 1);
+                }
+                match expires_at {
+                    Some(at) => self.expiries.insert(key, &at.to_be_bytes())?,
+                    None => self.expiries.remove(key)?,
+                };
+            }
+            None => {
+                if self.data.remove(key)?.is_some() {
+                    self.key_delta.set(self.key_delta.get() - 1);
+                }
+                self.expiries.remove(key)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn apply(
+        &self,
+        batch: &WriteBatch,
+        retention: Retention,
+    ) -> ConflictableTransactionResult<(), sled::Error> {
+        let now = now_millis();
+        for op in batch.ops() {
+            match op {
+                BatchOp::Set { key, value } => self.write(key, Some(value), None, retention)?,
+                BatchOp::SetWithTtl { key, value, ttl } => {
+                    let expires_at = now.saturating_add(ttl.as_millis() as u64);
+                    self.write(key, Some(value), Some(expires_at), retention)?
+                }
+                BatchOp::Remove { key } => self.write(key, None, None, retention)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    // removes a key that has expired, its value stays on as an old version if those are kept
+    fn expire(
+        &self,
+        key: &[u8],
+        retention: Retention,
+    ) -> ConflictableTransactionResult<(), sled::Error> {
+        let mut versions = self.versions(key)?;
+        let value = self.data.remove(key)?;
+        self.expiries.remove(key)?;
+        if value.is_some() {
+            self.key_delta.set(self.key_delta.get() - 1);
+        }
+
+        if let Some(last) = versions.last_mut() {
+            if let StoredValue::Current = last.value {
+                last.value = match value {
+                    Some(value) => StoredValue::Inline(value.to_vec()),
+                    None => StoredValue::Removed,
+                };
+            }
+        }
+
+        if retention == Retention::Latest {
+            versions.clear();
+        }
+        self.put_versions(key, versions, retention, now_millis())
+    }
+
+    // trims the versions of a key down to what the retention policy keeps and stores them
+    fn put_versions(
+        &self,
+        key: &[u8],
+        mut versions: Vec<StoredVersion>,
+        retention: Retention,
+        now: u64,
+    ) -> ConflictableTransactionResult<(), sled::Error> {
+        let written_at = versions
+            .iter()
+            .map(|version| version.written_at)
+            .collect::<Vec<_>>();
+        let mut num_expendable = num_expendable(retention, &written_at, now);
+
+        // whatever the policy, a version stays as long as an open snapshot may read it, that is
+        // until the oldest snapshot was taken after the write that replaced it
+        if let Some(oldest_snapshot) = self.oldest_snapshot {
+            let num_unread = versions
+                .windows(2)
+                .take_while(|pair| pair[1].seq <= oldest_snapshot)
+                .count();
+            num_expendable = num_expendable.min(num_unread);
+        }
+        versions.drain(..num_expendable);
+
+        // a removal with nothing kept before it reads the same as no version at all
+        let num_removals = versions
+            .iter()
+            .take_while(|version| matches!(version.value, StoredValue::Removed))
+            .count();
+        versions.drain(..num_removals);
+
+        if versions.is_empty() {
+            self.versions.remove(key)?;
+        } else {
+            self.versions.insert(key, encode_versions(&versions))?;
+        }
+
+        Ok(())
+    }
+}
+
+// a version of a key as kept in the versions tree
+#[derive(Debug, Clone)]
+struct StoredVersion {
+    seq: u64,
+    written_at: u64,
+    expires_at: Option<u64>,
+    value: StoredValue,
+}
+
+#[derive(Debug, Clone)]
+enum StoredValue {
+    Removed,
+    Inline(Vec<u8>),
+    Current, // the value in the data tree
 }
 
 impl SledKvsEngine {
-    /// create the sled db at some specified path
+    /// create the sled db at some specified path, flushing on every write
     pub fn open(path: impl Into<PathBuf>) -> Result<SledKvsEngine> {
+        Self::open_with_options(path, SledOptions::default())
+    }
+
+    /// create the sled db at some specified path with the given durability
+    ///
+    /// sled's flush already lets concurrent callers share one fsync, so group commit flushes on
+    /// every write just like `EveryWrite`. unlike KvStore, sled keeps unflushed writes in its own
+    /// cache, so with `Never` a crash of the process loses them too.
+    pub fn open_with_durability(
+        path: impl Into<PathBuf>,
+        durability: Durability,
+    ) -> Result<SledKvsEngine> {
+        Self::open_with_options(
+            path,
+            SledOptions {
+                durability,
+                ..SledOptions::default()
+            },
+        )
+    }
+
+    /// create the sled db at some specified path with non default options
+    pub fn open_with_options(
+        path: impl Into<PathBuf>,
+        options: SledOptions,
+    ) -> Result<SledKvsEngine> {
+        let path = path.into();
+        fs::create_dir_all(&path)?;
+        manifest::check_or_create(&path, EngineType::Sled, FORMAT_VERSION, options.describe())?;
+
+        let mut config = sled::Config::new().path(path.join("sled_db.log"));
+        if let Durability::EveryN(ms) = options.durability {
+            config = config.flush_every_ms(Some(ms));
+        }
+
+        let data = open_db(&config)?;
+        // ids only ever go up, so the one taken here is at least every sequence number so far and
+        // the next write gets a higher one. a fresh db starts at 0
+        let last_seq = data.generate_id()?;
+        let trees = Trees {
+            expiries: data.open_tree("expiries")?,
+            versions: data.open_tree("versions")?,
+            last_seq: Arc::new(AtomicU64::new(last_seq)),
+            keys: Arc::new(AtomicI64::new(data.len() as i64)),
+            snapshot_lock: Arc::new(RwLock::new(())),
+            snapshots: Arc::new(Mutex::new(BTreeMap::new())),
+            data,
+        };
+
+        let sweeper = {
+            let (trees, retention) = (trees.clone(), options.retention);
+            PeriodicTask::spawn("expiry sweep", SWEEP_INTERVAL, move || {
+                sweep_expired(&trees, retention)
+            })
+        };
+
         Ok(Self {
-            inner: sled::open(path.into().join("sled_db.log"))?,
+            trees,
+            durability: options.durability,
+            retention: options.retention,
+            _sweeper: Arc::new(sweeper),
         })
     }
+
+    fn flush_if_required(&self) -> Result<()> {
+        match self.durability {
+            Durability::EveryWrite | Durability::GroupCommit => {
+                self.trees.data.flush()?;
+            }
+            Durability::Never | Durability::EveryN(_) => {}
+        }
+
+        Ok(())
+    }
+
+    // when the key expires, `None` if it never does
+    fn expires_at(&self, key: &[u8]) -> Result<Option<u64>> {
+        Ok(self.trees.expiries.get(key)?.map(|at| decode_u64(&at)))
+    }
+
+    fn is_expired(&self, key: &[u8]) -> Result<bool> {
+        Ok(self.expires_at(key)?.is_some_and(|at| at <= now_millis()))
+    }
+
+    // sets the value along with its expiry in one transaction, `None` clears any old expiry
+    fn write_set(&self, key: &[u8], value: &[u8], expires_at: Option<u64>) -> Result<()> {
+        let retention = self.retention;
+        self.trees
+            .transaction(|trees| trees.write(key, Some(value), expires_at, retention))?;
+
+        self.flush_if_required()
+    }
 }
 
 impl KvsEngine for SledKvsEngine {
-    fn get(&mut self, key: String) -> Result<Option<String>> {
-        Ok(self
-            .inner
-            .get(key)?
-            .map(|i_vec| AsRef::<[u8]>::as_ref(&i_vec).to_vec())
-            .map(String::from_utf8)
-            .transpose()?)
+    fn scan(&self, range: KeyRange) -> Result<KvPairs<'_>> {
+        Ok(unexpired(
+            &self.trees.expiries,
+            self.trees.data.range(range),
+        ))
     }
 
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        self.inner.insert(key, value.into_bytes()).map(|_| ())?;
-        self.inner.flush()?;
-        Ok(())
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<KvPairs<'_>> {
+        Ok(unexpired(
+            &self.trees.expiries,
+            self.trees.data.scan_prefix(prefix),
+        ))
     }
 
-    fn remove(&mut self, key: String) -> Result<()> {
-        let result = self.inner.remove(key)?;
-        self.inner.flush()?;
+    fn scan_keys(&self, range: KeyRange) -> Result<Keys<'_>> {
+        let now = now_millis();
+        let expiries = self.trees.expiries.clone();
+        Ok(Box::new(self.trees.data.range(range).keys().filter_map(
+            move |key| {
+                let key = key.and_then(|key| {
+                    let expired = expiries.get(&key)?.is_some_and(|at| decode_u64(&at) <= now);
+                    Ok((key, expired))
+                });
 
-        if result.is_some() {
-            Ok(())
+                match key {
+                    Ok((_, true)) => None,
+                    Ok((key, false)) => Some(Ok(key.to_vec())),
+                    Err(e) => Some(Err(e.into())),
+                }
+            },
+        )))
+    }
+
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if self.is_expired(key)? {
+            return Ok(None);
+        }
+
+        Ok(self.trees.data.get(key)?.map(|i_vec| i_vec.to_vec()))
+    }
+
+    fn get_with_seq(&self, key: &[u8]) -> Result<Option<(Vec<u8>, u64)>> {
+        let now = now_millis();
+        self.trees
+            .transaction(|trees| trees.current_with_seq(key, now))
+    }
+
+    fn set_bytes(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.write_set(key, value, None)
+    }
+
+    fn remove_bytes(&self, key: &[u8]) -> Result<()> {
+        let (now, retention) = (now_millis(), self.retention);
+        let removed = self.trees.transaction(|trees| {
+            if trees.current(key, now)?.is_none() {
+                return Ok(false);
+            }
+
+            trees.write(key, None, None, retention)?;
+            Ok(true)
+        })?;
+
+        if removed {
+            self.flush_if_required()
         } else {
-            Err(format_err!("Removing non existent key"))
+            Err(KvsError::KeyNotFound)
         }
     }
+
+    fn set_bytes_with_ttl(&self, key: &[u8], value: &[u8], ttl: Duration) -> Result<()> {
+        let expires_at = now_millis().saturating_add(ttl.as_millis() as u64);
+        self.write_set(key, value, Some(expires_at))
+    }
+
+    fn ttl(&self, key: &[u8]) -> Result<KeyTtl> {
+        if !self.trees.data.contains_key(key)? {
+            return Ok(KeyTtl::Missing);
+        }
+
+        let now = now_millis();
+        Ok(match self.expires_at(key)? {
+            None => KeyTtl::NoExpiry,
+            Some(at) if at <= now => KeyTtl::Missing,
+            Some(at) => KeyTtl::ExpiresIn(Duration::from_millis(at - now)),
+        })
+    }
+
+    fn apply_batch(&self, batch: WriteBatch) -> Result<()> {
+        let retention = self.retention;
+        self.trees
+            .transaction(|trees| trees.apply(&batch, retention))?;
+
+        self.flush_if_required()
+    }
+
+    fn set_if(&self, key: &[u8], value: &[u8], condition: SetCondition) -> Result<SetOutcome> {
+        let (now, retention) = (now_millis(), self.retention);
+        let outcome = self.trees.transaction(|trees| {
+            let current = trees.current(key, now)?;
+            if !condition.is_met_by(current.as_deref()) {
+                return Ok(SetOutcome::Conflict(current));
+            }
+
+            trees.write(key, Some(value), None, retention)?;
+            Ok(SetOutcome::Written)
+        })?;
+
+        if outcome == SetOutcome::Written {
+            self.flush_if_required()?;
+        }
+
+        Ok(outcome)
+    }
+
+    fn commit(&self, transaction: Transaction) -> Result<()> {
+        let (now, retention, batch) = (now_millis(), self.retention, transaction.writes());
+        let conflict = self.trees.transaction(|trees| {
+            for (key, seq) in transaction.reads() {
+                if trees.current_with_seq(key, now)?.map(|(_, seq)| seq) != seq {
+                    return Ok(Some(key.to_vec()));
+                }
+            }
+
+            trees.apply(&batch, retention)?;
+            Ok(None)
+        })?;
+
+        if let Some(key) = conflict {
+            return Err(KvsError::TransactionConflict { key });
+        }
+
+        self.flush_if_required()
+    }
+
+    fn key_count(&self) -> Result<u64> {
+        Ok(self.trees.keys.load(Ordering::SeqCst).max(0) as u64)
+    }
+
+    fn current_seq(&self) -> Result<u64> {
+        Ok(self.trees.last_seq.load(Ordering::SeqCst))
+    }
+
+    fn get_at(&self, key: &[u8], at: ReadAt) -> Result<Option<Vec<u8>>> {
+        self.trees.transaction(|trees| {
+            let versions = trees.versions(key)?;
+            let version = match at {
+                ReadAt::Seq(seq) => versions.iter().rev().find(|version| version.seq <= seq),
+                ReadAt::Time(time) => {
+                    let at = unix_millis(time);
+                    versions
+                        .iter()
+                        .rev()
+                        .find(|version| version.written_at <= at)
+                        .filter(|version| version.expires_at.is_none_or(|expiry| expiry > at))
+                }
+            };
+
+            Ok(match version.map(|version| &version.value) {
+                Some(StoredValue::Inline(value)) => Some(value.clone()),
+                Some(StoredValue::Current) => trees.data.get(key)?.map(|value| value.to_vec()),
+                Some(StoredValue::Removed) | None => None,
+            })
+        })
+    }
+
+    fn history(&self, key: &[u8]) -> Result<Vec<Version>> {
+        self.trees.transaction(|trees| {
+            trees
+                .versions(key)?
+                .into_iter()
+                .map(|version| {
+                    let value = match version.value {
+                        StoredValue::Removed => None,
+                        StoredValue::Inline(value) => Some(value),
+                        StoredValue::Current => trees.data.get(key)?.map(|value| value.to_vec()),
+                    };
+
+                    Ok(Version {
+                        seq: version.seq,
+                        written_at: system_time(version.written_at),
+                        value,
+                        expires_at: version.expires_at.map(system_time),
+                    })
+                })
+                .collect()
+        })
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.trees.data.flush()?;
+        Ok(())
+    }
+
+    fn snapshot(&self) -> Result<Box<dyn KvsSnapshot + Send>> {
+        // every write goes through a transaction, so once the lock is held none is half way
+        // through and every write up to the latest sequence number is in
+        let _guard = self
+            .trees
+            .snapshot_lock
+            .write()
+            .expect("SledKvsEngine snapshot lock poisoned");
+        let (seq, taken_at) = (self.current_seq()?, now_millis());
+        *self.trees.snapshots().entry((seq, taken_at)).or_insert(0) += 1;
+
+        Ok(Box::new(SledSnapshot {
+            trees: self.trees.clone(),
+            seq,
+            taken_at,
+        }))
+    }
+
+    fn persist(&self, key: &[u8]) -> Result<bool> {
+        // the value is written again without an expiry so dropping it shows up in the history
+        let (now, retention) = (now_millis(), self.retention);
+        let persisted = self.trees.transaction(|trees| {
+            let expiring = trees
+                .expiries
+                .get(key)?
+                .is_some_and(|at| decode_u64(&at) > now);
+            match trees.data.get(key)? {
+                Some(value) if expiring => {
+                    trees.write(key, Some(&value), None, retention)?;
+                    Ok(true)
+                }
+                _ => Ok(false),
+            }
+        })?;
+
+        if persisted {
+            self.flush_if_required()?;
+        }
+
+        Ok(persisted)
+    }
+}
+
+/// a point-in-time view of a sled db
+///
+/// sled has no snapshots of its own, so this reads every key as of the sequence number of the
+/// last write before it was taken. the versions it may read are kept, whatever the retention
+/// policy, until it's dropped.
+pub struct SledSnapshot {
+    trees: Trees,
+    seq: u64,
+    taken_at: u64,
+}
+
+impl KvsSnapshot for SledSnapshot {
+    fn get_bytes(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let (seq, taken_at) = (self.seq, self.taken_at);
+        self.trees
+            .transaction(|trees| trees.value_at(key, seq, taken_at))
+    }
+
+    fn scan(&mut self, range: KeyRange) -> Result<KvPairs<'_>> {
+        // keys removed since the snapshot was taken are only left in the versions tree
+        let keys = merged_keys(
+            self.trees.data.range(range.clone()).keys(),
+            self.trees.versions.range(range).keys(),
+        );
+        let (trees, seq, taken_at) = (&self.trees, self.seq, self.taken_at);
+        Ok(Box::new(keys.filter_map(move |key| {
+            let pair = key.map_err(KvsError::from).and_then(|key| {
+                let value = trees.transaction(|trees| trees.value_at(&key, seq, taken_at))?;
+                Ok(value.map(|value| (key.to_vec(), value)))
+            });
+            pair.transpose()
+        })))
+    }
+}
+
+impl Drop for SledSnapshot {
+    fn drop(&mut self) {
+        let mut snapshots = self.trees.snapshots();
+        let key = (self.seq, self.taken_at);
+        if let Some(count) = snapshots.get_mut(&key) {
+            *count -= 1;
+            if *count == 0 {
+                snapshots.remove(&key);
+            }
+        }
+    }
+}
+
+// every key in either of two key ordered iterators, once
+fn merged_keys<A, B>(a: A, b: B) -> impl Iterator<Item = sled::Result<sled::IVec>>
+where
+    A: Iterator<Item = sled::Result<sled::IVec>>,
+    B: Iterator<Item = sled::Result<sled::IVec>>,
+{
+    let (mut a, mut b): (Peekable<A>, Peekable<B>) = (a.peekable(), b.peekable());
+    iter::from_fn(move || match (a.peek(), b.peek()) {
+        (Some(Ok(key_a)), Some(Ok(key_b))) if key_a == key_b => {
+            b.next();
+            a.next()
+        }
+        (Some(Ok(key_a)), Some(Ok(key_b))) if key_a > key_b => b.next(),
+        (Some(_), _) => a.next(),
+        (None, _) => b.next(),
+    })
+}
+
+// sled releases the lock on its directory some time after the last handle is dropped, from its own
+// background threads, so opening it again straight after closing it can briefly fail
+fn open_db(config: &sled::Config) -> Result<sled::Db> {
+    let mut retries = 0;
+    loop {
+        match config.open() {
+            Err(sled::Error::Io(e))
+                if retries < LOCK_RETRIES && e.to_string().contains("could not acquire lock") =>
+            {
+                retries += 1;
+                thread::sleep(LOCK_RETRY_DELAY);
+            }
+            result => return Ok(result?),
+        }
+    }
+}
+
+// leaves out the KV pairs whose keys have expired
+fn unexpired<'a>(
+    expiries: &sled::Tree,
+    pairs: impl Iterator<Item = sled::Result<(sled::IVec, sled::IVec)>> + 'a,
+) -> KvPairs<'a> {
+    let now = now_millis();
+    let expiries = expiries.clone();
+    Box::new(pairs.filter_map(move |entry| {
+        let pair = entry.and_then(|(key, value)| {
+            let expired = expiries.get(&key)?.is_some_and(|at| decode_u64(&at) <= now);
+            Ok((key, value, expired))
+        });
+
+        match pair {
+            Ok((_, _, true)) => None,
+            Ok((key, value, false)) => Some(Ok((key.to_vec(), value.to_vec()))),
+            Err(e) => Some(Err(e.into())),
+        }
+    }))
+}
+
+fn decode_u64(bytes: &[u8]) -> u64 {
+    let mut buf = [0; 8];
+    buf.copy_from_slice(bytes);
+    u64::from_be_bytes(buf)
+}
+
+// | seq (8) | written at (8) | expires at (8) | state (1) | value len (4) | value | per version,
+// big endian. expires at is 0 for values that never expire, state is 0 for a removal, 1 for a
+// value held inline and 2 for the value in the data tree
+fn encode_versions(versions: &[StoredVersion]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for version in versions {
+        buf.extend_from_slice(&version.seq.to_be_bytes());
+        buf.extend_from_slice(&version.written_at.to_be_bytes());
+        buf.extend_from_slice(&version.expires_at.unwrap_or(0).to_be_bytes());
+        let (state, value): (u8, &[u8]) = match &version.value {
+            StoredValue::Removed => (0, &[]),
+            StoredValue::Inline(value) => (1, value),
+            StoredValue::Current => (2, &[]),
+        };
+        buf.push(state);
+        buf.extend_from_slice(&(value.len() as u32).to_be_bytes());
+        buf.extend_from_slice(value);
+    }
+    buf
+}
+
+fn decode_versions(mut bytes: &[u8]) -> sled::Result<Vec<StoredVersion>> {
+    let mut versions = Vec::new();
+    while !bytes.is_empty() {
+        if bytes.len() < 29 {
+            return Err(corrupt_versions());
+        }
+        let (seq, written_at, expires_at) = (
+            decode_u64(&bytes[0..8]),
+            decode_u64(&bytes[8..16]),
+            decode_u64(&bytes[16..24]),
+        );
+        let state = bytes[24];
+        let value_len = u32::from_be_bytes([bytes[25], bytes[26], bytes[27], bytes[28]]) as usize;
+        let value = bytes.get(29..29 + value_len).ok_or_else(corrupt_versions)?;
+
+        versions.push(StoredVersion {
+            seq,
+            written_at,
+            expires_at: Some(expires_at).filter(|&at| at != 0),
+            value: match state {
+                0 => StoredValue::Removed,
+                1 => StoredValue::Inline(value.to_vec()),
+                2 => StoredValue::Current,
+                _ => return Err(corrupt_versions()),
+            },
+        });
+        bytes = &bytes[29 + value_len..];
+    }
+
+    Ok(versions)
+}
+
+fn corrupt_versions() -> sled::Error {
+    sled::Error::Unsupported(String::from("corrupt version list"))
+}
+
+// removes every key that has expired, unless it was set again in the meantime. keys an open
+// snapshot still sees as live are left for a later sweep
+fn sweep_expired(trees: &Trees, retention: Retention) -> Result<()> {
+    let now = now_millis();
+    let visible_until = trees.oldest_snapshot().map(|(_, taken_at)| taken_at);
+
+    for entry in trees.expiries.iter() {
+        let (key, at) = entry?;
+        let at_millis = decode_u64(&at);
+        if at_millis > now || visible_until.is_some_and(|taken_at| taken_at < at_millis) {
+            continue;
+        }
+
+        trees.transaction(|trees| {
+            if trees.expiries.get(&key)?.as_ref() == Some(&at) {
+                trees.expire(&key, retention)?;
+            }
+            Ok(())
+        })?;
+    }
+
+    Ok(())
 }
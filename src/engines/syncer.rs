@@ -0,0 +1,119 @@
+//! makes writes to KvStore's active log segment durable according to its `Durability`
+
+use super::PeriodicTask;
+use crate::{Durability, Result};
+use std::fs::File;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+/// tracks which writes have been fsynced
+pub(crate) struct LogSyncer {
+    durability: Durability,
+    state: Mutex<SyncState>,
+    synced: Condvar, // signalled whenever an fsync finishes
+}
+
+struct SyncState {
+    active: File,  // handle to the segment currently being appended to
+    written: u64,  // number of writes made so far
+    synced: u64,   // number of writes known to be on disk
+    syncing: bool, // whether some writer is in the middle of an fsync for everyone
+}
+
+impl LogSyncer {
+    pub(crate) fn new(durability: Durability, active: File) -> Self {
+        Self {
+            durability,
+            state: Mutex::new(SyncState {
+                active,
+                written: 0,
+                synced: 0,
+                syncing: false,
+            }),
+            synced: Condvar::new(),
+        }
+    }
+
+    /// starts the background thread if the policy syncs on an interval, it stops when dropped
+    pub(crate) fn spawn_interval_syncer(syncer: &Arc<LogSyncer>) -> Option<PeriodicTask> {
+        let interval = match syncer.durability {
+            Durability::EveryN(ms) => Duration::from_millis(ms),
+            _ => return None,
+        };
+
+        let syncer = Arc::clone(syncer);
+        Some(PeriodicTask::spawn("log sync", interval, move || {
+            syncer.sync_pending()
+        }))
+    }
+
+    /// records that a write reached the active segment, returns a ticket for `wait_durable`
+    pub(crate) fn written(&self) -> u64 {
+        let mut state = self.state.lock().expect("LogSyncer lock poisoned");
+        state.written += 1;
+        state.written
+    }
+
+    /// blocks until the write behind `ticket` is as durable as the policy asks for
+    pub(crate) fn wait_durable(&self, ticket: u64) -> Result<()> {
+        match self.durability {
+            Durability::Never | Durability::EveryN(_) => Ok(()),
+            Durability::EveryWrite => self.sync_pending(),
+            Durability::GroupCommit => self.group_commit(ticket),
+        }
+    }
+
+    /// fsyncs the active segment if anything was written since the last fsync
+    pub(crate) fn sync_pending(&self) -> Result<()> {
+        let mut state = self.state.lock().expect("LogSyncer lock poisoned");
+        if state.synced < state.written {
+            state.active.sync_data()?;
+            state.synced = state.written;
+            self.synced.notify_all();
+        }
+
+        Ok(())
+    }
+
+    /// switches to a new active segment, making the outgoing one durable first unless the policy
+    /// never syncs
+    pub(crate) fn roll_over(&self, active: File) -> Result<()> {
+        if self.durability != Durability::Never {
+            self.sync_pending()?;
+        }
+
+        self.state.lock().expect("LogSyncer lock poisoned").active = active;
+        Ok(())
+    }
+
+    // the first writer to arrive fsyncs on behalf of every write made so far, the rest wait for it
+    fn group_commit(&self, ticket: u64) -> Result<()> {
+        let mut state = self.state.lock().expect("LogSyncer lock poisoned");
+
+        loop {
+            if state.synced >= ticket {
+                return Ok(());
+            }
+
+            if state.syncing {
+                state = self.synced.wait(state).expect("LogSyncer lock poisoned");
+                continue;
+            }
+
+            state.syncing = true;
+            let target = state.written;
+            let active = state.active.try_clone();
+            drop(state);
+
+            let result = active.and_then(|active| active.sync_data());
+
+            state = self.state.lock().expect("LogSyncer lock poisoned");
+            state.syncing = false;
+            if result.is_ok() {
+                state.synced = state.synced.max(target);
+            }
+            self.synced.notify_all();
+            result?;
+        }
+    }
+}
@@ -0,0 +1,161 @@
+//! the one error type everything in the crate fails with, the server sends it to clients as is
+
+use crate::{base64_bytes, EngineType};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io;
+use std::num::ParseIntError;
+use std::string::FromUtf8Error;
+
+/// what went wrong, locally or on the server
+#[derive(Debug, Serialize, Deserialize)]
+pub enum KvsError {
+    /// the key to remove isn't set
+    KeyNotFound,
+
+    /// reading or writing a file or socket failed
+    Io(#[serde(with = "io_error")] io::Error),
+
+    /// a request, response or value couldn't be encoded or decoded
+    Serialization(String),
+
+    /// the store's files hold something that isn't a valid record
+    CorruptLog(String),
+
+    /// the directory already holds the data of a different engine
+    IncompatibleEngine {
+        /// the engine that was asked for
+        requested: EngineType,
+        /// the engine whose files are there
+        existing: EngineType,
+    },
+
+    /// the directory holds data in a newer on-disk format than this build reads
+    UnsupportedFormat {
+        /// the engine whose data it is
+        engine: EngineType,
+        /// the format version the data is in
+        version: u32,
+        /// the newest format version this build reads
+        supported: u32,
+    },
+
+    /// the other end broke the protocol, or a request isn't allowed where it was sent
+    Protocol(String),
+
+    /// a transaction wasn't committed because a key it read has been written since
+    TransactionConflict {
+        /// the key that was written
+        #[serde(with = "base64_bytes")]
+        key: Vec<u8>,
+    },
+
+    /// a setting or argument has a value that makes no sense
+    InvalidArgument(String),
+
+    /// the storage engine failed in a way none of the other variants covers
+    Engine(String),
+}
+
+impl fmt::Display for KvsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::KeyNotFound => write!(f, "Key not found"),
+            Self::Io(e) => write!(f, "IO error: {}", e),
+            Self::Serialization(message) => write!(f, "Serialization error: {}", message),
+            Self::CorruptLog(message) => write!(f, "Corrupt log: {}", message),
+            Self::IncompatibleEngine {
+                requested,
+                existing,
+            } => write!(
+                f,
+                "Incompatible engine specified: user: {:?}, existing: {:?}",
+                requested, existing
+            ),
+            Self::UnsupportedFormat {
+                engine,
+                version,
+                supported,
+            } => write!(
+                f,
+                "Unsupported format: the {} store is format version {}, this build reads up to {}",
+                engine, version, supported
+            ),
+            Self::Protocol(message) => write!(f, "Protocol error: {}", message),
+            Self::TransactionConflict { key } => write!(
+                f,
+                "Transaction conflict: {} was written since it was read",
+                String::from_utf8_lossy(key)
+            ),
+            Self::InvalidArgument(message) => write!(f, "Invalid argument: {}", message),
+            Self::Engine(message) => write!(f, "Engine error: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for KvsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for KvsError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for KvsError {
+    fn from(e: serde_json::Error) -> Self {
+        Self::Serialization(e.to_string())
+    }
+}
+
+impl From<FromUtf8Error> for KvsError {
+    fn from(e: FromUtf8Error) -> Self {
+        Self::Serialization(e.to_string())
+    }
+}
+
+impl From<ParseIntError> for KvsError {
+    fn from(e: ParseIntError) -> Self {
+        Self::InvalidArgument(e.to_string())
+    }
+}
+
+impl From<sled::Error> for KvsError {
+    fn from(e: sled::Error) -> Self {
+        match e {
+            sled::Error::Io(e) => Self::Io(e),
+            sled::Error::Corruption { .. } => Self::CorruptLog(e.to_string()),
+            e => Self::Engine(e.to_string()),
+        }
+    }
+}
+
+impl From<sled::transaction::TransactionError<sled::Error>> for KvsError {
+    fn from(e: sled::transaction::TransactionError<sled::Error>) -> Self {
+        match e {
+            sled::transaction::TransactionError::Abort(e)
+            | sled::transaction::TransactionError::Storage(e) => e.into(),
+        }
+    }
+}
+
+// io errors go over the wire as their message, and come back as `io::ErrorKind::Other` ones
+mod io_error {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::io;
+
+    pub fn serialize<S: Serializer>(e: &io::Error, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&e.to_string())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<io::Error, D::Error> {
+        let message = String::deserialize(deserializer)?;
+        Ok(io::Error::other(message))
+    }
+}
@@ -0,0 +1,381 @@
+//! an HTTP/1.1 gateway, which kvs-server serves with `--protocol http`
+//!
+//! - `GET /keys/{key}` reads a value, `PUT` sets it to the request body and `DELETE` removes it,
+//!   a `ttl` query parameter on `PUT` has the key expire after that many seconds
+//! - `GET /keys?prefix=&limit=&cursor=` lists a page of the keys starting with the prefix, as
+//!   `{"keys": [...], "next": ...}`. `next` is the `cursor` to get the following page with
+//! - `GET /health` and `GET /stats` tell whether the server is up and what it holds
+//!
+//! keys in paths and queries are percent-decoded, values go over as the raw body. listed keys are
+//! base64, as on the JSON protocol, and cursors URL-safe base64.
+
+use crate::{prefix_range, KvsEngine, KvsError, Result};
+use serde_json::json;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::net::TcpStream;
+use std::ops::Bound;
+use std::time::Duration;
+
+// the longest request line or header line read before the request is refused
+const MAX_LINE_LEN: u64 = 8 * 1024;
+
+const MAX_HEADERS: usize = 100;
+
+// the biggest value a PUT can set
+const MAX_BODY_LEN: usize = 16 * 1024 * 1024;
+
+// how many keys a page of `GET /keys` holds unless `limit` says otherwise, and at most
+const DEFAULT_LIST_LIMIT: usize = 1000;
+const MAX_LIST_LIMIT: usize = 10_000;
+
+struct Request {
+    method: String,
+    path: Vec<u8>,
+    query: Vec<(Vec<u8>, Vec<u8>)>,
+    body: Vec<u8>,
+    keep_alive: bool,
+}
+
+impl Request {
+    fn query(&self, name: &str) -> Option<&[u8]> {
+        self.query
+            .iter()
+            .find(|(key, _)| key == name.as_bytes())
+            .map(|(_, value)| value.as_slice())
+    }
+}
+
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    allow: Option<&'static str>, // the methods a path does take, for 405s
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn json(status: u16, body: serde_json::Value) -> Self {
+        Self {
+            status,
+            content_type: "application/json",
+            allow: None,
+            body: body.to_string().into_bytes(),
+        }
+    }
+
+    fn error(status: u16, message: &str) -> Self {
+        Self::json(status, json!({ "error": message }))
+    }
+
+    fn no_content() -> Self {
+        Self {
+            status: 204,
+            content_type: "application/json",
+            allow: None,
+            body: Vec::new(),
+        }
+    }
+
+    fn method_not_allowed(allow: &'static str) -> Self {
+        Self {
+            allow: Some(allow),
+            ..Self::error(405, "Method not allowed")
+        }
+    }
+
+    fn write_to(&self, writer: &mut impl Write, keep_alive: bool) -> Result<()> {
+        write!(
+            writer,
+            "HTTP/1.1 {} {}\r\nContent-Length: {}\r\n",
+            self.status,
+            reason(self.status),
+            self.body.len()
+        )?;
+        if !self.body.is_empty() {
+            write!(writer, "Content-Type: {}\r\n", self.content_type)?;
+        }
+        if let Some(allow) = self.allow {
+            write!(writer, "Allow: {}\r\n", allow)?;
+        }
+        if !keep_alive {
+            writer.write_all(b"Connection: close\r\n")?;
+        }
+        writer.write_all(b"\r\n")?;
+        writer.write_all(&self.body)?;
+        Ok(())
+    }
+}
+
+// GET /keys, a page of the keys starting with `prefix` from `cursor` on
+//
+// keys go over as base64 like on the JSON protocol, so any bytes round-trip. `next` is where the
+// following page starts, `null` once there are no more. it's URL-safe base64 so it can go in the
+// query as it is
+fn list_keys(engine: &impl KvsEngine, request: &Request) -> Result<Response> {
+    let limit = match request.query("limit") {
+        Some(limit) => match std::str::from_utf8(limit).ok().and_then(|s| s.parse().ok()) {
+            Some(limit) if limit > 0 && limit <= MAX_LIST_LIMIT => limit,
+            _ => return Ok(Response::error(400, "invalid limit")),
+        },
+        None => DEFAULT_LIST_LIMIT,
+    };
+    let (mut start, end) = prefix_range(request.query("prefix").unwrap_or_default());
+    if let Some(cursor) = request.query("cursor") {
+        let cursor = match base64::decode_config(cursor, base64::URL_SAFE_NO_PAD) {
+            Ok(cursor) => cursor,
+            Err(_) => return Ok(Response::error(400, "invalid cursor")),
+        };
+        // a cursor from before the prefix starts at the prefix instead
+        if matches!(&start, Bound::Included(prefix) if *prefix < cursor) {
+            start = Bound::Included(cursor);
+        }
+    }
+
+    // one key past the page tells where the next page starts
+    let mut keys = engine
+        .scan_keys((start, end))?
+        .take(limit + 1)
+        .collect::<Result<Vec<_>>>()?;
+    let next = if keys.len() > limit {
+        keys.pop()
+            .map(|key| base64::encode_config(key, base64::URL_SAFE_NO_PAD))
+    } else {
+        None
+    };
+    let keys = keys.into_iter().map(base64::encode).collect::<Vec<_>>();
+    Ok(Response::json(200, json!({ "keys": keys, "next": next })))
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        500 => "Internal Server Error",
+        _ => "Unknown",
+    }
+}
+
+// serves requests from one client until it closes the connection or asks for it to be closed
+//
+// responses are flushed once every request already received has been answered. a request that
+// can't be parsed leaves no way to find where the next one starts, so it's answered and the
+// connection closed.
+pub(crate) fn handle_connection(engine: &impl KvsEngine, stream: TcpStream) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    loop {
+        let request = match read_request(&mut reader) {
+            Ok(Some(request)) => request,
+            Ok(None) => return Ok(()),
+            Err(KvsError::Protocol(message)) => {
+                Response::error(400, &message).write_to(&mut writer, false)?;
+                writer.flush()?;
+                return Ok(());
+            }
+            // only a body too big to take, refused before it's read
+            Err(KvsError::InvalidArgument(message)) => {
+                Response::error(413, &message).write_to(&mut writer, false)?;
+                writer.flush()?;
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+
+        let response =
+            respond(engine, &request).unwrap_or_else(|e| Response::error(500, &e.to_string()));
+        response.write_to(&mut writer, request.keep_alive)?;
+        if !request.keep_alive {
+            writer.flush()?;
+            return Ok(());
+        }
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+}
+
+// reads the next request, `None` if the client closed the connection in between requests
+fn read_request(reader: &mut impl BufRead) -> Result<Option<Request>> {
+    let request_line = match read_line(reader)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    let mut parts = request_line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version), None) if version.starts_with("HTTP/1.") => {
+            (method.to_owned(), target.to_owned(), version.to_owned())
+        }
+        _ => return Err(bad_request("malformed request line")),
+    };
+
+    // HTTP/1.1 keeps the connection open unless told not to, 1.0 closes it unless told to keep it
+    let mut keep_alive = version != "HTTP/1.0";
+    let mut content_length = 0;
+    for headers in 0.. {
+        let line = read_line(reader)?.ok_or_else(|| bad_request("connection closed in headers"))?;
+        if line.is_empty() {
+            break;
+        }
+        if headers == MAX_HEADERS {
+            return Err(bad_request("too many headers"));
+        }
+
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| bad_request("malformed header"))?;
+        let value = value.trim();
+        match name.to_ascii_lowercase().as_str() {
+            "content-length" => {
+                content_length = value
+                    .parse()
+                    .map_err(|_| bad_request("invalid Content-Length"))?
+            }
+            "transfer-encoding" => {
+                return Err(bad_request(
+                    "chunked bodies aren't supported, send a Content-Length",
+                ))
+            }
+            "connection" if value.eq_ignore_ascii_case("close") => keep_alive = false,
+            "connection" if value.eq_ignore_ascii_case("keep-alive") => keep_alive = true,
+            _ => {}
+        }
+    }
+
+    if content_length > MAX_BODY_LEN {
+        return Err(KvsError::InvalidArgument(String::from(
+            "request body too large",
+        )));
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, query),
+        None => (target.as_str(), ""),
+    };
+    Ok(Some(Request {
+        method,
+        path: percent_decode(path.as_bytes(), false)?,
+        query: query
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                Ok((
+                    percent_decode(name.as_bytes(), true)?,
+                    percent_decode(value.as_bytes(), true)?,
+                ))
+            })
+            .collect::<Result<_>>()?,
+        body,
+        keep_alive,
+    }))
+}
+
+// reads a line ending in CRLF, or a bare LF, and returns it without the line ending
+fn read_line(reader: &mut impl BufRead) -> Result<Option<String>> {
+    let mut line = Vec::new();
+    if reader.take(MAX_LINE_LEN).read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with(b"\n") {
+        return Err(bad_request("line too long"));
+    }
+    line.pop();
+    if line.ends_with(b"\r") {
+        line.pop();
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|_| bad_request("request line or header isn't UTF-8"))
+}
+
+// decodes %XX escapes, and `+` as a space in query strings
+fn percent_decode(encoded: &[u8], query: bool) -> Result<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(encoded.len());
+    let mut bytes = encoded.iter();
+    while let Some(&b) = bytes.next() {
+        match b {
+            b'%' => {
+                let hex = [
+                    *bytes
+                        .next()
+                        .ok_or_else(|| bad_request("truncated % escape"))?,
+                    *bytes
+                        .next()
+                        .ok_or_else(|| bad_request("truncated % escape"))?,
+                ];
+                let byte = std::str::from_utf8(&hex)
+                    .ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .ok_or_else(|| bad_request("invalid % escape"))?;
+                decoded.push(byte);
+            }
+            b'+' if query => decoded.push(b' '),
+            b => decoded.push(b),
+        }
+    }
+    Ok(decoded)
+}
+
+fn bad_request(message: &str) -> KvsError {
+    KvsError::Protocol(message.to_owned())
+}
+
+fn respond(engine: &impl KvsEngine, request: &Request) -> Result<Response> {
+    let method = request.method.as_str();
+    if let Some(key) = request.path.strip_prefix(b"/keys/") {
+        if key.is_empty() {
+            return Ok(Response::error(404, "Not found"));
+        }
+        return Ok(match method {
+            "GET" => match engine.get_bytes(key)? {
+                Some(value) => Response {
+                    status: 200,
+                    content_type: "application/octet-stream",
+                    allow: None,
+                    body: value,
+                },
+                None => Response::error(404, "Key not found"),
+            },
+            "PUT" => {
+                match request.query("ttl") {
+                    Some(ttl) => {
+                        let ttl = match std::str::from_utf8(ttl).ok().and_then(|s| s.parse().ok()) {
+                            Some(seconds) if seconds > 0 => Duration::from_secs(seconds),
+                            _ => return Ok(Response::error(400, "invalid ttl")),
+                        };
+                        engine.set_bytes_with_ttl(key, &request.body, ttl)?
+                    }
+                    None => engine.set_bytes(key, &request.body)?,
+                }
+                Response::no_content()
+            }
+            "DELETE" => match engine.remove_bytes(key) {
+                Ok(()) => Response::no_content(),
+                Err(KvsError::KeyNotFound) => Response::error(404, "Key not found"),
+                Err(e) => return Err(e),
+            },
+            _ => Response::method_not_allowed("GET, PUT, DELETE"),
+        });
+    }
+
+    Ok(match (request.path.as_slice(), method) {
+        (b"/keys", "GET") => list_keys(engine, request)?,
+        (b"/health", "GET") => Response::json(200, json!({ "status": "ok" })),
+        (b"/stats", "GET") => Response::json(
+            200,
+            json!({
+                "version": env!("CARGO_PKG_VERSION"),
+                "keys": engine.key_count()?,
+                "current_seq": engine.current_seq()?,
+            }),
+        ),
+        (b"/keys", _) | (b"/health", _) | (b"/stats", _) => Response::method_not_allowed("GET"),
+        _ => Response::error(404, "Not found"),
+    })
+}
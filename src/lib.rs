@@ -2,257 +2,1302 @@
 
 #![deny(missing_docs)]
 
-use failure::format_err;
+use log::{error, info, warn};
 use serde::{Deserialize, Serialize};
-use std::net::{SocketAddr, TcpListener};
+use shutdown::Connections;
+use std::collections::BTreeMap;
+use std::net::{Shutdown, SocketAddr, TcpListener};
+use std::ops::Bound;
+use std::path::Path;
 use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
 use std::{
-    env, fs,
-    io::{BufRead, BufReader, Read, Write},
+    fmt,
+    io::{self, BufRead, BufReader, BufWriter, Write},
     net::TcpStream,
-    u64,
 };
+use thread_pool::ThreadPool;
 
+mod background;
+mod base64_bytes;
+mod config;
 mod engines;
+mod error;
+mod http;
+mod memcached;
+pub mod resp;
+mod shutdown;
+pub mod thread_pool;
 
-pub use engines::kvs::KvStore;
-pub use engines::sled::SledKvsEngine;
+pub use background::BackgroundServer;
+pub use config::ServerConfig;
+pub use engines::kvs::{KvStore, KvStoreOptions, KvStoreSnapshot};
+pub use engines::sled::{SledKvsEngine, SledOptions, SledSnapshot};
+pub use error::KvsError;
+pub use shutdown::ShutdownHandle;
 
 /// Whether command worked successfully
-pub type Result<T> = std::result::Result<T, failure::Error>;
+pub type Result<T> = std::result::Result<T, KvsError>;
 
 const COMPACTION_THRESHOLD: f32 = 0.5;
 
+// how long a shutting down server waits for its connections by default
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+// size in bytes after which KvStore starts writing to a new log segment
+const MAX_SEGMENT_SIZE: u64 = 1024 * 1024;
+
 #[derive(Debug, Serialize, Deserialize)]
 /// the command the kvs engine will execute
+///
+/// keys and values are arbitrary bytes, sent as base64 strings
 pub enum Command {
     /// set a value for a key
     Set {
         /// key of KV pair to insert
-        key: String,
+        #[serde(with = "base64_bytes")]
+        key: Vec<u8>,
         /// value of KV pair to insert
-        value: String,
+        #[serde(with = "base64_bytes")]
+        value: Vec<u8>,
     },
 
     /// retrieve a value for a key
     Get {
         /// want value of this key
-        key: String,
+        #[serde(with = "base64_bytes")]
+        key: Vec<u8>,
     },
 
     /// remove a key/value pairing
     Remove {
         /// remove KV pair of this key
-        key: String,
+        #[serde(with = "base64_bytes")]
+        key: Vec<u8>,
+    },
+
+    /// retrieve one page of the KV pairs in a range of keys, in key order
+    Scan {
+        /// first key of the range, from the smallest key if not given
+        #[serde(with = "base64_bytes::option")]
+        start: Option<Vec<u8>>,
+        /// the range stops just before this key, up to the largest key if not given
+        #[serde(with = "base64_bytes::option")]
+        end: Option<Vec<u8>>,
+        /// most KV pairs to send back in this page
+        limit: usize,
+    },
+
+    /// set a value for a key that expires after a number of seconds
+    SetEx {
+        /// key of KV pair to insert
+        #[serde(with = "base64_bytes")]
+        key: Vec<u8>,
+        /// value of KV pair to insert
+        #[serde(with = "base64_bytes")]
+        value: Vec<u8>,
+        /// seconds until the KV pair expires
+        ttl_secs: u64,
+    },
+
+    /// find out how long a key has left before it expires
+    Ttl {
+        /// key to look up
+        #[serde(with = "base64_bytes")]
+        key: Vec<u8>,
+    },
+
+    /// drop the expiry of a key so it's kept until removed
+    Persist {
+        /// key to keep
+        #[serde(with = "base64_bytes")]
+        key: Vec<u8>,
+    },
+
+    /// apply several sets and removes atomically
+    Batch(WriteBatch),
+
+    /// set a value for a key only if the key's current value meets a condition
+    SetIf {
+        /// key of KV pair to insert
+        #[serde(with = "base64_bytes")]
+        key: Vec<u8>,
+        /// value of KV pair to insert
+        #[serde(with = "base64_bytes")]
+        value: Vec<u8>,
+        /// what the key has to hold for the value to be set
+        condition: SetCondition,
     },
+
+    /// start a transaction, the connection stays open and the gets, sets and removes sent on it
+    /// are part of the transaction until it's committed or discarded
+    Multi,
+
+    /// commit the transaction started on this connection
+    Exec,
+
+    /// drop the transaction started on this connection without writing anything
+    Discard,
+}
+
+/// what a conditional set expects to find under the key
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum SetCondition {
+    /// the key isn't set
+    Absent,
+
+    /// the key is set to any value
+    Present,
+
+    /// the key is set to exactly this value, the compare in compare-and-swap
+    Equals(#[serde(with = "base64_bytes")] Vec<u8>),
+}
+
+impl SetCondition {
+    /// whether a key holding `current` meets the condition
+    pub fn is_met_by(&self, current: Option<&[u8]>) -> bool {
+        match (self, current) {
+            (SetCondition::Absent, current) => current.is_none(),
+            (SetCondition::Present, current) => current.is_some(),
+            (SetCondition::Equals(expected), Some(current)) => expected[..] == *current,
+            (SetCondition::Equals(_), None) => false,
+        }
+    }
+}
+
+/// outcome of a conditional set
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum SetOutcome {
+    /// the condition held and the value was set
+    Written,
+
+    /// the condition didn't hold so nothing was written, holds the key's current value
+    Conflict(Option<Vec<u8>>),
+}
+
+/// reads and writes of several keys that are committed together, as started by `KvsEngine::begin`
+///
+/// writes are held back until commit and reads see them. commit fails with
+/// `KvsError::TransactionConflict` if a key that was read has been written by anyone else since
+#[derive(Debug, Clone, Default)]
+pub struct Transaction {
+    reads: BTreeMap<Vec<u8>, Option<u64>>, // key -> seq of the value read, `None` if it wasn't set
+    writes: BTreeMap<Vec<u8>, BatchOp>,    // key -> write to make
+}
+
+impl Transaction {
+    /// an empty transaction
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// gets the value of a key as the transaction sees it
+    pub fn get(&mut self, engine: &impl KvsEngine, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if let Some(op) = self.writes.get(key) {
+            return Ok(op.value().map(<[u8]>::to_vec));
+        }
+
+        let current = engine.get_with_seq(key)?;
+        self.reads
+            .entry(key.to_vec())
+            .or_insert_with(|| current.as_ref().map(|&(_, seq)| seq));
+        Ok(current.map(|(value, _)| value))
+    }
+
+    /// has the commit fail unless the key was last written by the write numbered `seq`, or isn't
+    /// set for `None`, as though it had been read then
+    pub fn expect_seq(&mut self, key: impl Into<Vec<u8>>, seq: Option<u64>) -> &mut Self {
+        self.reads.insert(key.into(), seq);
+        self
+    }
+
+    /// sets a key-value once the transaction commits
+    pub fn set(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> &mut Self {
+        let key = key.into();
+        let value = value.into();
+        self.writes.insert(key.clone(), BatchOp::Set { key, value });
+        self
+    }
+
+    /// sets a key-value that reads as absent once `ttl` has passed, counted from the commit
+    pub fn set_with_ttl(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+        ttl: Duration,
+    ) -> &mut Self {
+        let key = key.into();
+        let value = value.into();
+        self.writes
+            .insert(key.clone(), BatchOp::SetWithTtl { key, value, ttl });
+        self
+    }
+
+    /// removes a key once the transaction commits, nothing happens if the key isn't set
+    pub fn remove(&mut self, key: impl Into<Vec<u8>>) -> &mut Self {
+        let key = key.into();
+        self.writes.insert(key.clone(), BatchOp::Remove { key });
+        self
+    }
+
+    /// every key read, along with the sequence number of the value read or `None` if it wasn't set
+    pub fn reads(&self) -> impl Iterator<Item = (&[u8], Option<u64>)> {
+        self.reads.iter().map(|(key, &seq)| (&key[..], seq))
+    }
+
+    /// the writes to make on commit
+    pub fn writes(&self) -> WriteBatch {
+        WriteBatch {
+            ops: self.writes.values().cloned().collect(),
+        }
+    }
+}
+
+/// a group of sets and removes that `KvsEngine::apply_batch` applies all at once, or not at all
+///
+/// the writes are applied in the order they were added, so a later write to a key wins
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+/// a single write in a `WriteBatch`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum BatchOp {
+    /// set a value for a key
+    Set {
+        /// key of KV pair to insert
+        #[serde(with = "base64_bytes")]
+        key: Vec<u8>,
+        /// value of KV pair to insert
+        #[serde(with = "base64_bytes")]
+        value: Vec<u8>,
+    },
+
+    /// set a value for a key that reads as absent once `ttl` has passed
+    SetWithTtl {
+        /// key of KV pair to insert
+        #[serde(with = "base64_bytes")]
+        key: Vec<u8>,
+        /// value of KV pair to insert
+        #[serde(with = "base64_bytes")]
+        value: Vec<u8>,
+        /// how long after the batch is applied the KV pair expires
+        ttl: Duration,
+    },
+
+    /// remove a key/value pairing, nothing happens if the key isn't set
+    Remove {
+        /// remove KV pair of this key
+        #[serde(with = "base64_bytes")]
+        key: Vec<u8>,
+    },
+}
+
+impl BatchOp {
+    /// the key the write is for
+    pub fn key(&self) -> &[u8] {
+        match self {
+            BatchOp::Set { key, .. }
+            | BatchOp::SetWithTtl { key, .. }
+            | BatchOp::Remove { key } => key,
+        }
+    }
+
+    /// the value the write sets, `None` for a remove
+    pub fn value(&self) -> Option<&[u8]> {
+        match self {
+            BatchOp::Set { value, .. } | BatchOp::SetWithTtl { value, .. } => Some(value),
+            BatchOp::Remove { .. } => None,
+        }
+    }
+}
+
+impl WriteBatch {
+    /// an empty batch
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// adds a set of a key-value to the batch
+    pub fn set(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> &mut Self {
+        self.ops.push(BatchOp::Set {
+            key: key.into(),
+            value: value.into(),
+        });
+        self
+    }
+
+    /// adds a set of a key-value that reads as absent once `ttl` has passed to the batch
+    pub fn set_with_ttl(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+        ttl: Duration,
+    ) -> &mut Self {
+        self.ops.push(BatchOp::SetWithTtl {
+            key: key.into(),
+            value: value.into(),
+            ttl,
+        });
+        self
+    }
+
+    /// adds a removal of a key to the batch
+    pub fn remove(&mut self, key: impl Into<Vec<u8>>) -> &mut Self {
+        self.ops.push(BatchOp::Remove { key: key.into() });
+        self
+    }
+
+    /// the writes in the batch, in the order they were added
+    pub fn ops(&self) -> &[BatchOp] {
+        &self.ops
+    }
+
+    /// how many writes are in the batch
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// whether the batch has no writes
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+/// a key along with its value
+pub struct KvPair {
+    /// the key
+    #[serde(with = "base64_bytes")]
+    pub key: Vec<u8>,
+    /// the value stored under the key
+    #[serde(with = "base64_bytes")]
+    pub value: Vec<u8>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 /// the response sent back from server to client
 pub enum ServerResponse {
     /// get response
-    GetResponse(Option<String>),
+    GetResponse(#[serde(with = "base64_bytes::option")] Option<Vec<u8>>),
 
     /// returned when removal of a key was successful
     RemoveSuccess,
 
-    /// returned when removal of a key was a failure
-    RemoveFailure,
-
     /// returned when setting a KV pair was successful
     SetSuccess,
 
-    /// returned when setting a KV pair was a failure
-    SetFailure,
+    /// returned when a batch was applied
+    BatchSuccess,
+
+    /// returned when a conditional set found the key didn't meet its condition
+    Conflict {
+        /// the key's current value, `None` if it isn't set
+        #[serde(with = "base64_bytes::option")]
+        current: Option<Vec<u8>>,
+    },
+
+    /// one page of a scan
+    ScanResponse {
+        /// the KV pairs in this page, in key order
+        pairs: Vec<KvPair>,
+        /// where the next page starts, `None` once the range is exhausted
+        #[serde(with = "base64_bytes::option")]
+        next: Option<Vec<u8>>,
+    },
+
+    /// how long the key has left
+    TtlResponse(KeyTtl),
+
+    /// whether an expiry was dropped by a persist
+    PersistResponse(bool),
+
+    /// returned when a transaction was started on the connection
+    TransactionStarted,
+
+    /// returned when a transaction was committed
+    Committed,
+
+    /// returned when a transaction was discarded
+    Discarded,
+
+    /// returned when a request failed, nothing it would have written was
+    Error(KvsError),
+}
+
+impl ServerResponse {
+    // turns an error response into the error it stands for
+    fn into_result(self) -> Result<Self> {
+        match self {
+            Self::Error(e) => Err(e),
+            server_response => Ok(server_response),
+        }
+    }
+}
+
+/// how long a key has left before it expires
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum KeyTtl {
+    /// the key isn't set, or has already expired
+    Missing,
+
+    /// the key is kept until it's removed
+    NoExpiry,
+
+    /// the key expires after this long
+    ExpiresIn(Duration),
 }
 
 /// where in the log file the value resides
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct CommandPos {
-    pos: u64, // where the command starts in the file in bytes
-    len: u64, // length of the command in bytes
+    gen: u64,                // which log segment the command was written to
+    pos: u64,                // where the command starts in the segment in bytes
+    len: u64,                // length of the command in bytes
+    expires_at: Option<u64>, // unix time in milliseconds the value expires at
+    seq: u64,                // sequence number of the write
+    written_at: u64,         // unix time in milliseconds of the write
+}
+
+/// a range of keys, as taken by `KvsEngine::scan`
+pub type KeyRange = (Bound<Vec<u8>>, Bound<Vec<u8>>);
+
+/// KV pairs coming out of a scan, in key order
+pub type KvPairs<'a> = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + 'a>;
+
+/// keys coming out of a scan, in order
+pub type Keys<'a> = Box<dyn Iterator<Item = Result<Vec<u8>>> + 'a>;
+
+/// the range holding exactly the keys that start with `prefix`
+pub fn prefix_range(prefix: &[u8]) -> KeyRange {
+    // the smallest key past every key with the prefix is the prefix with its last byte bumped,
+    // after dropping trailing bytes that can't be bumped any further
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return (Bound::Included(prefix.to_vec()), Bound::Excluded(end));
+        }
+    }
+
+    (Bound::Included(prefix.to_vec()), Bound::Unbounded)
 }
 
 /// defines the storage interface called by KvsServer
-pub trait KvsEngine {
+///
+/// keys and values are arbitrary bytes, the `String` methods are conveniences on top of them.
+/// clones of an engine share its state, so every thread can get one of its own and use it
+/// alongside the others.
+pub trait KvsEngine: Clone + Send + Sync + 'static {
+    /// iterates over the KV pairs whose keys fall in `range`, in key order
+    fn scan(&self, range: KeyRange) -> Result<KvPairs<'_>>;
+
+    /// iterates over the KV pairs whose keys start with `prefix`, in key order
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<KvPairs<'_>> {
+        self.scan(prefix_range(prefix))
+    }
+
+    /// iterates over the keys that fall in `range`, in order, without reading their values
+    fn scan_keys(&self, range: KeyRange) -> Result<Keys<'_>> {
+        Ok(Box::new(
+            self.scan(range)?.map(|pair| pair.map(|(key, _)| key)),
+        ))
+    }
+
     /// gets the value associated with a key
-    fn get(&mut self, key: String) -> Result<Option<String>>;
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    /// gets the value associated with a key along with the sequence number of the write that set
+    /// it
+    fn get_with_seq(&self, key: &[u8]) -> Result<Option<(Vec<u8>, u64)>>;
 
     /// set a key-value, overriding previous value if present
-    fn set(&mut self, key: String, value: String) -> Result<()>;
+    fn set_bytes(&self, key: &[u8], value: &[u8]) -> Result<()>;
 
     /// removes a key and it's value
-    fn remove(&mut self, key: String) -> Result<()>;
+    fn remove_bytes(&self, key: &[u8]) -> Result<()>;
+
+    /// set a key-value that reads as absent once `ttl` has passed, overriding previous value if
+    /// present
+    fn set_bytes_with_ttl(&self, key: &[u8], value: &[u8], ttl: Duration) -> Result<()>;
+
+    /// how long a key has left before it expires
+    fn ttl(&self, key: &[u8]) -> Result<KeyTtl>;
+
+    /// drops the expiry of a key, returns false if it had none or isn't set
+    fn persist(&self, key: &[u8]) -> Result<bool>;
+
+    /// applies every write in the batch atomically, after a crash either all of them are there or
+    /// none are
+    ///
+    /// removing a key that isn't set is not an error in a batch, and sets without a ttl drop any
+    /// expiry
+    fn apply_batch(&self, batch: WriteBatch) -> Result<()>;
+
+    /// sets a key-value only if the key's current value meets `condition`, checking and setting
+    /// as one step
+    ///
+    /// like a plain set, a conditional set drops any expiry
+    fn set_if(&self, key: &[u8], value: &[u8], condition: SetCondition) -> Result<SetOutcome>;
+
+    /// sets a key-value only if the key isn't set yet
+    fn set_if_absent(&self, key: &[u8], value: &[u8]) -> Result<SetOutcome> {
+        self.set_if(key, value, SetCondition::Absent)
+    }
+
+    /// overrides the value of a key only if it's already set
+    fn set_if_present(&self, key: &[u8], value: &[u8]) -> Result<SetOutcome> {
+        self.set_if(key, value, SetCondition::Present)
+    }
+
+    /// replaces the value of a key only if it currently holds `expected`
+    fn compare_and_swap(&self, key: &[u8], expected: &[u8], value: &[u8]) -> Result<SetOutcome> {
+        self.set_if(key, value, SetCondition::Equals(expected.to_vec()))
+    }
+
+    /// how many keys are set, kept track of by the engine rather than counted
+    ///
+    /// keys that have expired are counted until they're cleaned up
+    fn key_count(&self) -> Result<u64>;
+
+    /// the sequence number of the latest write, 0 before the first one
+    ///
+    /// every set and remove, including each write in a batch, gets a higher sequence number than
+    /// the ones before it. kvs counts up one at a time, sled can skip numbers and once reopened
+    /// reports one at least as high as the latest write's
+    fn current_seq(&self) -> Result<u64>;
+
+    /// gets the value a key had at a point in its history, as far back as the retention policy
+    /// keeps versions around
+    fn get_at(&self, key: &[u8], at: ReadAt) -> Result<Option<Vec<u8>>>;
+
+    /// every version of a key that's still kept, oldest first
+    ///
+    /// removals show up as versions without a value
+    fn history(&self, key: &[u8]) -> Result<Vec<Version>>;
+
+    /// starts a transaction against the engine
+    fn begin(&self) -> Transaction {
+        Transaction::new()
+    }
+
+    /// makes the writes of a transaction, all at once, if none of the keys it read has been
+    /// written since
+    ///
+    /// fails with `KvsError::TransactionConflict` otherwise, nothing is written then
+    fn commit(&self, transaction: Transaction) -> Result<()>;
+
+    /// a read-only view of every key as it stands right now, later writes don't show up in it
+    ///
+    /// the snapshot doesn't borrow the engine so writes can carry on while it's read, whatever it
+    /// holds on to is released when it's dropped
+    fn snapshot(&self) -> Result<Box<dyn KvsSnapshot + Send>>;
+
+    /// writes out anything still buffered and fsyncs it, whatever the durability policy
+    fn flush(&self) -> Result<()>;
+
+    /// gets the value associated with a key, failing if it isn't valid UTF-8
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self
+            .get_bytes(key.as_bytes())?
+            .map(String::from_utf8)
+            .transpose()?)
+    }
+
+    /// set a key-value, overriding previous value if present
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.as_bytes(), value.as_bytes())
+    }
+
+    /// removes a key and it's value
+    fn remove(&self, key: String) -> Result<()> {
+        self.remove_bytes(key.as_bytes())
+    }
+}
+
+/// a point in a key's history, as taken by `KvsEngine::get_at`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadAt {
+    /// right after the write with this sequence number, regardless of expiry
+    Seq(u64),
+
+    /// at this moment, values that had expired by then read as absent
+    Time(SystemTime),
+}
+
+/// one version of a key, as returned by `KvsEngine::history`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Version {
+    /// sequence number of the write that made this version
+    pub seq: u64,
+
+    /// when the write was made
+    pub written_at: SystemTime,
+
+    /// the value written, `None` if the key was removed
+    pub value: Option<Vec<u8>>,
+
+    /// when the value expires, if it does
+    pub expires_at: Option<SystemTime>,
+}
+
+/// how much of each key's history an engine keeps around
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Retention {
+    /// only the current value
+    #[default]
+    Latest,
+
+    /// up to this many versions per key, the current one included
+    Versions(usize),
+
+    /// every version that was still current at some point in this window up to now
+    Window(Duration),
+}
+
+/// a point-in-time view of an engine, as returned by `KvsEngine::snapshot`
+pub trait KvsSnapshot {
+    /// gets the value a key had when the snapshot was taken
+    fn get_bytes(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    /// iterates over the KV pairs whose keys fall in `range`, in key order
+    fn scan(&mut self, range: KeyRange) -> Result<KvPairs<'_>>;
+
+    /// iterates over the KV pairs whose keys start with `prefix`, in key order
+    fn scan_prefix(&mut self, prefix: &[u8]) -> Result<KvPairs<'_>> {
+        self.scan(prefix_range(prefix))
+    }
+
+    /// gets the value a key had when the snapshot was taken, failing if it isn't valid UTF-8
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        Ok(self
+            .get_bytes(key.as_bytes())?
+            .map(String::from_utf8)
+            .transpose()?)
+    }
 }
 
 /// this struct exposes the interface for interacting with the KVS server
+///
+/// the connection is opened on the first request and kept for the ones after it. if a request
+/// fails part way the connection is dropped and the next request opens a new one.
 pub struct KvsClient {
     server_addr: SocketAddr,
+    connection: Mutex<Option<Connection>>,
 }
 
 impl KvsClient {
     /// create a KvsClient that listens to the specified port
     pub fn with_addr(addr: SocketAddr) -> Self {
-        Self { server_addr: addr }
+        Self {
+            server_addr: addr,
+            connection: Mutex::new(None),
+        }
     }
 
     /// sends specified command to server
-    pub fn send_command(&self, command: Command) -> Result<Option<String>> {
-        // append newline char because server reads bytes up to a new line per command
-        let command_string = format!("{}\n", serde_json::to_string(&command)?);
-        let command_bytes = command_string.as_bytes();
+    pub fn send_command(&self, command: Command) -> Result<Option<Vec<u8>>> {
+        match self.request(&command)? {
+            ServerResponse::GetResponse(x) => Ok(x),
+            _ => Ok(None),
+        }
+    }
 
-        let mut tcp_stream = TcpStream::connect(self.server_addr)?;
+    /// fetches up to `limit` KV pairs with keys in `start..end`, in key order
+    ///
+    /// also returns where the next page starts, or `None` if there are no more pairs in the range
+    pub fn scan(
+        &self,
+        start: Option<Vec<u8>>,
+        end: Option<Vec<u8>>,
+        limit: usize,
+    ) -> Result<(Vec<KvPair>, Option<Vec<u8>>)> {
+        match self.request(&Command::Scan { start, end, limit })? {
+            ServerResponse::ScanResponse { pairs, next } => Ok((pairs, next)),
+            _ => Err(KvsError::Protocol(String::from(
+                "Unexpected response to scan",
+            ))),
+        }
+    }
 
-        tcp_stream.write_all(command_bytes)?;
+    /// applies the writes in a batch atomically on the server
+    pub fn apply_batch(&self, batch: WriteBatch) -> Result<()> {
+        match self.request(&Command::Batch(batch))? {
+            ServerResponse::BatchSuccess => Ok(()),
+            _ => Err(KvsError::Protocol(String::from(
+                "Unexpected response to batch",
+            ))),
+        }
+    }
 
-        let mut server_response = String::new();
-        tcp_stream.read_to_string(&mut server_response)?;
+    /// sets a key-value on the server only if the key's current value meets `condition`
+    pub fn set_if(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        condition: SetCondition,
+    ) -> Result<SetOutcome> {
+        match self.request(&Command::SetIf {
+            key,
+            value,
+            condition,
+        })? {
+            ServerResponse::SetSuccess => Ok(SetOutcome::Written),
+            ServerResponse::Conflict { current } => Ok(SetOutcome::Conflict(current)),
+            _ => Err(KvsError::Protocol(String::from(
+                "Unexpected response to conditional set",
+            ))),
+        }
+    }
 
-        let server_response: ServerResponse = serde_json::from_str(&server_response)?;
+    /// asks how long a key has left before it expires
+    pub fn ttl(&self, key: Vec<u8>) -> Result<KeyTtl> {
+        match self.request(&Command::Ttl { key })? {
+            ServerResponse::TtlResponse(ttl) => Ok(ttl),
+            _ => Err(KvsError::Protocol(String::from(
+                "Unexpected response to ttl",
+            ))),
+        }
+    }
 
-        match server_response {
-            ServerResponse::GetResponse(x) => Ok(x),
-            ServerResponse::RemoveFailure => Err(format_err!("Key not found")),
-            _ => Ok(None),
+    /// drops the expiry of a key, returns false if it had none or isn't set
+    pub fn persist(&self, key: Vec<u8>) -> Result<bool> {
+        match self.request(&Command::Persist { key })? {
+            ServerResponse::PersistResponse(persisted) => Ok(persisted),
+            _ => Err(KvsError::Protocol(String::from(
+                "Unexpected response to persist",
+            ))),
         }
     }
-}
 
-/// provides functionality to serve responses from server to client
-pub struct KvsServer {
-    listener: Option<TcpListener>,
-    engine: Box<dyn KvsEngine>,
+    /// starts a transaction on the server, it holds on to its own connection until committed or
+    /// discarded
+    pub fn begin(&self) -> Result<RemoteTransaction> {
+        let mut transaction = RemoteTransaction {
+            connection: Connection::open(self.server_addr)?,
+        };
+
+        match transaction.connection.request(&Command::Multi)? {
+            ServerResponse::TransactionStarted => Ok(transaction),
+            _ => Err(KvsError::Protocol(String::from(
+                "Unexpected response to multi",
+            ))),
+        }
+    }
+
+    /// sends all of `commands` without waiting for any response, returns their responses in order
+    ///
+    /// saves waiting a round trip per command when none of them depends on an earlier one. a
+    /// command that fails gets an error response, the others still run. responses are read while
+    /// the commands are still going out, so a pipeline of any length never leaves both ends
+    /// waiting on each other's full socket buffers.
+    pub fn send_pipelined(&self, commands: &[Command]) -> Result<Vec<ServerResponse>> {
+        let mut connection = self.connection.lock().expect("KvsClient lock poisoned");
+        let result = (|| -> Result<Vec<ServerResponse>> {
+            let open = match connection.as_mut() {
+                Some(open) => open,
+                None => connection.insert(Connection::open(self.server_addr)?),
+            };
+
+            open.send_all_and_receive(commands)
+        })();
+
+        // responses left unread would be taken for the answers to the next request
+        if result.is_err() {
+            *connection = None;
+        }
+        result
+    }
+
+    // fails with the error the server answered with, if it did
+    fn request(&self, command: &Command) -> Result<ServerResponse> {
+        let mut responses = self.send_pipelined(std::slice::from_ref(command))?;
+        responses.remove(0).into_result()
+    }
 }
 
-impl KvsServer {
-    /// creates a KvsServer that listens on provided port
-    pub fn new(addr: SocketAddr, engine: &Option<EngineType>) -> Result<Self> {
-        let existing_engine = Self::existing_engine()?;
-        let engine = match (&engine, &existing_engine) {
-            (None, _) => Self::load_existing_or_default_engine(existing_engine)?,
-            (Some(EngineType::Kvs), Some(EngineType::Kvs)) => {
-                Box::new(engines::kvs::KvStore::open(env::current_dir()?)?)
-            }
-            (Some(EngineType::Sled), Some(EngineType::Sled)) => {
-                Box::new(engines::sled::SledKvsEngine::open(env::current_dir()?)?)
-            }
-            (Some(EngineType::Kvs), None) => {
-                Box::new(engines::kvs::KvStore::open(env::current_dir()?)?)
-            }
-            (Some(EngineType::Sled), None) => {
-                Box::new(engines::sled::SledKvsEngine::open(env::current_dir()?)?)
-            }
-            _ => {
-                return Err(format_err!(
-                    "Incompatible engine specified: user: {:?}, existing: {:?}",
-                    engine,
-                    existing_engine
-                ))
-            }
-        };
+// a connection to the server, every request and every response is one line of JSON so any number
+// of them can follow each other on it
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+}
 
+impl Connection {
+    fn open(addr: SocketAddr) -> Result<Self> {
+        let stream = TcpStream::connect(addr)?;
         Ok(Self {
-            listener: Some(TcpListener::bind(addr)?),
-            engine,
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
         })
     }
 
-    fn load_existing_or_default_engine(
-        existing_engine: Option<EngineType>,
-    ) -> Result<Box<dyn KvsEngine>> {
-        match existing_engine {
-            None | Some(EngineType::Kvs) => {
-                Ok(Box::new(engines::kvs::KvStore::open(env::current_dir()?)?))
+    // sends every command and reads back a response for each
+    //
+    // with more than one the commands are written on a thread of their own while the responses
+    // are read here, otherwise a long enough pipeline fills the socket buffers both ways and
+    // neither end gets to read
+    fn send_all_and_receive(&mut self, commands: &[Command]) -> Result<Vec<ServerResponse>> {
+        if let [command] = commands {
+            send(&mut self.writer, command)?;
+            self.writer.flush()?;
+            return Ok(vec![receive(&mut self.reader)?]);
+        }
+
+        let Self { reader, writer } = self;
+        thread::scope(|scope| {
+            let sender = scope.spawn(move || {
+                let sent = commands
+                    .iter()
+                    .try_for_each(|command| send(writer, command))
+                    .and_then(|()| Ok(writer.flush()?));
+                // the responses to commands that never went out won't come, so stop waiting
+                if sent.is_err() {
+                    let _ = writer.get_ref().shutdown(Shutdown::Both);
+                }
+                sent
+            });
+
+            let responses = commands
+                .iter()
+                .map(|_| receive(reader))
+                .collect::<Result<Vec<_>>>();
+            // and if the server's answers can't be read, stop sending it more
+            if responses.is_err() {
+                let _ = reader.get_ref().shutdown(Shutdown::Both);
             }
-            Some(EngineType::Sled) => Ok(Box::new(engines::sled::SledKvsEngine::open(
-                env::current_dir()?,
-            )?)),
+            sender.join().expect("pipeline sender panicked")?;
+            responses
+        })
+    }
+
+    // fails with the error the server answered with, if it did
+    fn request(&mut self, command: &Command) -> Result<ServerResponse> {
+        send(&mut self.writer, command)?;
+        self.writer.flush()?;
+        receive(&mut self.reader)?.into_result()
+    }
+}
+
+// queues a request, it goes out on the next flush
+fn send(writer: &mut impl Write, command: &Command) -> Result<()> {
+    serde_json::to_writer(&mut *writer, command)?;
+    writer.write_all(b"\n")?;
+    Ok(())
+}
+
+fn receive(reader: &mut impl BufRead) -> Result<ServerResponse> {
+    let mut server_response = String::new();
+    if reader.read_line(&mut server_response)? == 0 {
+        return Err(KvsError::Io(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "Server closed the connection",
+        )));
+    }
+
+    Ok(serde_json::from_str(&server_response)?)
+}
+
+/// a transaction running on the server, as started by `KvsClient::begin`
+///
+/// dropping it without committing discards it
+pub struct RemoteTransaction {
+    connection: Connection,
+}
+
+impl RemoteTransaction {
+    /// gets the value of a key as the transaction sees it
+    pub fn get(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        match self.connection.request(&Command::Get { key })? {
+            ServerResponse::GetResponse(value) => Ok(value),
+            _ => Err(KvsError::Protocol(String::from(
+                "Unexpected response to get in transaction",
+            ))),
         }
     }
 
-    fn existing_engine() -> Result<Option<EngineType>> {
-        for entry in fs::read_dir(env::current_dir()?)? {
-            let entry = entry?;
-            let path = entry.path();
-            if path.ends_with("kvs.log") {
-                return Ok(Some(EngineType::Kvs));
-            } else if path.ends_with("sled_db.log") {
-                return Ok(Some(EngineType::Sled));
-            }
+    /// sets a key-value once the transaction commits
+    pub fn set(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        match self.connection.request(&Command::Set { key, value })? {
+            ServerResponse::SetSuccess => Ok(()),
+            _ => Err(KvsError::Protocol(String::from(
+                "Unexpected response to set in transaction",
+            ))),
         }
+    }
 
-        Ok(None)
+    /// removes a key once the transaction commits
+    pub fn remove(&mut self, key: Vec<u8>) -> Result<()> {
+        match self.connection.request(&Command::Remove { key })? {
+            ServerResponse::RemoveSuccess => Ok(()),
+            _ => Err(KvsError::Protocol(String::from(
+                "Unexpected response to remove in transaction",
+            ))),
+        }
     }
 
-    /// infinitely listens for incoming requests and executes them
+    /// commits the transaction, failing with `KvsError::TransactionConflict` if a key it read has been
+    /// written since
+    pub fn commit(mut self) -> Result<()> {
+        match self.connection.request(&Command::Exec)? {
+            ServerResponse::Committed => Ok(()),
+            _ => Err(KvsError::Protocol(String::from(
+                "Unexpected response to exec",
+            ))),
+        }
+    }
+
+    /// drops the transaction without writing anything
+    pub fn discard(mut self) -> Result<()> {
+        match self.connection.request(&Command::Discard)? {
+            ServerResponse::Discarded => Ok(()),
+            _ => Err(KvsError::Protocol(String::from(
+                "Unexpected response to discard",
+            ))),
+        }
+    }
+}
+
+/// provides functionality to serve responses from server to client
+///
+/// every connection is served on one of the pool's threads, each with its own clone of the
+/// engine, so a slow client or one holding a transaction open only keeps the others waiting once
+/// the pool has no thread left for them
+pub struct KvsServer<E: KvsEngine, P: ThreadPool> {
+    listener: Option<TcpListener>,
+    engine: E,
+    pool: P,
+    protocol: Protocol,
+    shutdown: ShutdownHandle,
+    shutdown_timeout: Duration,
+    max_connections: Option<usize>,
+}
+
+impl<E: KvsEngine, P: ThreadPool> KvsServer<E, P> {
+    /// creates a KvsServer that listens on provided port, serving requests against `engine` on the
+    /// threads of `pool`
+    pub fn new(addr: SocketAddr, engine: E, pool: P) -> Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let shutdown = ShutdownHandle::new(listener.local_addr()?);
+        Ok(Self {
+            listener: Some(listener),
+            engine,
+            pool,
+            protocol: Protocol::default(),
+            shutdown,
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            max_connections: None,
+        })
+    }
+
+    /// serves clients speaking `protocol` instead of the JSON line protocol
+    pub fn with_protocol(mut self, protocol: Protocol) -> Self {
+        self.protocol = protocol;
+        self
+    }
+
+    /// how long shutting down waits for connections to answer the requests they've already sent
+    pub fn with_shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// closes connections straight away while `max` others are being served
+    pub fn with_max_connections(mut self, max: usize) -> Self {
+        self.max_connections = Some(max);
+        self
+    }
+
+    /// a handle that stops `run` from another thread
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// the address the server listens on, with the port the system picked if it was asked for
+    /// port 0
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self
+            .listener
+            .as_ref()
+            .expect("KvsServer created without TCP listener!")
+            .local_addr()?)
+    }
+
+    /// runs the server on a thread of its own until the returned server is stopped or dropped
+    pub fn spawn(self) -> Result<BackgroundServer>
+    where
+        P: Send + 'static,
+    {
+        BackgroundServer::spawn(self)
+    }
+
+    /// listens for incoming requests and executes them until shut down through a
+    /// `ShutdownHandle`, then flushes the engine and returns
     pub fn run(mut self) -> Result<()> {
         let listener = self
             .listener
             .take()
             .expect("KvsServer created without TCP listener!");
+        let connections = Arc::new(Connections::default());
 
         for stream in listener.incoming() {
-            self.handle_client_request(stream?)?;
+            if self.shutdown.is_shutdown() {
+                break;
+            }
+            // a connection that failed before it was accepted only matters to its client
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    error!("Failed accepting connection: {}", e);
+                    continue;
+                }
+            };
+            if let Some(max) = self.max_connections.filter(|max| connections.len() >= *max) {
+                warn!(
+                    "Refusing connection from {:?}, already serving {} connections",
+                    stream.peer_addr(),
+                    max
+                );
+                continue;
+            }
+            let registration = match connections.register(&stream) {
+                Ok(registration) => registration,
+                Err(e) => {
+                    error!("Failed tracking connection: {}", e);
+                    continue;
+                }
+            };
+
+            let engine = self.engine.clone();
+            let protocol = self.protocol;
+            self.pool.spawn(move || {
+                let served = match protocol {
+                    Protocol::Json => handle_connection(&engine, stream),
+                    Protocol::Resp => resp::handle_connection(&engine, stream),
+                    Protocol::Memcached => memcached::handle_connection(&engine, stream),
+                    Protocol::Http => http::handle_connection(&engine, stream),
+                };
+                if let Err(e) = served {
+                    error!("Failed serving client: {}", e);
+                }
+                // the clone goes first, so once every connection is done the engine is only
+                // held by the server
+                drop(engine);
+                drop(registration);
+            });
         }
 
-        Err(format_err!(
-            "`incoming` loop broke on listener! Not listening on socket anymore."
-        ))
+        drop(listener);
+        info!("Shutting down, no longer accepting connections");
+        let still_open = connections.close_all(self.shutdown_timeout);
+        if still_open > 0 {
+            warn!(
+                "{} connections still open after {:?}, shutting down without them",
+                still_open, self.shutdown_timeout
+            );
+        }
+
+        self.engine.flush()?;
+        info!("Shut down cleanly");
+        Ok(())
     }
+}
 
-    // TODO return success message over TCP stream
-    fn handle_client_request(&mut self, mut stream: TcpStream) -> Result<()> {
-        let mut buf_reader = BufReader::new(&mut stream);
+// serves requests from one client until it closes the connection, answering each with one line
+//
+// responses are written out once every request already received has been answered, so a client
+// pipelining its requests gets their responses back together. a request that fails is answered
+// with an error and the connection carries on, only the connection itself failing ends it.
+fn handle_connection(engine: &impl KvsEngine, stream: TcpStream) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    let mut transaction = None;
 
-        let mut command = String::new(); // TODO initialize enough space for the smallest of get/set commands
-        buf_reader.read_line(&mut command)?;
+    loop {
+        let mut command = Vec::new();
+        if reader.read_until(b'\n', &mut command)? == 0 {
+            // a transaction still open when the client goes away is left uncommitted
+            return Ok(());
+        }
 
-        let command: Command = serde_json::from_str(&command)?;
+        let server_response = match serde_json::from_slice(&command) {
+            Ok(command) => respond(engine, &mut transaction, command),
+            Err(e) => Err(KvsError::Protocol(format!("Malformed request: {}", e))),
+        }
+        .unwrap_or_else(ServerResponse::Error);
 
-        match command {
-            Command::Get { key } => {
-                let result = self.engine.get(key)?;
+        serde_json::to_writer(&mut writer, &server_response)?;
+        writer.write_all(b"\n")?;
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+}
 
-                let server_response = ServerResponse::GetResponse(result);
-                let server_response = serde_json::to_string(&server_response)?;
-                let server_response = format!("{}\n", server_response);
+// runs a command, as part of the connection's transaction if it has one open
+fn respond(
+    engine: &impl KvsEngine,
+    transaction: &mut Option<Transaction>,
+    command: Command,
+) -> Result<ServerResponse> {
+    let open = match transaction {
+        Some(open) => open,
+        None if matches!(command, Command::Multi) => {
+            *transaction = Some(engine.begin());
+            return Ok(ServerResponse::TransactionStarted);
+        }
+        None => return execute(engine, command),
+    };
 
-                stream.write_all(server_response.as_bytes())?;
-                Ok(())
-            }
-            Command::Set { key, value } => {
-                let server_response = if self.engine.set(key, value).is_ok() {
-                    ServerResponse::SetSuccess
-                } else {
-                    ServerResponse::SetFailure
-                };
+    Ok(match command {
+        Command::Get { key } => ServerResponse::GetResponse(open.get(engine, &key)?),
+        Command::Set { key, value } => {
+            open.set(key, value);
+            ServerResponse::SetSuccess
+        }
+        Command::Remove { key } => {
+            open.remove(key);
+            ServerResponse::RemoveSuccess
+        }
+        Command::Exec => {
+            let open = transaction.take().expect("transaction checked to be open");
+            engine.commit(open)?;
+            ServerResponse::Committed
+        }
+        Command::Discard => {
+            *transaction = None;
+            ServerResponse::Discarded
+        }
+        Command::Multi => {
+            return Err(KvsError::Protocol(String::from(
+                "A transaction is already open",
+            )))
+        }
+        _ => {
+            return Err(KvsError::Protocol(String::from(
+                "Command can't be part of a transaction",
+            )))
+        }
+    })
+}
 
-                let server_response = serde_json::to_string(&server_response)?;
-                let server_response = format!("{}\n", server_response);
+// runs a command outside of any transaction
+fn execute(engine: &impl KvsEngine, command: Command) -> Result<ServerResponse> {
+    Ok(match command {
+        Command::Get { key } => ServerResponse::GetResponse(engine.get_bytes(&key)?),
+        Command::Set { key, value } => {
+            engine.set_bytes(&key, &value)?;
+            ServerResponse::SetSuccess
+        }
+        Command::Remove { key } => {
+            engine.remove_bytes(&key)?;
+            ServerResponse::RemoveSuccess
+        }
+        Command::Scan { start, end, limit } => {
+            let range = (
+                start.map_or(Bound::Unbounded, Bound::Included),
+                end.map_or(Bound::Unbounded, Bound::Excluded),
+            );
 
-                stream.write(server_response.as_bytes())?;
-                Ok(())
-            }
-            Command::Remove { key } => {
-                let server_response = if let Ok(_) = self.engine.remove(key) {
-                    ServerResponse::RemoveSuccess
-                } else {
-                    ServerResponse::RemoveFailure
-                };
+            // one pair past the page tells where the next page starts
+            let mut pairs = engine
+                .scan(range)?
+                .take(limit.saturating_add(1))
+                .map(|pair| pair.map(|(key, value)| KvPair { key, value }))
+                .collect::<Result<Vec<_>>>()?;
+            let next = if pairs.len() > limit {
+                pairs.pop().map(|pair| pair.key)
+            } else {
+                None
+            };
 
-                let server_response = serde_json::to_string(&server_response)?;
-                let server_response = format!("{}\n", server_response);
+            ServerResponse::ScanResponse { pairs, next }
+        }
+        Command::SetEx {
+            key,
+            value,
+            ttl_secs,
+        } => {
+            engine.set_bytes_with_ttl(&key, &value, Duration::from_secs(ttl_secs))?;
+            ServerResponse::SetSuccess
+        }
+        Command::Ttl { key } => ServerResponse::TtlResponse(engine.ttl(&key)?),
+        Command::Persist { key } => ServerResponse::PersistResponse(engine.persist(&key)?),
+        Command::Batch(batch) => {
+            engine.apply_batch(batch)?;
+            ServerResponse::BatchSuccess
+        }
+        Command::SetIf {
+            key,
+            value,
+            condition,
+        } => match engine.set_if(&key, &value, condition)? {
+            SetOutcome::Written => ServerResponse::SetSuccess,
+            SetOutcome::Conflict(current) => ServerResponse::Conflict { current },
+        },
+        // only make sense on a connection a transaction was started on
+        Command::Exec | Command::Discard => {
+            return Err(KvsError::Protocol(String::from("No transaction is open")))
+        }
+        Command::Multi => unreachable!("transactions are started by `respond`"),
+    })
+}
 
-                stream.write_all(server_response.as_bytes())?;
-                Ok(())
-            }
+/// how eagerly an engine makes its writes durable against power loss, honored by every engine
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Durability {
+    /// never fsync, leave it to the OS (and sled's own background flushing)
+    #[default]
+    Never,
+
+    /// fsync before every write returns
+    EveryWrite,
+
+    /// fsync in the background every so many milliseconds, a crash loses at most that window
+    EveryN(u64),
+
+    /// every write waits for an fsync, but concurrent writes share one fsync between them
+    GroupCommit,
+}
+
+impl fmt::Display for Durability {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Never => write!(f, "never"),
+            Self::EveryWrite => write!(f, "every-write"),
+            Self::EveryN(ms) => write!(f, "every-{}ms", ms),
+            Self::GroupCommit => write!(f, "group-commit"),
+        }
+    }
+}
+
+impl FromStr for Durability {
+    type Err = KvsError;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "never" => Ok(Self::Never),
+            "every-write" => Ok(Self::EveryWrite),
+            "group-commit" => Ok(Self::GroupCommit),
+            _ => s
+                .strip_prefix("every-")
+                .and_then(|s| s.strip_suffix("ms"))
+                .and_then(|ms| ms.parse().ok())
+                .filter(|&ms| ms > 0)
+                .map(Self::EveryN)
+                .ok_or_else(|| {
+                    KvsError::InvalidArgument(String::from(
+                        "invalid sync policy, expected never, every-write, every-<n>ms or group-commit",
+                    ))
+                }),
         }
     }
 }
 
 /// the type of key value storage engine
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum EngineType {
     /// custom in house definition
     Kvs,
@@ -261,22 +1306,125 @@ pub enum EngineType {
     Sled,
 }
 
-impl ToString for EngineType {
-    fn to_string(&self) -> String {
+impl EngineType {
+    /// the engine to run in `dir`, the one its MANIFEST names or kvs for an empty directory
+    ///
+    /// fails if `requested` is a different engine than the one already there
+    pub fn resolve(requested: Option<EngineType>, dir: &Path) -> Result<EngineType> {
+        let existing = Self::existing(dir)?;
+        match (requested, existing) {
+            (None, existing) => Ok(existing.unwrap_or(EngineType::Kvs)),
+            (Some(requested), None) => Ok(requested),
+            (Some(requested), Some(existing)) if requested == existing => Ok(requested),
+            (Some(requested), Some(existing)) => Err(KvsError::IncompatibleEngine {
+                requested,
+                existing,
+            }),
+        }
+    }
+
+    fn existing(dir: &Path) -> Result<Option<EngineType>> {
+        engines::manifest::existing_engine(dir)
+    }
+}
+
+impl fmt::Display for EngineType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            Self::Kvs => String::from("kvs"),
-            Self::Sled => String::from("sled"),
+            Self::Kvs => write!(f, "kvs"),
+            Self::Sled => write!(f, "sled"),
         }
     }
 }
 
 impl FromStr for EngineType {
-    type Err = failure::Error;
+    type Err = KvsError;
     fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
         match s {
             "kvs" => Ok(Self::Kvs),
             "sled" => Ok(Self::Sled),
-            _ => Err(format_err!("invalid engine type")),
+            _ => Err(KvsError::InvalidArgument(String::from(
+                "invalid engine type",
+            ))),
+        }
+    }
+}
+
+/// the kind of thread pool the server runs requests on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PoolType {
+    /// a new thread for every connection
+    Naive,
+
+    /// a fixed number of threads sharing one queue of connections
+    #[default]
+    SharedQueue,
+
+    /// rayon's work stealing pool
+    Rayon,
+}
+
+impl fmt::Display for PoolType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Naive => write!(f, "naive"),
+            Self::SharedQueue => write!(f, "shared-queue"),
+            Self::Rayon => write!(f, "rayon"),
+        }
+    }
+}
+
+impl FromStr for PoolType {
+    type Err = KvsError;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "naive" => Ok(Self::Naive),
+            "shared-queue" => Ok(Self::SharedQueue),
+            "rayon" => Ok(Self::Rayon),
+            _ => Err(KvsError::InvalidArgument(String::from(
+                "invalid thread pool type",
+            ))),
+        }
+    }
+}
+
+/// the wire protocol the server speaks to its clients
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Protocol {
+    /// one JSON request per line, as sent by KvsClient
+    #[default]
+    Json,
+
+    /// Redis' RESP2, for redis-cli and Redis client libraries
+    Resp,
+
+    /// memcached's text protocol, for memcached clients
+    Memcached,
+
+    /// HTTP/1.1 requests against `/keys/{key}`, for anything that can only speak HTTP
+    Http,
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Json => write!(f, "json"),
+            Self::Resp => write!(f, "resp"),
+            Self::Memcached => write!(f, "memcached"),
+            Self::Http => write!(f, "http"),
+        }
+    }
+}
+
+impl FromStr for Protocol {
+    type Err = KvsError;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Self::Json),
+            "resp" => Ok(Self::Resp),
+            "memcached" => Ok(Self::Memcached),
+            "http" => Ok(Self::Http),
+            _ => Err(KvsError::InvalidArgument(String::from("invalid protocol"))),
         }
     }
 }
@@ -0,0 +1,371 @@
+//! the memcached text protocol, which kvs-server speaks with `--protocol memcached`
+//!
+//! every item is stored as its data behind a header holding the client's flags. nothing marks a
+//! value as an item, so one written through the other protocols reads with its first 4 bytes
+//! taken for flags, and one shorter than that fails to read. an item's exptime becomes the ttl of
+//! its key, so expired items are swept and compacted away like any other expired key.
+
+use crate::engines::now_millis;
+use crate::{KeyTtl, KvsEngine, KvsError, Result, Transaction, WriteBatch};
+use std::convert::TryInto;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::TcpStream;
+use std::ops::Bound;
+use std::str::FromStr;
+use std::time::Duration;
+
+// flags as a big endian u32
+const HEADER_LEN: usize = 4;
+
+const MAX_KEY_LEN: usize = 250;
+
+// memcached's default item size limit
+const MAX_ITEM_SIZE: usize = 1024 * 1024;
+
+// the most data skipped past for an item too large to store, a longer one is taken to be a broken
+// command line rather than read through
+const MAX_SKIPPED_LEN: usize = 64 * MAX_ITEM_SIZE;
+
+// how many keys flush_all removes per batch
+const FLUSH_BATCH_LEN: usize = 1000;
+
+// exptimes up to 30 days are seconds from now, bigger ones are unix times
+const MAX_RELATIVE_EXPTIME: i64 = 60 * 60 * 24 * 30;
+
+struct Item {
+    flags: u32,
+    ttl: Option<Duration>, // kept by the engine rather than in the header, `None` for never
+    data: Vec<u8>,
+}
+
+impl Item {
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_LEN + self.data.len());
+        bytes.extend_from_slice(&self.flags.to_be_bytes());
+        bytes.extend_from_slice(&self.data);
+        bytes
+    }
+
+    fn decode(bytes: &[u8], ttl: Option<Duration>) -> Result<Self> {
+        if bytes.len() < HEADER_LEN {
+            return Err(KvsError::Serialization(String::from(
+                "value wasn't stored through the memcached protocol",
+            )));
+        }
+        let (flags, data) = bytes.split_at(HEADER_LEN);
+        Ok(Self {
+            flags: u32::from_be_bytes(flags.try_into().expect("split at 4 bytes")),
+            ttl,
+            data: data.to_vec(),
+        })
+    }
+
+    // adds the write of the item under `key` to a transaction
+    fn write_to(&self, transaction: &mut Transaction, key: &[u8]) {
+        match self.ttl {
+            Some(ttl) => transaction.set_with_ttl(key, self.encode(), ttl),
+            None => transaction.set(key, self.encode()),
+        };
+    }
+}
+
+// how long an item given `exptime` lives, `None` until it's removed. a negative exptime, or a
+// unix time that's already passed, has it expire straight away
+fn ttl(exptime: i64) -> Option<Duration> {
+    match exptime {
+        0 => None,
+        exptime if exptime < 0 => Some(Duration::ZERO),
+        exptime if exptime <= MAX_RELATIVE_EXPTIME => Some(Duration::from_secs(exptime as u64)),
+        exptime => Some(Duration::from_secs(
+            (exptime as u64).saturating_sub(now_millis() / 1000),
+        )),
+    }
+}
+
+// writes what `update` makes of the item under `key` as one step, starting over when another
+// write gets in between. returns false without writing if `update` gives nothing
+fn update_item(
+    engine: &impl KvsEngine,
+    key: &[u8],
+    mut update: impl FnMut(Option<&Item>) -> Option<Item>,
+) -> Result<bool> {
+    loop {
+        let mut transaction = engine.begin();
+        let item = match transaction.get(engine, key)? {
+            // a ttl that's changed since the value was read comes with a write the commit catches
+            Some(bytes) => Some(Item::decode(
+                &bytes,
+                match engine.ttl(key)? {
+                    KeyTtl::ExpiresIn(ttl) => Some(ttl),
+                    KeyTtl::NoExpiry => None,
+                    KeyTtl::Missing => Some(Duration::ZERO),
+                },
+            )?),
+            None => None,
+        };
+        match update(item.as_ref()) {
+            Some(new) => new.write_to(&mut transaction, key),
+            None => return Ok(false),
+        }
+
+        match engine.commit(transaction) {
+            Ok(()) => return Ok(true),
+            Err(KvsError::TransactionConflict { .. }) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// serves commands from one client until it closes the connection or sends `quit`
+//
+// replies are flushed once every command already received has been answered. data that doesn't
+// end where its command line said it would leaves no way to find the next command, so it's
+// answered and the connection closed.
+pub(crate) fn handle_connection(engine: &impl KvsEngine, stream: TcpStream) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    loop {
+        let mut line = Vec::new();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            return Ok(());
+        }
+        let words: Vec<&[u8]> = line
+            .split(|b| b.is_ascii_whitespace())
+            .filter(|word| !word.is_empty())
+            .collect();
+        if words.first() == Some(&&b"quit"[..]) {
+            writer.flush()?;
+            return Ok(());
+        }
+
+        match respond(engine, &mut reader, &words) {
+            Ok(reply) => writer.write_all(&reply)?,
+            Err(KvsError::Protocol(message)) => {
+                write!(writer, "CLIENT_ERROR {}\r\n", message)?;
+                writer.flush()?;
+                return Ok(());
+            }
+            Err(KvsError::Io(e)) => return Err(KvsError::Io(e)),
+            Err(e) => write!(writer, "SERVER_ERROR {}\r\n", e)?,
+        }
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+}
+
+// runs one command, returning what to answer, nothing when the client asked for no reply
+fn respond(engine: &impl KvsEngine, reader: &mut impl BufRead, words: &[&[u8]]) -> Result<Vec<u8>> {
+    let (command, args) = match words.split_first() {
+        Some((command, args)) => (*command, args),
+        None => return Ok(b"ERROR\r\n".to_vec()),
+    };
+    let (args, noreply) = match args.split_last() {
+        Some((last, rest)) if *last == b"noreply" => (rest, true),
+        _ => (args, false),
+    };
+    let bad_format = || Ok(b"CLIENT_ERROR bad command line format\r\n".to_vec());
+    let reply = |text: &str| {
+        Ok(if noreply {
+            Vec::new()
+        } else {
+            format!("{}\r\n", text).into_bytes()
+        })
+    };
+
+    match command {
+        b"get" | b"gets" if !args.is_empty() => {
+            let mut values = Vec::new();
+            for key in args {
+                let (bytes, seq) = match engine.get_with_seq(key)? {
+                    Some(found) => found,
+                    None => continue,
+                };
+                let item = Item::decode(&bytes, None)?;
+
+                values.extend_from_slice(b"VALUE ");
+                values.extend_from_slice(key);
+                write!(values, " {} {}", item.flags, item.data.len())?;
+                if command == b"gets" {
+                    write!(values, " {}", seq)?;
+                }
+                values.extend_from_slice(b"\r\n");
+                values.extend_from_slice(&item.data);
+                values.extend_from_slice(b"\r\n");
+            }
+            values.extend_from_slice(b"END\r\n");
+            Ok(values)
+        }
+
+        b"set" | b"add" | b"replace" | b"cas" => {
+            let with_cas = command == b"cas";
+            if args.len() != if with_cas { 5 } else { 4 } {
+                return bad_format();
+            }
+            let (key, flags, exptime, len) = match (
+                parse_key(args[0]),
+                parse::<u32>(args[1]),
+                parse::<i64>(args[2]),
+                parse::<usize>(args[3]),
+            ) {
+                (Some(key), Some(flags), Some(exptime), Some(len)) => (key, flags, exptime, len),
+                _ => return bad_format(),
+            };
+            let unique = if with_cas {
+                match parse::<u64>(args[4]) {
+                    Some(unique) => Some(unique),
+                    None => return bad_format(),
+                }
+            } else {
+                None
+            };
+
+            if len > MAX_SKIPPED_LEN {
+                return Err(KvsError::Protocol(String::from("bad data chunk")));
+            }
+            if len > MAX_ITEM_SIZE {
+                io::copy(&mut reader.take(len as u64 + 2), &mut io::sink())?;
+                return Ok(b"SERVER_ERROR object too large for cache\r\n".to_vec());
+            }
+            let mut data = vec![0; len + 2];
+            reader.read_exact(&mut data)?;
+            if !data.ends_with(b"\r\n") {
+                return Err(KvsError::Protocol(String::from("bad data chunk")));
+            }
+            data.truncate(len);
+
+            let item = Item {
+                flags,
+                ttl: ttl(exptime),
+                data,
+            };
+            match (command, unique) {
+                (b"set", _) => {
+                    match item.ttl {
+                        Some(ttl) => engine.set_bytes_with_ttl(key, &item.encode(), ttl)?,
+                        None => engine.set_bytes(key, &item.encode())?,
+                    }
+                    reply("STORED")
+                }
+                (b"cas", Some(unique)) => reply(cas(engine, key, item, unique)?),
+                _ => {
+                    let add = command == b"add";
+                    let mut item = Some(item);
+                    let stored = update_item(engine, key, |current| {
+                        if current.is_some() == add {
+                            None
+                        } else {
+                            item.take()
+                        }
+                    })?;
+                    reply(if stored { "STORED" } else { "NOT_STORED" })
+                }
+            }
+        }
+
+        b"delete" if args.len() == 1 => match engine.remove_bytes(args[0]) {
+            Ok(()) => reply("DELETED"),
+            Err(KvsError::KeyNotFound) => reply("NOT_FOUND"),
+            Err(e) => Err(e),
+        },
+
+        b"incr" | b"decr" if args.len() == 2 => {
+            let key = args[0];
+            let delta = match parse::<u64>(args[1]) {
+                Some(delta) => delta,
+                None => return Ok(b"CLIENT_ERROR invalid numeric delta argument\r\n".to_vec()),
+            };
+
+            let mut found = false;
+            let mut counter = None;
+            update_item(engine, key, |current| {
+                let current = current?;
+                found = true;
+                let value = parse::<u64>(&current.data)?;
+                // incr wraps around at 64 bits, decr stops at 0
+                let value = if command == b"incr" {
+                    value.wrapping_add(delta)
+                } else {
+                    value.saturating_sub(delta)
+                };
+                counter = Some(value);
+                Some(Item {
+                    flags: current.flags,
+                    ttl: current.ttl,
+                    data: value.to_string().into_bytes(),
+                })
+            })?;
+
+            match (found, counter) {
+                (false, _) => reply("NOT_FOUND"),
+                (true, Some(value)) => reply(&value.to_string()),
+                (true, None) => Ok(
+                    b"CLIENT_ERROR cannot increment or decrement non-numeric value\r\n".to_vec(),
+                ),
+            }
+        }
+
+        b"flush_all" if args.len() <= 1 => {
+            if args.first().is_some_and(|delay| *delay != b"0") {
+                return Ok(b"CLIENT_ERROR delayed flush_all is not supported\r\n".to_vec());
+            }
+            flush_all(engine)?;
+            reply("OK")
+        }
+
+        b"version" if args.is_empty() => {
+            Ok(format!("VERSION {}\r\n", env!("CARGO_PKG_VERSION")).into_bytes())
+        }
+
+        _ => Ok(b"ERROR\r\n".to_vec()),
+    }
+}
+
+// removes every key, a batch of at most `FLUSH_BATCH_LEN` at a time so a big store is never held
+// in memory at once
+fn flush_all(engine: &impl KvsEngine) -> Result<()> {
+    let mut start = Bound::Unbounded;
+    loop {
+        let keys = engine
+            .scan_keys((start, Bound::Unbounded))?
+            .take(FLUSH_BATCH_LEN)
+            .collect::<Result<Vec<_>>>()?;
+        let last = match keys.last() {
+            Some(last) => last.clone(),
+            None => return Ok(()),
+        };
+
+        let mut batch = WriteBatch::new();
+        for key in keys {
+            batch.remove(key);
+        }
+        engine.apply_batch(batch)?;
+        start = Bound::Excluded(last);
+    }
+}
+
+// stores `item` only if the one there was last written by the write numbered `unique`
+//
+// the commit itself checks the key is still at that write, so nothing can slip in between
+fn cas(engine: &impl KvsEngine, key: &[u8], item: Item, unique: u64) -> Result<&'static str> {
+    let mut transaction = engine.begin();
+    transaction.expect_seq(key, Some(unique));
+    item.write_to(&mut transaction, key);
+    match engine.commit(transaction) {
+        Ok(()) => Ok("STORED"),
+        Err(KvsError::TransactionConflict { .. }) => match engine.get_bytes(key)? {
+            Some(_) => Ok("EXISTS"),
+            None => Ok("NOT_FOUND"),
+        },
+        Err(e) => Err(e),
+    }
+}
+
+fn parse_key(key: &[u8]) -> Option<&[u8]> {
+    Some(key).filter(|key| key.len() <= MAX_KEY_LEN)
+}
+
+fn parse<T: FromStr>(word: &[u8]) -> Option<T> {
+    std::str::from_utf8(word).ok()?.parse().ok()
+}
@@ -0,0 +1,434 @@
+//! the Redis serialization protocol (RESP2), which kvs-server speaks with `--protocol resp`
+//!
+//! requests are arrays of bulk strings, or one line of words for clients typing by hand. the
+//! commands served are GET, SET, DEL, EXISTS, MGET, MSET, PING, INFO and DBSIZE, along with the
+//! COMMAND and QUIT that clients like `redis-cli` send on their own.
+
+use crate::{KvsEngine, KvsError, Result, SetCondition, SetOutcome, WriteBatch};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+// the longest bulk string Redis accepts, anything past it is taken to be a broken request
+const MAX_BULK_LEN: i64 = 512 * 1024 * 1024;
+
+// how deep arrays may nest in a value read, anything deeper is taken to be a broken one rather
+// than recursed into
+const MAX_DEPTH: usize = 32;
+
+/// one RESP2 value
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    /// a line of text, `+OK`
+    SimpleString(String),
+
+    /// a line of text saying what went wrong, `-ERR ...`
+    Error(String),
+
+    /// a signed integer, `:1`
+    Integer(i64),
+
+    /// binary safe bytes, `None` is the null bulk string a missing key reads as
+    BulkString(Option<Vec<u8>>),
+
+    /// a list of values, `None` is the null array
+    Array(Option<Vec<Value>>),
+}
+
+impl Value {
+    /// a bulk string holding `bytes`
+    pub fn bulk(bytes: impl Into<Vec<u8>>) -> Self {
+        Self::BulkString(Some(bytes.into()))
+    }
+
+    /// the request a client sends to run a command, an array of bulk strings
+    pub fn command<I>(args: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<Vec<u8>>,
+    {
+        Self::Array(Some(args.into_iter().map(Self::bulk).collect()))
+    }
+
+    /// writes the value in its wire form
+    pub fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        match self {
+            Self::SimpleString(s) => write!(writer, "+{}\r\n", s),
+            Self::Error(message) => write!(writer, "-{}\r\n", message),
+            Self::Integer(n) => write!(writer, ":{}\r\n", n),
+            Self::BulkString(None) => writer.write_all(b"$-1\r\n"),
+            Self::BulkString(Some(bytes)) => {
+                write!(writer, "${}\r\n", bytes.len())?;
+                writer.write_all(bytes)?;
+                writer.write_all(b"\r\n")
+            }
+            Self::Array(None) => writer.write_all(b"*-1\r\n"),
+            Self::Array(Some(values)) => {
+                write!(writer, "*{}\r\n", values.len())?;
+                values.iter().try_for_each(|value| value.write_to(writer))
+            }
+        }
+    }
+
+    /// the value in its wire form
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        self.write_to(&mut bytes)
+            .expect("writing to a Vec doesn't fail");
+        bytes
+    }
+
+    /// reads one value, `None` if the stream ended before it started
+    pub fn read_from(reader: &mut impl BufRead) -> Result<Option<Self>> {
+        Self::read_nested(reader, 0)
+    }
+
+    // reads one value found `depth` arrays deep
+    fn read_nested(reader: &mut impl BufRead, depth: usize) -> Result<Option<Self>> {
+        let line = match read_line(reader)? {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+        let (kind, rest) = match line.split_first() {
+            Some(split) => split,
+            None => return Err(protocol_error("empty line where a value was expected")),
+        };
+
+        let value = match kind {
+            b'+' => Self::SimpleString(String::from_utf8(rest.to_vec())?),
+            b'-' => Self::Error(String::from_utf8(rest.to_vec())?),
+            b':' => Self::Integer(parse_int(rest)?),
+            b'$' => match parse_int(rest)? {
+                -1 => Self::BulkString(None),
+                len => Self::bulk(read_bulk(reader, len)?),
+            },
+            b'*' if depth == MAX_DEPTH => return Err(protocol_error("arrays nested too deep")),
+            b'*' => match parse_int(rest)? {
+                -1 => Self::Array(None),
+                len if len >= 0 => {
+                    // the length comes from the other end, so don't trust it for the allocation
+                    let mut values = Vec::with_capacity(len.min(1024) as usize);
+                    for _ in 0..len {
+                        match Self::read_nested(reader, depth + 1)? {
+                            Some(value) => values.push(value),
+                            None => return Err(protocol_error("stream ended inside an array")),
+                        }
+                    }
+                    Self::Array(Some(values))
+                }
+                len => return Err(protocol_error(&format!("invalid array length {}", len))),
+            },
+            kind => {
+                return Err(protocol_error(&format!(
+                    "unknown value type {:?}",
+                    *kind as char
+                )))
+            }
+        };
+        Ok(Some(value))
+    }
+}
+
+// reads the bytes of a bulk string `len` long and the CRLF after them
+//
+// the buffer grows as the bytes arrive, so a length that's declared but never sent isn't allocated
+fn read_bulk(reader: &mut impl BufRead, len: i64) -> Result<Vec<u8>> {
+    if !(0..=MAX_BULK_LEN).contains(&len) {
+        return Err(protocol_error(&format!("invalid bulk length {}", len)));
+    }
+
+    let mut bytes = Vec::new();
+    reader.take(len as u64 + 2).read_to_end(&mut bytes)?;
+    if bytes.len() as i64 != len + 2 {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    if !bytes.ends_with(b"\r\n") {
+        return Err(protocol_error("bulk string not terminated by CRLF"));
+    }
+    bytes.truncate(len as usize);
+    Ok(bytes)
+}
+
+// reads a line up to CRLF and returns it without the CRLF, `None` at the end of the stream
+fn read_line(reader: &mut impl BufRead) -> Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    if reader.read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with(b"\r\n") {
+        return Err(protocol_error("line not terminated by CRLF"));
+    }
+    line.truncate(line.len() - 2);
+    Ok(Some(line))
+}
+
+fn parse_int(bytes: &[u8]) -> Result<i64> {
+    std::str::from_utf8(bytes)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| protocol_error("invalid integer"))
+}
+
+fn protocol_error(message: &str) -> KvsError {
+    KvsError::Protocol(format!("RESP: {}", message))
+}
+
+// serves commands from one client until it closes the connection or sends QUIT
+//
+// like the JSON protocol, replies are flushed once every command already received has been
+// answered, so pipelined commands get their replies back together. a request that can't be
+// parsed leaves no way to find where the next one starts, so it's answered and the connection
+// closed, as Redis does.
+pub(crate) fn handle_connection(engine: &impl KvsEngine, stream: TcpStream) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    loop {
+        let args = match read_command(&mut reader) {
+            Ok(Some(args)) => args,
+            Ok(None) => return Ok(()),
+            Err(KvsError::Protocol(message)) => {
+                Value::Error(format!("ERR {}", message)).write_to(&mut writer)?;
+                writer.flush()?;
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        if args.is_empty() {
+            continue;
+        }
+
+        let quit = args[0].eq_ignore_ascii_case(b"QUIT");
+        let reply = if quit {
+            Value::SimpleString(String::from("OK"))
+        } else {
+            execute(engine, &args)
+        };
+
+        reply.write_to(&mut writer)?;
+        if quit {
+            writer.flush()?;
+            return Ok(());
+        }
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+}
+
+// reads the arguments of the next command, either an array of bulk strings or an inline command
+// made of words separated by spaces
+fn read_command(reader: &mut impl BufRead) -> Result<Option<Vec<Vec<u8>>>> {
+    let inline = match reader.fill_buf()?.first() {
+        Some(first) => *first != b'*',
+        None => return Ok(None),
+    };
+
+    if inline {
+        let mut line = Vec::new();
+        reader.read_until(b'\n', &mut line)?;
+        let line = line.strip_suffix(b"\n").unwrap_or(&line);
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        return Ok(Some(
+            line.split(|b| b.is_ascii_whitespace())
+                .filter(|word| !word.is_empty())
+                .map(<[u8]>::to_vec)
+                .collect(),
+        ));
+    }
+
+    // a command is one flat array of bulk strings, so it's read as just that and anything else in
+    // it is refused before it's read
+    let not_a_command = || protocol_error("commands are arrays of bulk strings");
+    let len = match read_line(reader)? {
+        Some(line) => parse_int(&line[1..])?,
+        None => return Ok(None),
+    };
+    if len < 0 {
+        return Err(not_a_command());
+    }
+
+    // the length comes from the other end, so don't trust it for the allocation
+    let mut args = Vec::with_capacity(len.min(1024) as usize);
+    for _ in 0..len {
+        let line =
+            read_line(reader)?.ok_or_else(|| protocol_error("stream ended inside an array"))?;
+        match line.split_first() {
+            Some((b'$', len)) => args.push(read_bulk(reader, parse_int(len)?)?),
+            _ => return Err(not_a_command()),
+        }
+    }
+    Ok(Some(args))
+}
+
+// runs one command, anything that goes wrong is the error reply
+fn execute(engine: &impl KvsEngine, args: &[Vec<u8>]) -> Value {
+    let name = String::from_utf8_lossy(&args[0]).to_ascii_lowercase();
+    run(engine, &name, &args[1..]).unwrap_or_else(|e| Value::Error(format!("ERR {}", e)))
+}
+
+fn run(engine: &impl KvsEngine, name: &str, args: &[Vec<u8>]) -> Result<Value> {
+    let arity_ok = match name {
+        "ping" => args.len() <= 1,
+        "get" => args.len() == 1,
+        "set" => args.len() >= 2,
+        "del" | "exists" | "mget" => !args.is_empty(),
+        "mset" => !args.is_empty() && args.len().is_multiple_of(2),
+        "info" => args.len() <= 1,
+        "dbsize" => args.is_empty(),
+        "command" => true,
+        _ => return Ok(Value::Error(format!("ERR unknown command '{}'", name))),
+    };
+    if !arity_ok {
+        return Ok(Value::Error(format!(
+            "ERR wrong number of arguments for '{}' command",
+            name
+        )));
+    }
+
+    Ok(match name {
+        "ping" => match args.first() {
+            Some(message) => Value::bulk(message.clone()),
+            None => Value::SimpleString(String::from("PONG")),
+        },
+        "get" => Value::BulkString(engine.get_bytes(&args[0])?),
+        "set" => return set(engine, &args[0], &args[1], &args[2..]),
+        "del" => {
+            let mut removed = 0;
+            for key in args {
+                match engine.remove_bytes(key) {
+                    Ok(()) => removed += 1,
+                    Err(KvsError::KeyNotFound) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            Value::Integer(removed)
+        }
+        "exists" => {
+            let mut found = 0;
+            for key in args {
+                if engine.get_bytes(key)?.is_some() {
+                    found += 1;
+                }
+            }
+            Value::Integer(found)
+        }
+        "mget" => Value::Array(Some(
+            args.iter()
+                .map(|key| Ok(Value::BulkString(engine.get_bytes(key)?)))
+                .collect::<Result<_>>()?,
+        )),
+        "mset" => {
+            let mut batch = WriteBatch::new();
+            for pair in args.chunks(2) {
+                batch.set(pair[0].clone(), pair[1].clone());
+            }
+            engine.apply_batch(batch)?;
+            Value::SimpleString(String::from("OK"))
+        }
+        "info" => Value::bulk(info(engine, args.first())?),
+        "dbsize" => Value::Integer(engine.key_count()? as i64),
+        // only sent by clients looking up what the server supports, which they cope without
+        "command" => Value::Array(Some(Vec::new())),
+        _ => unreachable!("unknown commands are answered above"),
+    })
+}
+
+// SET key value [NX|XX] [EX seconds|PX milliseconds]
+fn set(engine: &impl KvsEngine, key: &[u8], value: &[u8], options: &[Vec<u8>]) -> Result<Value> {
+    let syntax_error = || Ok(Value::Error(String::from("ERR syntax error")));
+    let mut condition = None;
+    let mut ttl = None;
+
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match option.to_ascii_uppercase().as_slice() {
+            b"NX" if condition.is_none() => condition = Some(SetCondition::Absent),
+            b"XX" if condition.is_none() => condition = Some(SetCondition::Present),
+            unit @ (b"EX" | b"PX") if ttl.is_none() => {
+                let amount = match options.next().map(|amount| parse_int(amount)) {
+                    Some(Ok(amount)) if amount > 0 => amount as u64,
+                    Some(_) => {
+                        return Ok(Value::Error(String::from(
+                            "ERR invalid expire time in 'set' command",
+                        )))
+                    }
+                    None => return syntax_error(),
+                };
+                ttl = Some(if unit == b"EX" {
+                    Duration::from_secs(amount)
+                } else {
+                    Duration::from_millis(amount)
+                });
+            }
+            _ => return syntax_error(),
+        }
+    }
+
+    match (condition, ttl) {
+        (None, None) => engine.set_bytes(key, value)?,
+        (None, Some(ttl)) => engine.set_bytes_with_ttl(key, value, ttl)?,
+        (Some(condition), None) => {
+            if let SetOutcome::Conflict(_) = engine.set_if(key, value, condition)? {
+                return Ok(Value::BulkString(None));
+            }
+        }
+        (Some(condition), Some(ttl)) => {
+            if !set_with_ttl_if(engine, key, value, condition, ttl)? {
+                return Ok(Value::BulkString(None));
+            }
+        }
+    }
+    Ok(Value::SimpleString(String::from("OK")))
+}
+
+// sets a key-value that expires after `ttl` if `condition` holds, returns whether it was set
+//
+// the commit checks the key is still at the write the condition was checked against, a write
+// slipping in between means checking again
+fn set_with_ttl_if(
+    engine: &impl KvsEngine,
+    key: &[u8],
+    value: &[u8],
+    condition: SetCondition,
+    ttl: Duration,
+) -> Result<bool> {
+    loop {
+        let current = engine.get_with_seq(key)?;
+        if !condition.is_met_by(current.as_ref().map(|(value, _)| &value[..])) {
+            return Ok(false);
+        }
+        let seq = current.map(|(_, seq)| seq);
+
+        let mut transaction = engine.begin();
+        transaction.expect_seq(key, seq);
+        transaction.set_with_ttl(key, value, ttl);
+        match engine.commit(transaction) {
+            Ok(()) => return Ok(true),
+            Err(KvsError::TransactionConflict { .. }) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn info(engine: &impl KvsEngine, section: Option<&Vec<u8>>) -> Result<String> {
+    let section = section.map(|section| String::from_utf8_lossy(section).to_ascii_lowercase());
+    let wants = |name: &str| {
+        section.as_deref().is_none_or(|section| {
+            matches!(section, "all" | "default" | "everything") || section == name
+        })
+    };
+
+    let mut info = String::new();
+    if wants("server") {
+        info.push_str("# Server\r\n");
+        info.push_str(&format!("kvs_version:{}\r\n", env!("CARGO_PKG_VERSION")));
+        info.push_str("redis_mode:standalone\r\n");
+        info.push_str("\r\n");
+    }
+    if wants("keyspace") {
+        info.push_str("# Keyspace\r\n");
+        info.push_str(&format!("db0:keys={}\r\n", engine.key_count()?));
+    }
+    Ok(info)
+}
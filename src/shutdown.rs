@@ -0,0 +1,129 @@
+//! stopping a running KvsServer, and keeping track of the connections it has to wait for
+
+use log::error;
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+/// stops a running KvsServer, from any thread
+///
+/// the server stops accepting connections, gives the ones it's serving until its shutdown
+/// timeout to answer the requests they've already sent, flushes the engine and returns from
+/// `run`. clones stop the same server.
+#[derive(Debug, Clone)]
+pub struct ShutdownHandle {
+    requested: Arc<AtomicBool>,
+    addr: SocketAddr, // where the server listens, connected to so it wakes up and notices
+}
+
+impl ShutdownHandle {
+    pub(crate) fn new(addr: SocketAddr) -> Self {
+        Self {
+            requested: Arc::new(AtomicBool::new(false)),
+            addr,
+        }
+    }
+
+    /// asks the server to shut down, without waiting for it to
+    pub fn shutdown(&self) {
+        if self.requested.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        // the server is blocked accepting connections, so hand it one
+        let mut addr = self.addr;
+        if addr.ip().is_unspecified() {
+            addr.set_ip(match addr {
+                SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            });
+        }
+        if let Err(e) = TcpStream::connect(addr) {
+            error!("Failed waking the server up to shut down: {}", e);
+        }
+    }
+
+    /// whether shutting down has been asked for
+    pub fn is_shutdown(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+}
+
+// the connections a server is serving
+#[derive(Default)]
+pub(crate) struct Connections {
+    state: Mutex<ConnectionsState>,
+    closed: Condvar, // signalled whenever a connection is done with
+}
+
+#[derive(Default)]
+struct ConnectionsState {
+    open: HashMap<u64, TcpStream>, // handles used to stop reading from each connection
+    next_id: u64,
+}
+
+impl Connections {
+    fn state(&self) -> MutexGuard<'_, ConnectionsState> {
+        // a panic in a handler can't leave the map half updated
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// tracks a connection until the returned registration is dropped
+    pub(crate) fn register(self: &Arc<Self>, stream: &TcpStream) -> io::Result<Registration> {
+        let handle = stream.try_clone()?;
+        let mut state = self.state();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.open.insert(id, handle);
+
+        Ok(Registration {
+            connections: Arc::clone(self),
+            id,
+        })
+    }
+
+    /// how many connections are being served
+    pub(crate) fn len(&self) -> usize {
+        self.state().open.len()
+    }
+
+    /// stops reading from every connection, so each ends once the requests already read from it
+    /// are answered, then waits up to `timeout` for them to. returns how many are still open
+    pub(crate) fn close_all(&self, timeout: Duration) -> usize {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.state();
+        for stream in state.open.values() {
+            // fails if the client already went away, which is as good
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+
+        while !state.open.is_empty() {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            state = self
+                .closed
+                .wait_timeout(state, deadline - now)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+        state.open.len()
+    }
+}
+
+// a connection being served, it stops being tracked when dropped, even by a panicking handler
+pub(crate) struct Registration {
+    connections: Arc<Connections>,
+    id: u64,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.connections.state().open.remove(&self.id);
+        self.connections.closed.notify_all();
+    }
+}
@@ -0,0 +1,28 @@
+//! pools of threads the server runs its requests on
+
+use crate::Result;
+
+mod naive;
+mod rayon;
+mod shared_queue;
+
+pub use self::naive::NaiveThreadPool;
+pub use self::rayon::RayonThreadPool;
+pub use self::shared_queue::SharedQueueThreadPool;
+
+/// runs jobs on a set of threads
+pub trait ThreadPool {
+    /// creates a pool with `threads` threads
+    ///
+    /// fails if any of the threads can't be started
+    fn new(threads: u32) -> Result<Self>
+    where
+        Self: Sized;
+
+    /// runs a job on one of the pool's threads
+    ///
+    /// a job that panics doesn't take the pool down with it, the pool keeps its number of threads
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static;
+}
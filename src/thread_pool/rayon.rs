@@ -0,0 +1,31 @@
+use super::ThreadPool;
+use crate::{KvsError, Result};
+use std::io;
+
+/// rayon's work stealing pool, every thread has a queue of its own and steals from the others when
+/// it runs out
+pub struct RayonThreadPool(rayon::ThreadPool);
+
+impl ThreadPool for RayonThreadPool {
+    fn new(threads: u32) -> Result<Self> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads as usize)
+            .panic_handler(|_| {}) // a panicking job leaves the thread it ran on up and running
+            .build()
+            .map_err(|e| {
+                KvsError::Io(io::Error::other(format!(
+                    "Failed building rayon thread pool: {}",
+                    e
+                )))
+            })?;
+
+        Ok(Self(pool))
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.0.spawn(job);
+    }
+}
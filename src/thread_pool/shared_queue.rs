@@ -0,0 +1,59 @@
+use super::ThreadPool;
+use crate::Result;
+use crossbeam_channel::{Receiver, Sender};
+use log::error;
+use std::thread;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// a fixed number of threads taking jobs off one shared queue
+///
+/// a thread whose job panics is replaced by a new one. the threads stop once the pool is dropped
+/// and the queue has run dry.
+pub struct SharedQueueThreadPool {
+    jobs: Sender<Job>,
+}
+
+impl ThreadPool for SharedQueueThreadPool {
+    fn new(threads: u32) -> Result<Self> {
+        let (jobs, queue) = crossbeam_channel::unbounded::<Job>();
+        for _ in 0..threads {
+            spawn_worker(Worker(queue.clone()))?;
+        }
+
+        Ok(Self { jobs })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.jobs
+            .send(Box::new(job))
+            .expect("SharedQueueThreadPool has no threads left");
+    }
+}
+
+// one of the pool's threads, dropped while panicking it starts another one in its place
+struct Worker(Receiver<Job>);
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            if let Err(e) = spawn_worker(Worker(self.0.clone())) {
+                error!("Failed replacing a panicked pool thread: {}", e);
+            }
+        }
+    }
+}
+
+fn spawn_worker(worker: Worker) -> Result<()> {
+    thread::Builder::new().spawn(move || {
+        // ends once every sender is gone
+        while let Ok(job) = worker.0.recv() {
+            job();
+        }
+    })?;
+
+    Ok(())
+}
@@ -1,7 +1,17 @@
 use assert_cmd::prelude::*;
+use kvs::thread_pool::{NaiveThreadPool, SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    BackgroundServer, Command as KvsCommand, Durability, EngineType, KvStore, KvsClient, KvsEngine,
+    KvsError, KvsServer, ServerConfig, ServerResponse, SetCondition, SetOutcome, SledKvsEngine,
+    WriteBatch,
+};
+use predicates::prelude::*;
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
-use std::process::Command;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
 use std::sync::mpsc;
 use std::thread;
 use std::time::Duration;
@@ -20,28 +30,28 @@ fn client_cli_invalid_get() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--addr", "invalid-addr"])
+        .args(["get", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -52,35 +62,35 @@ fn client_cli_invalid_set() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "extra_field"])
+        .args(["set", "key", "value", "extra_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "--addr", "invalid-addr"])
+        .args(["set", "key", "value", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -91,28 +101,28 @@ fn client_cli_invalid_rm() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--addr", "invalid-addr"])
+        .args(["rm", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--unknown-flag"])
+        .args(["rm", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -123,7 +133,7 @@ fn client_cli_invalid_subcommand() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["unknown"])
+        .args(["unknown"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -134,7 +144,7 @@ fn client_cli_invalid_subcommand() {
 fn client_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-client").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -145,30 +155,57 @@ fn client_cli_version() {
 fn server_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
 
+#[test]
+fn server_cli_invalid_sync() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--sync", "sometimes", "--addr", "127.0.0.1:4006"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--sync", "every-0ms", "--addr", "127.0.0.1:4006"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
 #[test]
 fn cli_log_configuration() {
     let temp_dir = TempDir::new().unwrap();
     let stderr_path = temp_dir.path().join("stderr");
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
     let mut child = cmd
-        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4001"])
+        .args([
+            "--engine",
+            "kvs",
+            "--addr",
+            "127.0.0.1:4001",
+            "--sync",
+            "every-100ms",
+        ])
         .current_dir(&temp_dir)
         .stderr(File::create(&stderr_path).unwrap())
         .spawn()
         .unwrap();
     thread::sleep(Duration::from_secs(1));
     child.kill().expect("server exited before killed");
+    child.wait().expect("failed waiting on killed server");
 
     let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
     assert!(content.contains(env!("CARGO_PKG_VERSION")));
     assert!(content.contains("kvs"));
     assert!(content.contains("127.0.0.1:4001"));
+    assert!(content.contains("every-100ms"));
 }
 
 #[test]
@@ -178,15 +215,16 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "sled", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "sled", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed waiting on killed server");
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "kvs", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "kvs", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
@@ -197,15 +235,16 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "kvs", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "kvs", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed waiting on killed server");
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "sled", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "sled", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
@@ -217,19 +256,20 @@ fn cli_access_server(engine: &str, addr: &str) {
     let temp_dir = TempDir::new().unwrap();
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed waiting on killed server");
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value1", "--addr", addr])
+        .args(["set", "key1", "value1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -237,7 +277,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -245,7 +285,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value2", "--addr", addr])
+        .args(["set", "key1", "value2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -253,7 +293,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -261,7 +301,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -269,7 +309,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key2", "--addr", addr])
+        .args(["rm", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .failure()
@@ -277,7 +317,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key2", "value3", "--addr", addr])
+        .args(["set", "key2", "value3", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -285,7 +325,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key1", "--addr", addr])
+        .args(["rm", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -298,26 +338,27 @@ fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed waiting on killed server");
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(contains("value3"));
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -335,3 +376,667 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+// Binary keys and values should survive the trip through the server
+#[test]
+fn client_binary_values() {
+    let temp_dir = TempDir::new().unwrap();
+    let server = BackgroundServer::start(EngineType::Kvs, temp_dir.path()).unwrap();
+
+    let key = vec![0xff, 0x00, b'\n'];
+    let value = vec![0x00, 0x9f, 0x92, 0x96, b'"', b'\n'];
+    let client = server.client();
+
+    client
+        .send_command(KvsCommand::Set {
+            key: key.clone(),
+            value: value.clone(),
+        })
+        .unwrap();
+    let result = client.send_command(KvsCommand::Get { key: key.clone() });
+
+    assert_eq!(result.unwrap(), Some(value));
+}
+
+// Scans should page through keys in order
+#[test]
+fn client_scan_pages() {
+    let temp_dir = TempDir::new().unwrap();
+    let server = BackgroundServer::start(EngineType::Kvs, temp_dir.path()).unwrap();
+
+    let client = server.client();
+    let result = (|| -> kvs::Result<Vec<Vec<u8>>> {
+        for key_id in (0..10).rev() {
+            client.send_command(KvsCommand::Set {
+                key: format!("key{}", key_id).into_bytes(),
+                value: b"value".to_vec(),
+            })?;
+        }
+
+        let (_, end) = kvs::prefix_range(b"key");
+        let end = match end {
+            std::ops::Bound::Excluded(end) => Some(end),
+            _ => None,
+        };
+
+        let mut keys = Vec::new();
+        let mut start = Some(b"key2".to_vec());
+        while let Some(page_start) = start {
+            let (pairs, next) = client.scan(Some(page_start), end.clone(), 3)?;
+            assert!(pairs.len() <= 3);
+            keys.extend(pairs.into_iter().map(|pair| pair.key));
+            start = next;
+        }
+        Ok(keys)
+    })();
+
+    let expected: Vec<Vec<u8>> = (2..10)
+        .map(|key_id| format!("key{}", key_id).into_bytes())
+        .collect();
+    assert_eq!(result.unwrap(), expected);
+}
+
+// Keys set with setex should count down until persisted
+#[test]
+fn cli_setex_ttl_persist() {
+    let temp_dir = TempDir::new().unwrap();
+    let server = BackgroundServer::start(EngineType::Kvs, temp_dir.path()).unwrap();
+
+    let addr = server.local_addr().to_string();
+    let client = |args: &[&str]| {
+        let mut command = Command::cargo_bin("kvs-client").unwrap();
+        command
+            .args(args)
+            .args(["--addr", &addr])
+            .current_dir(&temp_dir);
+        command.assert()
+    };
+
+    client(&["setex", "session", "100", "value"]).success();
+    client(&["get", "session"]).success().stdout("value\n");
+    client(&["ttl", "session"])
+        .success()
+        .stdout(predicates::str::is_match("^(99|100)\n$").unwrap());
+    client(&["persist", "session"]).success();
+    client(&["ttl", "session"]).success().stdout("No expiry\n");
+    client(&["persist", "session"]).failure();
+    client(&["ttl", "missing"])
+        .success()
+        .stdout("Key not found\n");
+
+    client(&["setex", "short", "0", "value"]).success();
+    client(&["get", "short"])
+        .success()
+        .stdout("Key not found\n");
+}
+
+// Batches should be applied on the server as a whole
+#[test]
+fn client_write_batch() {
+    let temp_dir = TempDir::new().unwrap();
+    let server = BackgroundServer::start(EngineType::Kvs, temp_dir.path()).unwrap();
+
+    let client = server.client();
+    let result = (|| -> kvs::Result<_> {
+        client.send_command(KvsCommand::Set {
+            key: b"key1".to_vec(),
+            value: b"value1".to_vec(),
+        })?;
+
+        let mut batch = WriteBatch::new();
+        batch.remove("key1").set("key2", "value2");
+        client.apply_batch(batch)?;
+
+        Ok((
+            client.send_command(KvsCommand::Get {
+                key: b"key1".to_vec(),
+            })?,
+            client.send_command(KvsCommand::Get {
+                key: b"key2".to_vec(),
+            })?,
+        ))
+    })();
+
+    assert_eq!(result.unwrap(), (None, Some(b"value2".to_vec())));
+}
+
+// Clients racing to increment a counter with compare-and-swap shouldn't lose updates
+#[test]
+fn client_compare_and_swap() {
+    let temp_dir = TempDir::new().unwrap();
+    let server = BackgroundServer::start(EngineType::Kvs, temp_dir.path()).unwrap();
+
+    let addr = server.local_addr();
+    let increment = move || -> kvs::Result<()> {
+        let client = KvsClient::with_addr(addr);
+        for _ in 0..20 {
+            loop {
+                let current = client.send_command(KvsCommand::Get {
+                    key: b"counter".to_vec(),
+                })?;
+                let (next, condition) = match current {
+                    Some(current) => {
+                        let count: u64 = String::from_utf8(current.clone())?.parse()?;
+                        (count + 1, SetCondition::Equals(current))
+                    }
+                    None => (1, SetCondition::Absent),
+                };
+
+                let outcome = client.set_if(
+                    b"counter".to_vec(),
+                    next.to_string().into_bytes(),
+                    condition,
+                )?;
+                if outcome == SetOutcome::Written {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    };
+
+    let clients: Vec<_> = (0..2).map(|_| thread::spawn(increment)).collect();
+    let results: Vec<_> = clients.into_iter().map(|c| c.join().unwrap()).collect();
+    let counter = server.client().send_command(KvsCommand::Get {
+        key: b"counter".to_vec(),
+    });
+
+    for result in results {
+        result.unwrap();
+    }
+    assert_eq!(counter.unwrap(), Some(b"40".to_vec()));
+}
+
+// Transactions started with MULTI should only commit if nothing they read changed meanwhile
+#[test]
+fn client_transactions() {
+    let temp_dir = TempDir::new().unwrap();
+    let server = BackgroundServer::start(EngineType::Kvs, temp_dir.path()).unwrap();
+
+    let run = || -> kvs::Result<()> {
+        let client = server.client();
+        client.send_command(KvsCommand::Set {
+            key: b"from".to_vec(),
+            value: b"10".to_vec(),
+        })?;
+
+        // a transaction that's left alone commits
+        let mut transaction = client.begin()?;
+        assert_eq!(transaction.get(b"from".to_vec())?, Some(b"10".to_vec()));
+        transaction.set(b"from".to_vec(), b"5".to_vec())?;
+        transaction.set(b"to".to_vec(), b"5".to_vec())?;
+        assert_eq!(transaction.get(b"to".to_vec())?, Some(b"5".to_vec()));
+        transaction.commit()?;
+        assert_eq!(
+            client.send_command(KvsCommand::Get {
+                key: b"to".to_vec()
+            })?,
+            Some(b"5".to_vec())
+        );
+
+        // another client writing a key the transaction read makes the commit fail
+        let mut transaction = client.begin()?;
+        assert_eq!(transaction.get(b"from".to_vec())?, Some(b"5".to_vec()));
+        transaction.set(b"to".to_vec(), b"10".to_vec())?;
+        client.send_command(KvsCommand::Set {
+            key: b"from".to_vec(),
+            value: b"0".to_vec(),
+        })?;
+        match transaction.commit() {
+            Err(KvsError::TransactionConflict { key }) => assert_eq!(key, b"from"),
+            result => panic!("expected a conflict on from, got {:?}", result),
+        }
+        assert_eq!(
+            client.send_command(KvsCommand::Get {
+                key: b"to".to_vec()
+            })?,
+            Some(b"5".to_vec())
+        );
+
+        // discarded transactions write nothing
+        let mut transaction = client.begin()?;
+        transaction.remove(b"to".to_vec())?;
+        transaction.discard()?;
+        assert_eq!(
+            client.send_command(KvsCommand::Get {
+                key: b"to".to_vec()
+            })?,
+            Some(b"5".to_vec())
+        );
+
+        Ok(())
+    };
+    run().unwrap();
+}
+
+// Pipelined requests on one connection should be answered in order
+#[test]
+fn client_pipelining() {
+    let temp_dir = TempDir::new().unwrap();
+    let server = BackgroundServer::start(EngineType::Kvs, temp_dir.path()).unwrap();
+
+    let client = server.client();
+    let commands = (0..100)
+        .flat_map(|i| {
+            let key = format!("key{}", i).into_bytes();
+            vec![
+                KvsCommand::Set {
+                    key: key.clone(),
+                    value: format!("value{}", i).into_bytes(),
+                },
+                KvsCommand::Get { key },
+            ]
+        })
+        .collect::<Vec<_>>();
+    let responses = client.send_pipelined(&commands);
+
+    // the same connection keeps serving requests afterwards
+    let last = client.send_command(KvsCommand::Get {
+        key: b"key99".to_vec(),
+    });
+
+    let responses = responses.unwrap();
+    assert_eq!(responses.len(), 200);
+    for (i, pair) in responses.chunks(2).enumerate() {
+        assert!(matches!(pair[0], ServerResponse::SetSuccess));
+        match &pair[1] {
+            ServerResponse::GetResponse(Some(value)) => {
+                assert_eq!(value, format!("value{}", i).as_bytes())
+            }
+            response => panic!("unexpected response {:?}", response),
+        }
+    }
+    assert_eq!(last.unwrap(), Some(b"value99".to_vec()));
+}
+
+// A pipeline too big for the socket buffers both ways should still get all its responses
+#[test]
+fn client_pipelining_past_socket_buffers() {
+    let temp_dir = TempDir::new().unwrap();
+    let server = BackgroundServer::start(EngineType::Kvs, temp_dir.path()).unwrap();
+    let client = server.client();
+    let key = vec![b'k'; 16 * 1024];
+    let value = vec![b'v'; 16 * 1024];
+    client
+        .send_command(KvsCommand::Set {
+            key: key.clone(),
+            value: value.clone(),
+        })
+        .unwrap();
+
+    // tens of megabytes of requests and as much again in responses
+    let commands = (0..1000)
+        .map(|_| KvsCommand::Get { key: key.clone() })
+        .collect::<Vec<_>>();
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || sender.send(client.send_pipelined(&commands)).unwrap());
+    let responses = receiver
+        .recv_timeout(Duration::from_secs(30))
+        .expect("pipeline deadlocked")
+        .unwrap();
+
+    assert_eq!(responses.len(), 1000);
+    for response in responses {
+        match response {
+            ServerResponse::GetResponse(Some(got)) => assert_eq!(got, value),
+            response => panic!("unexpected response {:?}", response),
+        }
+    }
+}
+
+// Bad requests should get an error back without taking the connection or the server down
+#[test]
+fn server_error_responses() {
+    let temp_dir = TempDir::new().unwrap();
+    let server = BackgroundServer::start(EngineType::Kvs, temp_dir.path()).unwrap();
+    let addr = server.local_addr();
+
+    let run = || -> kvs::Result<_> {
+        let (malformed, invalid_utf8, exec, remove, get) = {
+            let stream = TcpStream::connect(addr)?;
+            let mut reader = BufReader::new(stream.try_clone()?);
+            let mut writer = stream;
+            let mut exchange = |request: &[u8]| -> kvs::Result<ServerResponse> {
+                writer.write_all(request)?;
+                let mut response = String::new();
+                reader.read_line(&mut response)?;
+                Ok(serde_json::from_str(&response)?)
+            };
+
+            let malformed = exchange(b"{\"Get\":\n")?;
+            let invalid_utf8 = exchange(b"\xff\xfe\n")?;
+            let exec = exchange(b"\"Exec\"\n")?;
+            let remove = exchange(b"{\"Remove\":{\"key\":\"a2V5\"}}\n")?;
+            let get = exchange(b"{\"Get\":{\"key\":\"a2V5\"}}\n")?;
+            (malformed, invalid_utf8, exec, remove, get)
+        };
+
+        // errors reach KvsClient callers as they were on the server
+        let client_remove = server
+            .client()
+            .send_command(KvsCommand::Remove {
+                key: b"key".to_vec(),
+            })
+            .unwrap_err();
+
+        Ok((malformed, invalid_utf8, exec, remove, get, client_remove))
+    };
+    let result = run();
+
+    let (malformed, invalid_utf8, exec, remove, get, client_remove) = result.unwrap();
+    for response in [malformed, invalid_utf8, exec] {
+        assert!(matches!(
+            response,
+            ServerResponse::Error(KvsError::Protocol(_))
+        ));
+    }
+    assert!(matches!(
+        remove,
+        ServerResponse::Error(KvsError::KeyNotFound)
+    ));
+    assert!(matches!(get, ServerResponse::GetResponse(None)));
+    assert!(matches!(client_remove, KvsError::KeyNotFound));
+}
+
+// Errors should come out of the wire protocol as the same variant they went in as
+#[test]
+fn errors_round_trip_over_the_wire() {
+    let round_trip = |e: KvsError| -> KvsError {
+        let json = serde_json::to_string(&ServerResponse::Error(e)).unwrap();
+        match serde_json::from_str(&json).unwrap() {
+            ServerResponse::Error(e) => e,
+            response => panic!("unexpected response {:?}", response),
+        }
+    };
+
+    assert!(matches!(
+        round_trip(KvsError::KeyNotFound),
+        KvsError::KeyNotFound
+    ));
+    match round_trip(KvsError::TransactionConflict {
+        key: vec![0xff, b'\n'],
+    }) {
+        KvsError::TransactionConflict { key } => assert_eq!(key, vec![0xff, b'\n']),
+        e => panic!("unexpected error {:?}", e),
+    }
+    match round_trip(KvsError::Io(std::io::Error::other("disk on fire"))) {
+        KvsError::Io(e) => assert_eq!(e.to_string(), "disk on fire"),
+        e => panic!("unexpected error {:?}", e),
+    }
+}
+
+// Shutting a server down should let go of idle connections, flush the engine and return from run
+#[test]
+fn server_shuts_down_gracefully() {
+    let temp_dir = TempDir::new().unwrap();
+    let server = KvsServer::new(
+        "127.0.0.1:0".parse().unwrap(),
+        KvStore::open(temp_dir.path()).unwrap(),
+        SharedQueueThreadPool::new(2).unwrap(),
+    )
+    .unwrap()
+    .with_shutdown_timeout(Duration::from_secs(5))
+    .spawn()
+    .unwrap();
+    let addr = server.local_addr();
+
+    // the client keeps its connection open after the request
+    let client = server.client();
+    client
+        .send_command(KvsCommand::Set {
+            key: b"key1".to_vec(),
+            value: b"value1".to_vec(),
+        })
+        .unwrap();
+
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || sender.send(server.stop()).unwrap());
+    receiver
+        .recv_timeout(Duration::from_secs(3))
+        .expect("server didn't shut down in time")
+        .unwrap();
+    assert!(TcpStream::connect(addr).is_err());
+
+    // the idle connection was closed rather than left hanging
+    assert!(client
+        .send_command(KvsCommand::Get {
+            key: b"key1".to_vec()
+        })
+        .is_err());
+
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get_bytes(b"key1").unwrap(), Some(b"value1".to_vec()));
+}
+
+// the address a kvs-server started on port 0 with its log piped says it's listening on
+fn listening_addr(child: &mut Child) -> SocketAddr {
+    let mut log = BufReader::new(child.stderr.take().unwrap()).lines();
+    let addr = log
+        .find_map(|line| {
+            let line = line.unwrap();
+            let (_, addr) = line.split_once("Listening on ")?;
+            Some(addr.trim().parse().unwrap())
+        })
+        .expect("server exited before listening");
+
+    // the rest of the log is drained so the server never writes to a closed pipe
+    thread::spawn(move || log.for_each(drop));
+    addr
+}
+
+// SIGTERM should have kvs-server shut down cleanly and exit successfully
+#[cfg(unix)]
+#[test]
+fn server_exits_cleanly_on_sigterm() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:0"])
+        .current_dir(&temp_dir)
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let addr = listening_addr(&mut child);
+
+    let client = KvsClient::with_addr(addr);
+    let set = client.send_command(KvsCommand::Set {
+        key: b"key1".to_vec(),
+        value: b"value1".to_vec(),
+    });
+
+    Command::new("kill")
+        .args(["-TERM", &child.id().to_string()])
+        .assert()
+        .success();
+    let mut status = None;
+    for _ in 0..50 {
+        status = child.try_wait().unwrap();
+        if status.is_some() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    if status.is_none() {
+        child.kill().expect("server exited before killed");
+        child.wait().expect("failed waiting on killed server");
+    }
+
+    set.unwrap();
+    assert!(status.expect("server didn't exit on SIGTERM").success());
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get_bytes(b"key1").unwrap(), Some(b"value1".to_vec()));
+}
+
+// Background servers should each get a port of their own and keep their data once stopped
+#[test]
+fn background_servers_run_side_by_side() {
+    let (kvs_dir, sled_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+    let kvs_server = BackgroundServer::start(EngineType::Kvs, kvs_dir.path()).unwrap();
+    let sled_server = BackgroundServer::start(EngineType::Sled, sled_dir.path()).unwrap();
+    assert_ne!(kvs_server.local_addr(), sled_server.local_addr());
+
+    for (server, value) in [(&kvs_server, b"kvs"), (&sled_server, b"sld")] {
+        server
+            .client()
+            .send_command(KvsCommand::Set {
+                key: b"engine".to_vec(),
+                value: value.to_vec(),
+            })
+            .unwrap();
+    }
+    assert_eq!(
+        kvs_server
+            .client()
+            .send_command(KvsCommand::Get {
+                key: b"engine".to_vec()
+            })
+            .unwrap(),
+        Some(b"kvs".to_vec())
+    );
+
+    // stopping waits for the server, and so does dropping it
+    let addr = kvs_server.local_addr();
+    kvs_server.stop().unwrap();
+    assert!(TcpStream::connect(addr).is_err());
+    drop(sled_server);
+
+    let store = KvStore::open(kvs_dir.path()).unwrap();
+    assert_eq!(store.get_bytes(b"engine").unwrap(), Some(b"kvs".to_vec()));
+    let store = SledKvsEngine::open(sled_dir.path()).unwrap();
+    assert_eq!(store.get_bytes(b"engine").unwrap(), Some(b"sld".to_vec()));
+}
+
+// `--print-config` should show the config file's settings, with any flags given laid over them
+#[test]
+fn server_print_config() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::create_dir(temp_dir.path().join("conf")).unwrap();
+    fs::write(
+        temp_dir.path().join("conf/kvs.toml"),
+        "addr = \"127.0.0.1:5000\"\n\
+         engine = \"sled\"\n\
+         data_dir = \"data\"\n\
+         sync = \"every-100ms\"\n\
+         log_level = \"warn\"\n\
+         max_connections = 8\n",
+    )
+    .unwrap();
+
+    let output = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--config", "conf/kvs.toml", "--addr", "127.0.0.1:6000"])
+        .args(["--threads", "3", "--print-config"])
+        .current_dir(&temp_dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let config: ServerConfig = toml::from_str(&String::from_utf8(output.stdout).unwrap()).unwrap();
+    assert_eq!(config.addr, "127.0.0.1:6000".parse().unwrap());
+    assert_eq!(config.engine, Some(EngineType::Sled));
+    assert_eq!(config.data_dir, Path::new("conf").join("data"));
+    assert_eq!(config.sync, Some(Durability::EveryN(100)));
+    assert_eq!(config.log_level, log::LevelFilter::Warn);
+    assert_eq!(config.max_connections, Some(8));
+    assert_eq!(config.threads, Some(3));
+
+    // with no file the defaults are filled in for whatever the data dir holds
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--print-config"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("engine = \"kvs\"").and(contains("sync = \"never\"")));
+}
+
+// Config files with settings that don't exist or can't be used should be refused
+#[test]
+fn server_invalid_config() {
+    let temp_dir = TempDir::new().unwrap();
+    for contents in [
+        "bogus = 1\n",
+        "engine = \"mysql\"\n",
+        "compaction_threshold = 0.0\n",
+        "max_connections = 0\n",
+    ] {
+        fs::write(temp_dir.path().join("kvs.toml"), contents).unwrap();
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--config", "kvs.toml", "--print-config"])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure();
+    }
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--config", "missing.toml", "--print-config"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+// `--data-dir` should have the server keep its data there rather than the working directory
+#[test]
+fn server_data_dir() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args([
+            "--engine",
+            "kvs",
+            "--addr",
+            "127.0.0.1:0",
+            "--data-dir",
+            "data",
+        ])
+        .current_dir(&temp_dir)
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    let addr = listening_addr(&mut child);
+
+    let result = KvsClient::with_addr(addr).send_command(KvsCommand::Set {
+        key: b"key1".to_vec(),
+        value: b"value1".to_vec(),
+    });
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed waiting on killed server");
+    result.unwrap();
+
+    let entries = fs::read_dir(&temp_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect::<Vec<_>>();
+    assert_eq!(entries, vec!["data"]);
+    let store = KvStore::open(temp_dir.path().join("data")).unwrap();
+    assert_eq!(store.get_bytes(b"key1").unwrap(), Some(b"value1".to_vec()));
+}
+
+// Connections past the limit should be closed straight away, until one of the others ends
+#[test]
+fn server_max_connections() {
+    let temp_dir = TempDir::new().unwrap();
+    let server = KvsServer::new(
+        SocketAddr::from(([127, 0, 0, 1], 0)),
+        KvStore::open(temp_dir.path()).unwrap(),
+        NaiveThreadPool::new(0).unwrap(),
+    )
+    .unwrap()
+    .with_max_connections(1)
+    .spawn()
+    .unwrap();
+    let ping = || {
+        server.client().send_command(KvsCommand::Get {
+            key: b"key1".to_vec(),
+        })
+    };
+
+    let idle = TcpStream::connect(server.local_addr()).unwrap();
+    assert!(ping().is_err());
+
+    drop(idle);
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(ping().unwrap(), None);
+}
@@ -0,0 +1,161 @@
+use kvs::{BackgroundServer, EngineType, Protocol, Result};
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use tempfile::TempDir;
+
+struct Response {
+    status: u16,
+    headers: Vec<String>,
+    body: Vec<u8>,
+}
+
+// reads one response, going by its Content-Length
+fn read_response(reader: &mut impl BufRead) -> Result<Response> {
+    let mut status_line = String::new();
+    reader.read_line(&mut status_line)?;
+    let status = status_line.split(' ').nth(1).unwrap().parse()?;
+
+    let mut headers = Vec::new();
+    let mut content_length = 0;
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let line = line.trim_end().to_owned();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(length) = line.strip_prefix("Content-Length: ") {
+            content_length = length.parse()?;
+        }
+        headers.push(line);
+    }
+
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+    Ok(Response {
+        status,
+        headers,
+        body,
+    })
+}
+
+// the keys and cursor of a page of `GET /keys`
+fn keys_page(response: &Response) -> Result<(Vec<Vec<u8>>, Option<String>)> {
+    let page = serde_json::from_slice::<serde_json::Value>(&response.body)?;
+    let keys = page["keys"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|key| base64::decode(key.as_str().unwrap()).unwrap())
+        .collect();
+    Ok((keys, page["next"].as_str().map(String::from)))
+}
+
+// The server should serve the store when speaking HTTP
+#[test]
+fn server_speaks_http() {
+    let temp_dir = TempDir::new().unwrap();
+    let server =
+        BackgroundServer::start_with_protocol(EngineType::Kvs, temp_dir.path(), Protocol::Http)
+            .unwrap();
+    let addr = server.local_addr();
+
+    let run = || -> Result<()> {
+        let stream = TcpStream::connect(addr)?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = stream;
+        let mut exchange = |method: &str, target: &str, body: &[u8]| -> Result<Response> {
+            write!(
+                writer,
+                "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\n\r\n",
+                method,
+                target,
+                addr,
+                body.len()
+            )?;
+            writer.write_all(body)?;
+            read_response(&mut reader)
+        };
+
+        assert_eq!(exchange("GET", "/keys/key1", b"")?.status, 404);
+        assert_eq!(exchange("PUT", "/keys/key1", b"value1")?.status, 204);
+        let get = exchange("GET", "/keys/key1", b"")?;
+        assert_eq!(get.status, 200);
+        assert_eq!(get.body, b"value1");
+
+        // keys are percent-decoded
+        assert_eq!(exchange("PUT", "/keys/key%202", b"value2")?.status, 204);
+        assert_eq!(exchange("GET", "/keys/key%202", b"")?.body, b"value2");
+        assert_eq!(exchange("PUT", "/keys/other?ttl=100", b"x")?.status, 204);
+        assert_eq!(exchange("PUT", "/keys/other?ttl=never", b"x")?.status, 400);
+
+        // keys are listed as base64, so any bytes round-trip
+        assert_eq!(exchange("PUT", "/keys/key%FF%00", b"x")?.status, 204);
+        let list = exchange("GET", "/keys?prefix=key", b"")?;
+        assert_eq!(list.status, 200);
+        assert!(list
+            .headers
+            .contains(&String::from("Content-Type: application/json")));
+        let (keys, next) = keys_page(&list)?;
+        assert_eq!(keys, vec![&b"key 2"[..], b"key1", b"key\xff\x00"]);
+        assert_eq!(next, None);
+
+        // pages are walked with the cursor each one ends with
+        let mut keys = Vec::new();
+        let mut target = String::from("/keys?limit=2");
+        loop {
+            let (page, next) = keys_page(&exchange("GET", &target, b"")?)?;
+            assert!(page.len() <= 2);
+            keys.extend(page);
+            match next {
+                Some(cursor) => target = format!("/keys?limit=2&cursor={}", cursor),
+                None => break,
+            }
+        }
+        assert_eq!(keys, vec![&b"key 2"[..], b"key1", b"key\xff\x00", b"other"]);
+        assert_eq!(exchange("GET", "/keys?limit=0", b"")?.status, 400);
+        assert_eq!(exchange("GET", "/keys?cursor=!", b"")?.status, 400);
+        assert_eq!(exchange("DELETE", "/keys/key%FF%00", b"")?.status, 204);
+
+        assert_eq!(exchange("DELETE", "/keys/key1", b"")?.status, 204);
+        let delete = exchange("DELETE", "/keys/key1", b"")?;
+        assert_eq!(delete.status, 404);
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&delete.body)?["error"],
+            "Key not found"
+        );
+
+        let health = exchange("GET", "/health", b"")?;
+        assert_eq!(health.status, 200);
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&health.body)?["status"],
+            "ok"
+        );
+        let stats = exchange("GET", "/stats", b"")?;
+        assert_eq!(stats.status, 200);
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&stats.body)?["keys"],
+            2
+        );
+
+        let not_allowed = exchange("POST", "/keys/key1", b"")?;
+        assert_eq!(not_allowed.status, 405);
+        assert!(not_allowed
+            .headers
+            .contains(&String::from("Allow: GET, PUT, DELETE")));
+        assert_eq!(exchange("GET", "/nowhere", b"")?.status, 404);
+
+        // a request that can't be parsed is answered, then the connection is closed
+        let malformed = exchange("GET", "/keys/key 2", b"")?;
+        assert_eq!(malformed.status, 400);
+        assert!(malformed
+            .headers
+            .contains(&String::from("Connection: close")));
+
+        Ok(())
+    };
+    let result = run();
+    server.stop().unwrap();
+
+    result.unwrap();
+}
@@ -1,4 +1,11 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{
+    prefix_range, Durability, EngineType, KeyTtl, KvStore, KvStoreOptions, KvsEngine, KvsError,
+    ReadAt, Result, Retention, SetOutcome, SledKvsEngine, SledOptions, WriteBatch,
+};
+use std::fs;
+use std::ops::Bound;
+use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -6,7 +13,7 @@ use walkdir::WalkDir;
 #[test]
 fn get_stored_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
@@ -16,7 +23,7 @@ fn get_stored_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
     assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
 
@@ -27,7 +34,7 @@ fn get_stored_value() -> Result<()> {
 #[test]
 fn overwrite_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
@@ -36,7 +43,7 @@ fn overwrite_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
     store.set("key1".to_owned(), "value3".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
@@ -48,14 +55,14 @@ fn overwrite_value() -> Result<()> {
 #[test]
 fn get_non_existent_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     Ok(())
@@ -64,15 +71,132 @@ fn get_non_existent_value() -> Result<()> {
 #[test]
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert!(store.remove("key1".to_owned()).is_err());
     Ok(())
 }
 
+// A directory holding one engine's data shouldn't be opened as the other
+#[test]
+fn incompatible_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    KvStore::open(temp_dir.path())?.set("key1".to_owned(), "value1".to_owned())?;
+
+    assert_eq!(EngineType::resolve(None, temp_dir.path())?, EngineType::Kvs);
+    assert!(matches!(
+        EngineType::resolve(Some(EngineType::Sled), temp_dir.path()),
+        Err(KvsError::IncompatibleEngine {
+            requested: EngineType::Sled,
+            existing: EngineType::Kvs,
+        })
+    ));
+
+    Ok(())
+}
+
+// Opening a store should record which engine it belongs to, and the other engine should refuse it
+#[test]
+fn manifest_names_the_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    drop(SledKvsEngine::open(temp_dir.path())?);
+
+    let manifest: serde_json::Value =
+        serde_json::from_slice(&fs::read(temp_dir.path().join("MANIFEST"))?)?;
+    assert_eq!(manifest["engine"], "sled");
+    assert_eq!(manifest["format_version"], 1);
+    assert_eq!(manifest["options"]["durability"], "every-write");
+    assert!(manifest["created_at"].as_u64().unwrap() > 0);
+
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::IncompatibleEngine {
+            requested: EngineType::Kvs,
+            existing: EngineType::Sled,
+        })
+    ));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    KvStore::open(temp_dir.path())?.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(matches!(
+        SledKvsEngine::open(temp_dir.path()),
+        Err(KvsError::IncompatibleEngine {
+            requested: EngineType::Sled,
+            existing: EngineType::Kvs,
+        })
+    ));
+
+    // a stray file named like the other engine's doesn't change what the manifest says
+    fs::write(temp_dir.path().join("sled_db.log"), "stray")?;
+    assert_eq!(EngineType::resolve(None, temp_dir.path())?, EngineType::Kvs);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// A store written in a newer format than this build reads should be refused
+#[test]
+fn refuse_future_format_version() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    drop(KvStore::open(temp_dir.path())?);
+
+    let manifest_path = temp_dir.path().join("MANIFEST");
+    let mut manifest: serde_json::Value = serde_json::from_slice(&fs::read(&manifest_path)?)?;
+    manifest["format_version"] = serde_json::json!(999);
+    fs::write(&manifest_path, manifest.to_string())?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::UnsupportedFormat {
+            engine: EngineType::Kvs,
+            version: 999,
+            supported: 1,
+        }) => {}
+        other => panic!("unexpected result {:?}", other.map(|_| ())),
+    }
+
+    Ok(())
+}
+
+// A store created before manifests existed should open and be given one
+#[test]
+fn manifest_added_to_existing_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    KvStore::open(temp_dir.path())?.set("key1".to_owned(), "value1".to_owned())?;
+    fs::remove_file(temp_dir.path().join("MANIFEST"))?;
+
+    assert!(matches!(
+        SledKvsEngine::open(temp_dir.path()),
+        Err(KvsError::IncompatibleEngine { .. })
+    ));
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(temp_dir.path().join("MANIFEST").is_file());
+
+    Ok(())
+}
+
+// Both engines should fail the same way when removing a key that isn't set
+#[test]
+fn remove_non_existent_key_is_key_not_found() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let err = KvStore::open(temp_dir.path())?
+        .remove("key1".to_owned())
+        .unwrap_err();
+    assert!(matches!(err, KvsError::KeyNotFound));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let err = SledKvsEngine::open(temp_dir.path())?
+        .remove("key1".to_owned())
+        .unwrap_err();
+    assert!(matches!(err, KvsError::KeyNotFound));
+
+    Ok(())
+}
+
 #[test]
 fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert!(store.remove("key1".to_owned()).is_ok());
     assert_eq!(store.get("key1".to_owned())?, None);
@@ -84,7 +208,7 @@ fn remove_key() -> Result<()> {
 #[test]
 fn compaction() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     let dir_size = || {
         let entries = WalkDir::new(temp_dir.path()).into_iter();
@@ -114,7 +238,7 @@ fn compaction() -> Result<()> {
 
         drop(store);
         // reopen and check content
-        let mut store = KvStore::open(temp_dir.path())?;
+        let store = KvStore::open(temp_dir.path())?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
             assert_eq!(store.get(key)?, Some(format!("{}", iter)));
@@ -124,3 +248,1418 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// Writing past the segment size should start new log segments that are all read back on open
+#[test]
+fn log_rolls_over_into_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let value = "v".repeat(1024);
+    for key_id in 0..2000 {
+        store.set(format!("key{}", key_id), value.clone())?;
+    }
+
+    let num_segments = fs::read_dir(temp_dir.path())?
+        .filter(|entry| {
+            entry
+                .as_ref()
+                .map(|entry| entry.path().extension() == Some("log".as_ref()))
+                .unwrap_or(false)
+        })
+        .count();
+    assert!(num_segments > 1, "expected several log segments");
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..2000 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(value.clone()));
+    }
+
+    Ok(())
+}
+
+// A store written before the log was segmented should still open
+#[test]
+fn open_legacy_single_file_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("kvs.log"),
+        "{\"Set\":{\"key\":\"key1\",\"value\":\"value1\"}}\n\
+         {\"Set\":{\"key\":\"key2\",\"value\":\"value2\"}}\n\
+         {\"Remove\":{\"key\":\"key1\"}}\n",
+    )?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// A flipped bit inside a record should be caught by its checksum
+#[test]
+fn detect_corrupt_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log_path = temp_dir.path().join("1.log");
+    let mut log = fs::read(&log_path)?;
+    let value_pos = log
+        .windows(b"value1".len())
+        .position(|window| window == b"value1")
+        .expect("value not found in log");
+    log[value_pos] ^= 0x01;
+    fs::write(&log_path, log)?;
+
+    // the hint written on shutdown means the log isn't replayed, reading the record catches it
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.get("key1".to_owned()).is_err());
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+
+    // replaying the whole log catches it on open
+    fs::remove_file(temp_dir.path().join("index.hint"))?;
+    assert!(KvStore::open(temp_dir.path()).is_err());
+
+    Ok(())
+}
+
+// Cutting the log anywhere inside its last record, like a crash halfway through a write would,
+// should still open with every earlier record intact
+#[test]
+fn recover_from_torn_last_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_path = temp_dir.path().join("1.log");
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let good_len = fs::metadata(&log_path)?.len();
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    let full_log = fs::read(&log_path)?;
+
+    for crash_at in good_len..full_log.len() as u64 {
+        let crash_dir = TempDir::new().expect("unable to create temporary working directory");
+        let crash_log_path = crash_dir.path().join("1.log");
+        fs::write(&crash_log_path, &full_log[..crash_at as usize])?;
+
+        let strict = KvStoreOptions {
+            strict: true,
+            ..KvStoreOptions::default()
+        };
+        if crash_at > good_len {
+            assert!(KvStore::open_with_options(crash_dir.path(), strict).is_err());
+        }
+
+        let store = KvStore::open(crash_dir.path())?;
+        assert_eq!(fs::metadata(&crash_log_path)?.len(), good_len);
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(store.get("key3".to_owned())?, None);
+
+        // the store should keep working after recovering
+        store.set("key3".to_owned(), "value4".to_owned())?;
+        drop(store);
+        let store = KvStore::open(crash_dir.path())?;
+        assert_eq!(store.get("key3".to_owned())?, Some("value4".to_owned()));
+    }
+
+    Ok(())
+}
+
+// A damaged last record should be dropped, or refused in strict mode
+#[test]
+fn recover_from_corrupt_last_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    std::mem::forget(store); // crash without a clean shutdown
+
+    let log_path = temp_dir.path().join("1.log");
+    let mut log = fs::read(&log_path)?;
+    let last = log.len() - 1;
+    log[last] ^= 0x01;
+    fs::write(&log_path, log)?;
+
+    let strict = KvStoreOptions {
+        strict: true,
+        ..KvStoreOptions::default()
+    };
+    assert!(matches!(
+        KvStore::open_with_options(temp_dir.path(), strict),
+        Err(KvsError::CorruptLog(_))
+    ));
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// A damaged length in a record before the last shouldn't pass for a torn tail and cut off every
+// record after it
+#[test]
+fn detect_damaged_length_before_last_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    std::mem::forget(store); // crash without a clean shutdown
+
+    // the second record's value length now runs far past the end of the segment
+    let log_path = temp_dir.path().join("1.log");
+    let mut log = fs::read(&log_path)?;
+    let log_len = log.len();
+    let record_len = log_len / 3;
+    log[record_len + 11] ^= 0x80;
+    fs::write(&log_path, log)?;
+
+    let strict = KvStoreOptions {
+        strict: true,
+        ..KvStoreOptions::default()
+    };
+    assert!(matches!(
+        KvStore::open_with_options(temp_dir.path(), strict),
+        Err(KvsError::CorruptLog(_))
+    ));
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::CorruptLog(_))
+    ));
+    assert_eq!(fs::metadata(&log_path)?.len(), log_len as u64);
+
+    Ok(())
+}
+
+// A length damaged past repair in the middle of a segment shouldn't be taken for a torn tail, that
+// would cut off every record after it
+#[test]
+fn detect_multi_bit_length_damage() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..5 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    std::mem::forget(store); // crash without a clean shutdown
+
+    // two bits of the second record's value length, so it runs far past the end of the segment
+    let log_path = temp_dir.path().join("1.log");
+    let mut log = fs::read(&log_path)?;
+    let log_len = log.len();
+    let record_len = log_len / 5;
+    log[record_len + 11] ^= 0xc0;
+    log[record_len + 10] ^= 0x01;
+    fs::write(&log_path, log)?;
+
+    let strict = KvStoreOptions {
+        strict: true,
+        ..KvStoreOptions::default()
+    };
+    assert!(matches!(
+        KvStore::open_with_options(temp_dir.path(), strict),
+        Err(KvsError::CorruptLog(_))
+    ));
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::CorruptLog(_))
+    ));
+    assert_eq!(fs::metadata(&log_path)?.len(), log_len as u64);
+
+    Ok(())
+}
+
+// Reads and writes should see the latest values while compactions run in the background
+#[test]
+fn read_and_write_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    for iter in 0..200 {
+        for key_id in 0..100 {
+            let key = format!("key{}", key_id);
+            store.set(key.clone(), format!("{}-{}", key_id, iter))?;
+            assert_eq!(store.get(key)?, Some(format!("{}-{}", key_id, iter)));
+        }
+
+        let removed = format!("key{}", iter % 100);
+        store.remove(removed.clone())?;
+        assert_eq!(store.get(removed.clone())?, None);
+        store.set(removed, format!("{}-{}", iter % 100, iter))?;
+    }
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("{}-199", key_id))
+        );
+    }
+
+    Ok(())
+}
+
+// A clean shutdown should leave a hint file the next open starts from
+#[test]
+fn hint_written_on_clean_shutdown() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    drop(store);
+
+    assert!(temp_dir.path().join("index.hint").is_file());
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Commands written after the hint should be replayed on top of it
+#[test]
+fn replay_log_written_after_hint() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.remove("key1".to_owned())?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    store.set("key3".to_owned(), "value4".to_owned())?;
+    std::mem::forget(store); // crash without a clean shutdown
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+// A damaged hint file should be ignored in favour of replaying the whole log
+#[test]
+fn damaged_hint_falls_back_to_full_replay() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let hint_path = temp_dir.path().join("index.hint");
+    let mut hint = fs::read(&hint_path)?;
+    let middle = hint.len() / 2;
+    hint[middle] ^= 0x01;
+    fs::write(&hint_path, hint)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Every durability policy should keep data across reopening, for both engines
+#[test]
+fn durability_policies() -> Result<()> {
+    let policies = [
+        Durability::Never,
+        Durability::EveryWrite,
+        Durability::EveryN(10),
+        Durability::GroupCommit,
+    ];
+
+    for &durability in &policies {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions {
+            durability,
+            ..KvStoreOptions::default()
+        };
+
+        let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        }
+        store.remove("key0".to_owned())?;
+        drop(store);
+
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        assert_eq!(store.get("key0".to_owned())?, None);
+        assert_eq!(store.get("key99".to_owned())?, Some("value99".to_owned()));
+
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let sled = SledKvsEngine::open_with_durability(temp_dir.path(), durability)?;
+        sled.set("key1".to_owned(), "value1".to_owned())?;
+        drop(sled);
+
+        let sled = SledKvsEngine::open_with_durability(temp_dir.path(), durability)?;
+        assert_eq!(sled.get("key1".to_owned())?, Some("value1".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Keys and values that aren't valid UTF-8 should round trip through both engines
+#[test]
+fn binary_keys_and_values() -> Result<()> {
+    let key: &[u8] = &[0xff, 0x00, 0xfe, b'\n'];
+    let value: &[u8] = &[0x00, 0x9f, 0x92, 0x96, 0xc3, 0x28, b'\n', b'{'];
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_bytes(key, value)?;
+    assert_eq!(store.get_bytes(key)?, Some(value.to_vec()));
+    assert!(store
+        .get(String::from_utf8_lossy(key).into_owned())?
+        .is_none());
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes(key)?, Some(value.to_vec()));
+    store.set_bytes(b"text", value)?;
+    assert!(store.get("text".to_owned()).is_err());
+    store.remove_bytes(key)?;
+    assert_eq!(store.get_bytes(key)?, None);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let sled = SledKvsEngine::open(temp_dir.path())?;
+    sled.set_bytes(key, value)?;
+    drop(sled);
+
+    let sled = SledKvsEngine::open(temp_dir.path())?;
+    assert_eq!(sled.get_bytes(key)?, Some(value.to_vec()));
+    sled.set_bytes(b"text", value)?;
+    assert!(sled.get("text".to_owned()).is_err());
+    sled.remove_bytes(key)?;
+    assert_eq!(sled.get_bytes(key)?, None);
+
+    Ok(())
+}
+
+fn check_scans(engine: &impl KvsEngine) -> Result<()> {
+    for key in &[
+        "user:42:name",
+        "user:41:name",
+        "user:42:age",
+        "user:43:age",
+        "order:1",
+    ] {
+        engine.set(key.to_string(), format!("{}-value", key))?;
+    }
+    engine.set_bytes(&[0xff, 0xff], b"last")?;
+    engine.set_bytes(&[0xff, 0xff, 0x01], b"after last")?;
+    engine.remove("user:43:age".to_owned())?;
+
+    let keys = |pairs: Vec<(Vec<u8>, Vec<u8>)>| -> Vec<Vec<u8>> {
+        pairs.into_iter().map(|(key, _)| key).collect()
+    };
+
+    let all = engine
+        .scan((Bound::Unbounded, Bound::Unbounded))?
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        keys(all),
+        vec![
+            b"order:1".to_vec(),
+            b"user:41:name".to_vec(),
+            b"user:42:age".to_vec(),
+            b"user:42:name".to_vec(),
+            vec![0xff, 0xff],
+            vec![0xff, 0xff, 0x01],
+        ]
+    );
+
+    let user_42 = engine
+        .scan_prefix(b"user:42:")?
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        user_42,
+        vec![
+            (b"user:42:age".to_vec(), b"user:42:age-value".to_vec()),
+            (b"user:42:name".to_vec(), b"user:42:name-value".to_vec()),
+        ]
+    );
+
+    let range = engine
+        .scan((
+            Bound::Excluded(b"order:1".to_vec()),
+            Bound::Included(b"user:42:age".to_vec()),
+        ))?
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        keys(range),
+        vec![b"user:41:name".to_vec(), b"user:42:age".to_vec()]
+    );
+
+    let high = engine
+        .scan_prefix(&[0xff, 0xff])?
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(keys(high), vec![vec![0xff, 0xff], vec![0xff, 0xff, 0x01]]);
+
+    assert_eq!(engine.scan_prefix(b"missing")?.count(), 0);
+
+    // scanning keys alone finds the same keys
+    let key_range = engine
+        .scan_keys((
+            Bound::Excluded(b"order:1".to_vec()),
+            Bound::Included(b"user:42:age".to_vec()),
+        ))?
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        key_range,
+        vec![b"user:41:name".to_vec(), b"user:42:age".to_vec()]
+    );
+    engine.set_bytes_with_ttl(b"user:44:name", b"brief", Duration::from_millis(1))?;
+    thread::sleep(Duration::from_millis(10));
+    assert_eq!(
+        engine
+            .scan_keys(prefix_range(b"user:4"))?
+            .collect::<Result<Vec<_>>>()?,
+        vec![
+            b"user:41:name".to_vec(),
+            b"user:42:age".to_vec(),
+            b"user:42:name".to_vec(),
+        ]
+    );
+
+    Ok(())
+}
+
+// Scans should return KV pairs in key order for both engines
+#[test]
+fn scan_in_key_order() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_scans(&KvStore::open(temp_dir.path())?)?;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_scans(&SledKvsEngine::open(temp_dir.path())?)?;
+
+    Ok(())
+}
+
+// The ordered index should survive reopening and compaction
+#[test]
+fn scan_after_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for iter in 0..20 {
+        for key_id in 0..100 {
+            store.set(format!("key{:03}", key_id), format!("{}", iter))?;
+        }
+    }
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    let pairs = store.scan_prefix(b"key")?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(pairs.len(), 100);
+    for (key_id, (key, value)) in pairs.into_iter().enumerate() {
+        assert_eq!(key, format!("key{:03}", key_id).into_bytes());
+        assert_eq!(value, b"19".to_vec());
+    }
+
+    Ok(())
+}
+
+fn check_expiry(engine: &impl KvsEngine) -> Result<()> {
+    engine.set_bytes_with_ttl(b"session", b"short", Duration::from_millis(200))?;
+    engine.set_bytes_with_ttl(b"counter", b"long", Duration::from_secs(3600))?;
+    engine.set_bytes(b"plain", b"kept")?;
+
+    assert_eq!(engine.get_bytes(b"session")?, Some(b"short".to_vec()));
+    match engine.ttl(b"counter")? {
+        KeyTtl::ExpiresIn(ttl) => assert!(ttl > Duration::from_secs(3590)),
+        ttl => panic!("unexpected ttl {:?}", ttl),
+    }
+    assert_eq!(engine.ttl(b"plain")?, KeyTtl::NoExpiry);
+    assert_eq!(engine.ttl(b"missing")?, KeyTtl::Missing);
+
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(engine.get_bytes(b"session")?, None);
+    assert_eq!(engine.ttl(b"session")?, KeyTtl::Missing);
+    assert!(engine.remove_bytes(b"session").is_err());
+    let keys: Vec<Vec<u8>> = engine
+        .scan((Bound::Unbounded, Bound::Unbounded))?
+        .map(|pair| pair.map(|(key, _)| key))
+        .collect::<Result<_>>()?;
+    assert_eq!(keys, vec![b"counter".to_vec(), b"plain".to_vec()]);
+
+    assert!(engine.persist(b"counter")?);
+    assert_eq!(engine.ttl(b"counter")?, KeyTtl::NoExpiry);
+    assert_eq!(engine.get_bytes(b"counter")?, Some(b"long".to_vec()));
+    assert!(!engine.persist(b"plain")?);
+    assert!(!engine.persist(b"session")?);
+
+    // a plain set drops the expiry
+    engine.set_bytes_with_ttl(b"session", b"short", Duration::from_millis(200))?;
+    engine.set_bytes(b"session", b"renewed")?;
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(engine.get_bytes(b"session")?, Some(b"renewed".to_vec()));
+
+    Ok(())
+}
+
+// Keys set with a time to live should read as absent once it's up
+#[test]
+fn keys_expire() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_expiry(&KvStore::open(temp_dir.path())?)?;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_expiry(&SledKvsEngine::open(temp_dir.path())?)?;
+
+    Ok(())
+}
+
+// Expiry times should be read back from the log, with or without a hint file
+#[test]
+fn expiry_survives_reopen() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_bytes_with_ttl(b"short", b"value", Duration::from_millis(200))?;
+    store.set_bytes_with_ttl(b"long", b"value", Duration::from_secs(3600))?;
+    drop(store);
+
+    for _ in 0..2 {
+        let store = KvStore::open(temp_dir.path())?;
+        assert!(matches!(store.ttl(b"long")?, KeyTtl::ExpiresIn(_)));
+        drop(store);
+        fs::remove_file(temp_dir.path().join("index.hint"))?;
+    }
+
+    thread::sleep(Duration::from_millis(300));
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes(b"short")?, None);
+    assert_eq!(store.get_bytes(b"long")?, Some(b"value".to_vec()));
+    drop(store);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let sled = SledKvsEngine::open(temp_dir.path())?;
+    sled.set_bytes_with_ttl(b"long", b"value", Duration::from_secs(3600))?;
+    drop(sled);
+
+    let sled = SledKvsEngine::open(temp_dir.path())?;
+    assert!(matches!(sled.ttl(b"long")?, KeyTtl::ExpiresIn(_)));
+
+    Ok(())
+}
+
+// Compaction should leave expired keys behind
+#[test]
+fn compaction_drops_expired_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let value = vec![b'v'; 1024];
+    for key_id in 0..500 {
+        store.set_bytes_with_ttl(
+            format!("key{}", key_id).as_bytes(),
+            &value,
+            Duration::from_millis(100),
+        )?;
+    }
+    thread::sleep(Duration::from_millis(200));
+
+    // overwriting one key over and over makes compaction kick in
+    for iter in 0..1000 {
+        store.set(String::from("counter"), format!("{}", iter))?;
+    }
+    drop(store);
+
+    let log_size: u64 = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some("log".as_ref()))
+        .map(|path| fs::metadata(path).unwrap().len())
+        .sum();
+    assert!(log_size < 100 * 1024, "log still {} bytes", log_size);
+
+    fs::remove_file(temp_dir.path().join("index.hint"))?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes(b"key0")?, None);
+    assert_eq!(store.get("counter".to_owned())?, Some("999".to_owned()));
+    assert_eq!(store.scan_prefix(b"key")?.count(), 0);
+
+    Ok(())
+}
+
+// Keys written again while compaction drops them as expired shouldn't keep a version in the
+// segments it deletes
+#[test]
+fn write_expired_keys_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        retention: Retention::Window(Duration::from_secs(1)),
+        compaction_threshold: 0.0,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+
+    // live values give compaction something to copy while the expired keys are written again
+    let value = vec![b'v'; 4096];
+    for key_id in 0..2000 {
+        store.set_bytes(format!("live{}", key_id).as_bytes(), &value)?;
+    }
+    for key_id in 0..500 {
+        store.set_bytes_with_ttl(
+            format!("key{}", key_id).as_bytes(),
+            &value,
+            Duration::from_millis(1),
+        )?;
+    }
+    // an overwrite that falls out of the window is what makes compaction kick in
+    store.set(String::from("counter"), String::from("0"))?;
+    store.set(String::from("counter"), String::from("1"))?;
+    thread::sleep(Duration::from_millis(1200));
+    store.set(String::from("counter"), String::from("2"))?;
+
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || -> Result<()> {
+            for key_id in 0..500 {
+                store.set(format!("key{}", key_id), "again".to_owned())?;
+            }
+            Ok(())
+        })
+    };
+    writer.join().unwrap()?;
+    // let compaction delete the old segments
+    thread::sleep(Duration::from_millis(200));
+
+    let check = |store: &KvStore| -> Result<()> {
+        for key_id in 0..500 {
+            let key = format!("key{}", key_id);
+            store.history(key.as_bytes())?;
+            assert_eq!(store.get(key)?, Some("again".to_owned()));
+        }
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    check(&KvStore::open_with_options(temp_dir.path(), options)?)?;
+
+    Ok(())
+}
+
+fn check_batches(engine: &impl KvsEngine) -> Result<()> {
+    engine.set("user:1".to_owned(), "old".to_owned())?;
+    engine.set("email:old@example.com".to_owned(), "user:1".to_owned())?;
+    engine.set_bytes_with_ttl(b"session:1", b"old", Duration::from_millis(200))?;
+
+    let mut batch = WriteBatch::new();
+    batch
+        .set("user:1", "new")
+        .remove("email:old@example.com")
+        .set("email:new@example.com", "user:1")
+        .remove("missing")
+        .set("temp", "value")
+        .remove("temp")
+        .set("session:1", "kept")
+        .set_with_ttl("session:2", "short", Duration::from_millis(200));
+    assert_eq!(batch.len(), 8);
+    engine.apply_batch(batch)?;
+    engine.apply_batch(WriteBatch::new())?;
+
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(engine.get("user:1".to_owned())?, Some("new".to_owned()));
+    assert_eq!(engine.get("email:old@example.com".to_owned())?, None);
+    assert_eq!(
+        engine.get("email:new@example.com".to_owned())?,
+        Some("user:1".to_owned())
+    );
+    assert_eq!(engine.get("temp".to_owned())?, None);
+    assert_eq!(engine.get("missing".to_owned())?, None);
+    assert_eq!(engine.get("session:1".to_owned())?, Some("kept".to_owned()));
+    assert_eq!(engine.ttl(b"session:1")?, KeyTtl::NoExpiry);
+    assert_eq!(engine.get("session:2".to_owned())?, None);
+
+    Ok(())
+}
+
+// All the writes in a batch should be applied
+#[test]
+fn apply_write_batches() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_batches(&KvStore::open(temp_dir.path())?)?;
+    fs::remove_file(temp_dir.path().join("index.hint"))?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("user:1".to_owned())?, Some("new".to_owned()));
+    assert_eq!(store.get("email:old@example.com".to_owned())?, None);
+    drop(store);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_batches(&SledKvsEngine::open(temp_dir.path())?)?;
+    let sled = SledKvsEngine::open(temp_dir.path())?;
+    assert_eq!(sled.get("user:1".to_owned())?, Some("new".to_owned()));
+
+    Ok(())
+}
+
+// A batch cut short by a crash should be dropped as a whole
+#[test]
+fn torn_batch_is_discarded() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_path = temp_dir.path().join("1.log");
+
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 1..5 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    drop(store);
+    let good_len = fs::metadata(&log_path)?.len();
+
+    let store = KvStore::open(temp_dir.path())?;
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key5", "value5")
+        .set("key6", "value6")
+        .remove("key1");
+    store.apply_batch(batch)?;
+    drop(store);
+    let full_log = fs::read(&log_path)?;
+
+    for crash_at in (good_len + 1..full_log.len() as u64).step_by(7) {
+        let crash_dir = TempDir::new().expect("unable to create temporary working directory");
+        fs::write(
+            crash_dir.path().join("1.log"),
+            &full_log[..crash_at as usize],
+        )?;
+
+        let store = KvStore::open(crash_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key5".to_owned())?, None);
+        assert_eq!(store.get("key6".to_owned())?, None);
+    }
+
+    fs::remove_file(temp_dir.path().join("index.hint"))?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key5".to_owned())?, Some("value5".to_owned()));
+    assert_eq!(store.get("key6".to_owned())?, Some("value6".to_owned()));
+
+    Ok(())
+}
+
+fn check_conditional_sets(engine: &impl KvsEngine) -> Result<()> {
+    assert_eq!(
+        engine.set_if_present(b"key1", b"value1")?,
+        SetOutcome::Conflict(None)
+    );
+    assert_eq!(
+        engine.set_if_absent(b"key1", b"value1")?,
+        SetOutcome::Written
+    );
+    assert_eq!(
+        engine.set_if_absent(b"key1", b"value2")?,
+        SetOutcome::Conflict(Some(b"value1".to_vec()))
+    );
+    assert_eq!(
+        engine.set_if_present(b"key1", b"value2")?,
+        SetOutcome::Written
+    );
+
+    assert_eq!(
+        engine.compare_and_swap(b"key1", b"value1", b"value3")?,
+        SetOutcome::Conflict(Some(b"value2".to_vec()))
+    );
+    assert_eq!(
+        engine.compare_and_swap(b"key1", b"value2", b"value3")?,
+        SetOutcome::Written
+    );
+    assert_eq!(engine.get_bytes(b"key1")?, Some(b"value3".to_vec()));
+    assert_eq!(
+        engine.compare_and_swap(b"missing", b"value", b"value")?,
+        SetOutcome::Conflict(None)
+    );
+
+    // an expired key is as good as absent
+    engine.set_bytes_with_ttl(b"lock", b"holder1", Duration::from_millis(100))?;
+    assert_eq!(
+        engine.set_if_absent(b"lock", b"holder2")?,
+        SetOutcome::Conflict(Some(b"holder1".to_vec()))
+    );
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(
+        engine.set_if_absent(b"lock", b"holder2")?,
+        SetOutcome::Written
+    );
+    assert_eq!(engine.ttl(b"lock")?, KeyTtl::NoExpiry);
+
+    Ok(())
+}
+
+// Conditional sets should only write when their condition holds
+#[test]
+fn conditional_sets() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_conditional_sets(&KvStore::open(temp_dir.path())?)?;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_conditional_sets(&SledKvsEngine::open(temp_dir.path())?)?;
+
+    Ok(())
+}
+
+fn check_snapshots(engine: &impl KvsEngine) -> Result<()> {
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    engine.set_bytes_with_ttl(b"key3", b"value3", Duration::from_secs(3600))?;
+
+    let mut snapshot = engine.snapshot()?;
+
+    engine.set("key1".to_owned(), "changed".to_owned())?;
+    engine.remove("key2".to_owned())?;
+    engine.remove("key3".to_owned())?;
+    engine.set("key4".to_owned(), "value4".to_owned())?;
+
+    assert_eq!(snapshot.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(snapshot.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(snapshot.get_bytes(b"key3")?, Some(b"value3".to_vec()));
+    assert_eq!(snapshot.get("key4".to_owned())?, None);
+    let pairs = snapshot.scan_prefix(b"key")?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        pairs,
+        vec![
+            (b"key1".to_vec(), b"value1".to_vec()),
+            (b"key2".to_vec(), b"value2".to_vec()),
+            (b"key3".to_vec(), b"value3".to_vec()),
+        ]
+    );
+
+    // snapshots can be read from another thread
+    let reader = thread::spawn(move || snapshot.get("key1".to_owned()));
+    assert_eq!(reader.join().unwrap()?, Some("value1".to_owned()));
+
+    assert_eq!(engine.get("key1".to_owned())?, Some("changed".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// Snapshots shouldn't see writes made after they were taken
+#[test]
+fn snapshot_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_snapshots(&KvStore::open(temp_dir.path())?)?;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_snapshots(&SledKvsEngine::open(temp_dir.path())?)?;
+
+    Ok(())
+}
+
+// Compaction should carry on while a snapshot is open, leaving the segments it reads from in place
+// until it's dropped
+#[test]
+fn snapshot_pins_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "0".to_owned())?;
+    }
+
+    let mut snapshot = store.snapshot()?;
+    for iter in 1..20 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    // dropping the store waits for the compaction that kicked in
+    drop(store);
+    assert!(temp_dir.path().join("1.log").exists());
+
+    let pairs = snapshot.scan_prefix(b"key")?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(pairs.len(), 100);
+    assert!(pairs.iter().all(|(_, value)| value == b"0"));
+    drop(snapshot);
+    assert!(!temp_dir.path().join("1.log").exists());
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key42".to_owned())?, Some("19".to_owned()));
+
+    Ok(())
+}
+
+// Sled writes to different keys made at once should each get a sequence number of their own, and
+// numbers should keep going up once the db is reopened
+#[test]
+fn sled_sequence_numbers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    let writers = (0..4)
+        .map(|thread_id| {
+            let engine = engine.clone();
+            thread::spawn(move || -> Result<()> {
+                for key_id in 0..50 {
+                    engine.set(format!("key{}-{}", thread_id, key_id), "value".to_owned())?;
+                }
+                Ok(())
+            })
+        })
+        .collect::<Vec<_>>();
+    for writer in writers {
+        writer.join().unwrap()?;
+    }
+
+    let mut seqs = Vec::new();
+    for thread_id in 0..4 {
+        for key_id in 0..50 {
+            seqs.extend(
+                engine
+                    .history(format!("key{}-{}", thread_id, key_id).as_bytes())?
+                    .iter()
+                    .map(|version| version.seq),
+            );
+        }
+    }
+    seqs.sort_unstable();
+    seqs.dedup();
+    assert_eq!(seqs.len(), 200);
+    let last_seq = engine.current_seq()?;
+    assert_eq!(seqs.last(), Some(&last_seq));
+    drop(engine);
+
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    assert!(engine.current_seq()? >= last_seq);
+    engine.set("key".to_owned(), "value".to_owned())?;
+    assert!(engine.history(b"key")?[0].seq > last_seq);
+
+    Ok(())
+}
+
+// A sled snapshot should keep reading the values it was taken over however often they're
+// replaced, even when only the latest version is kept
+#[test]
+fn sled_snapshot_keeps_its_versions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        engine.set(format!("key{}", key_id), "0".to_owned())?;
+    }
+
+    let mut snapshot = engine.snapshot()?;
+    for iter in 1..5 {
+        for key_id in 0..10 {
+            engine.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    engine.remove("key3".to_owned())?;
+    engine.set("key10".to_owned(), "new".to_owned())?;
+
+    let pairs = snapshot.scan_prefix(b"key")?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(pairs.len(), 10);
+    assert!(pairs.iter().all(|(_, value)| value == b"0"));
+    assert_eq!(snapshot.get("key3".to_owned())?, Some("0".to_owned()));
+    drop(snapshot);
+
+    // once the snapshot is gone the next write lets go of the versions it held on to
+    engine.set("key1".to_owned(), "5".to_owned())?;
+    assert_eq!(engine.history(b"key1")?.len(), 1);
+
+    Ok(())
+}
+
+fn check_history(engine: &impl KvsEngine) -> Result<()> {
+    let first_seq = engine.current_seq()?;
+    for value in ["v1", "v2", "v3", "v4"] {
+        engine.set("key1".to_owned(), value.to_owned())?;
+        thread::sleep(Duration::from_millis(5));
+    }
+    assert_eq!(engine.current_seq()?, first_seq + 4);
+
+    // only the last three versions are kept
+    let history = engine.history(b"key1")?;
+    let values = history
+        .iter()
+        .map(|version| version.value.as_deref())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        values,
+        vec![Some(&b"v2"[..]), Some(&b"v3"[..]), Some(&b"v4"[..])]
+    );
+    assert!(history.windows(2).all(|w| w[0].seq < w[1].seq));
+    assert_eq!(history[2].seq, first_seq + 4);
+
+    assert_eq!(
+        engine.get_at(b"key1", ReadAt::Seq(history[0].seq))?,
+        Some(b"v2".to_vec())
+    );
+    assert_eq!(
+        engine.get_at(b"key1", ReadAt::Seq(history[1].seq))?,
+        Some(b"v3".to_vec())
+    );
+    assert_eq!(engine.get_at(b"key1", ReadAt::Seq(first_seq + 1))?, None);
+    assert_eq!(
+        engine.get_at(b"key1", ReadAt::Time(history[1].written_at))?,
+        Some(b"v3".to_vec())
+    );
+
+    // removals are versions without a value
+    engine.remove("key1".to_owned())?;
+    let history = engine.history(b"key1")?;
+    assert_eq!(history.len(), 3);
+    assert_eq!(history[2].value, None);
+    assert_eq!(engine.get_at(b"key1", ReadAt::Seq(first_seq + 5))?, None);
+    assert_eq!(
+        engine.get_at(b"key1", ReadAt::Seq(first_seq + 4))?,
+        Some(b"v4".to_vec())
+    );
+
+    // every write in a batch gets a sequence number of its own
+    let mut batch = WriteBatch::new();
+    batch.set(b"key2".to_vec(), b"a".to_vec());
+    batch.set(b"key2".to_vec(), b"b".to_vec());
+    engine.apply_batch(batch)?;
+    assert_eq!(engine.current_seq()?, first_seq + 7);
+    assert_eq!(
+        engine.get_at(b"key2", ReadAt::Seq(first_seq + 6))?,
+        Some(b"a".to_vec())
+    );
+
+    // a value read as of a time after it expired is gone
+    engine.set_bytes_with_ttl(b"key3", b"short", Duration::from_millis(50))?;
+    let written_at = engine.history(b"key3")?[0].written_at;
+    assert_eq!(
+        engine.get_at(b"key3", ReadAt::Time(written_at))?,
+        Some(b"short".to_vec())
+    );
+    assert_eq!(
+        engine.get_at(b"key3", ReadAt::Time(written_at + Duration::from_secs(1)))?,
+        None
+    );
+
+    Ok(())
+}
+
+// Old versions of a key should be kept as the retention policy says
+#[test]
+fn version_history() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        retention: Retention::Versions(3),
+        ..KvStoreOptions::default()
+    };
+    check_history(&KvStore::open_with_options(temp_dir.path(), options)?)?;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = SledOptions {
+        retention: Retention::Versions(3),
+        ..SledOptions::default()
+    };
+    check_history(&SledKvsEngine::open_with_options(temp_dir.path(), options)?)?;
+
+    Ok(())
+}
+
+fn check_history_window(engine: &impl KvsEngine) -> Result<()> {
+    engine.set("key1".to_owned(), "v1".to_owned())?;
+    engine.set("key1".to_owned(), "v2".to_owned())?;
+    thread::sleep(Duration::from_millis(300));
+    engine.set("key1".to_owned(), "v3".to_owned())?;
+
+    // v2 was still current within the window, v1 wasn't
+    let values = engine
+        .history(b"key1")?
+        .into_iter()
+        .map(|version| version.value)
+        .collect::<Vec<_>>();
+    assert_eq!(values, vec![Some(b"v2".to_vec()), Some(b"v3".to_vec())]);
+
+    Ok(())
+}
+
+// Versions should be kept for as long as they were current within the window
+#[test]
+fn history_window() -> Result<()> {
+    let retention = Retention::Window(Duration::from_millis(200));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        retention,
+        ..KvStoreOptions::default()
+    };
+    check_history_window(&KvStore::open_with_options(temp_dir.path(), options)?)?;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = SledOptions {
+        retention,
+        ..SledOptions::default()
+    };
+    check_history_window(&SledKvsEngine::open_with_options(temp_dir.path(), options)?)?;
+
+    Ok(())
+}
+
+// Only the current value should be kept by default
+#[test]
+fn latest_retention_keeps_one_version() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "v1".to_owned())?;
+    store.set("key1".to_owned(), "v2".to_owned())?;
+
+    let history = store.history(b"key1")?;
+    assert_eq!(history.len(), 1);
+    assert_eq!(history[0].value, Some(b"v2".to_vec()));
+    assert_eq!(store.get_at(b"key1", ReadAt::Seq(1))?, None);
+
+    store.remove("key1".to_owned())?;
+    assert!(store.history(b"key1")?.is_empty());
+
+    Ok(())
+}
+
+// Kept versions and sequence numbers should survive compaction and reopening, with or without a
+// hint file
+#[test]
+fn history_survives_compaction_and_reopen() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        retention: Retention::Versions(2),
+        ..KvStoreOptions::default()
+    };
+
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for iter in 0..1000 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    store.remove("key0".to_owned())?;
+    let last_seq = store.current_seq()?;
+    drop(store);
+    assert!(!temp_dir.path().join("1.log").exists());
+
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.current_seq()?, last_seq);
+
+        let values = store
+            .history(b"key42")?
+            .into_iter()
+            .map(|version| version.value)
+            .collect::<Vec<_>>();
+        assert_eq!(values, vec![Some(b"998".to_vec()), Some(b"999".to_vec())]);
+
+        let history = store.history(b"key0")?;
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].value, Some(b"999".to_vec()));
+        assert_eq!(history[1].value, None);
+        assert_eq!(
+            store.get_at(b"key0", ReadAt::Seq(last_seq - 1))?,
+            Some(b"999".to_vec())
+        );
+        Ok(())
+    };
+
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    check(&store)?;
+    drop(store);
+
+    fs::remove_file(temp_dir.path().join("index.hint"))?;
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    check(&store)?;
+
+    Ok(())
+}
+
+fn check_transactions(engine: &impl KvsEngine) -> Result<()> {
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+
+    // reads see the transaction's own writes, nothing is written before commit
+    let mut transaction = engine.begin();
+    assert_eq!(transaction.get(engine, b"key1")?, Some(b"value1".to_vec()));
+    transaction.set(b"key2".to_vec(), b"changed".to_vec());
+    transaction.remove(b"key1".to_vec());
+    assert_eq!(transaction.get(engine, b"key2")?, Some(b"changed".to_vec()));
+    assert_eq!(transaction.get(engine, b"key1")?, None);
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+    engine.commit(transaction)?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert_eq!(engine.get("key2".to_owned())?, Some("changed".to_owned()));
+
+    let is_conflict_on = |result: Result<()>, key: &[u8]| matches!(result, Err(KvsError::TransactionConflict { key: conflict }) if conflict == key);
+
+    // a key read being written since fails the commit, even if it's written with the same value
+    let mut transaction = engine.begin();
+    transaction.get(engine, b"key2")?;
+    transaction.set(b"key3".to_vec(), b"value3".to_vec());
+    engine.set("key2".to_owned(), "changed".to_owned())?;
+    assert!(is_conflict_on(engine.commit(transaction), b"key2"));
+    assert_eq!(engine.get("key3".to_owned())?, None);
+
+    // so does a key read as absent being set since
+    let mut transaction = engine.begin();
+    assert_eq!(transaction.get(engine, b"key1")?, None);
+    transaction.set(b"key1".to_vec(), b"first".to_vec());
+    engine.set("key1".to_owned(), "second".to_owned())?;
+    assert!(is_conflict_on(engine.commit(transaction), b"key1"));
+    assert_eq!(engine.get("key1".to_owned())?, Some("second".to_owned()));
+
+    // writes to keys that weren't read don't get in the way
+    let mut transaction = engine.begin();
+    transaction.get(engine, b"key1")?;
+    transaction.set(b"key3".to_vec(), b"value3".to_vec());
+    engine.set("key2".to_owned(), "again".to_owned())?;
+    engine.commit(transaction)?;
+    assert_eq!(engine.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    // an expected sequence number is checked on commit like one that was read
+    let seq = engine.get_with_seq(b"key3")?.map(|(_, seq)| seq);
+    let mut transaction = engine.begin();
+    transaction.expect_seq(b"key3".to_vec(), seq);
+    transaction.set(b"key3".to_vec(), b"swapped".to_vec());
+    engine.commit(transaction)?;
+    let mut transaction = engine.begin();
+    transaction.expect_seq(b"key3".to_vec(), seq);
+    transaction.set(b"key3".to_vec(), b"stale".to_vec());
+    assert!(is_conflict_on(engine.commit(transaction), b"key3"));
+    assert_eq!(engine.get("key3".to_owned())?, Some("swapped".to_owned()));
+
+    Ok(())
+}
+
+fn check_key_count(engine: &impl KvsEngine) -> Result<()> {
+    assert_eq!(engine.key_count()?, 0);
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key1".to_owned(), "value2".to_owned())?;
+    engine.set("key2".to_owned(), "value".to_owned())?;
+    engine.remove("key2".to_owned())?;
+    assert_eq!(engine.key_count()?, 1);
+
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key2", "value")
+        .set("key3", "value")
+        .remove("key1");
+    engine.apply_batch(batch)?;
+    engine.set_if_absent(b"key4", b"value")?;
+    let mut transaction = engine.begin();
+    transaction.set(b"key5".to_vec(), b"value".to_vec());
+    transaction.remove(b"key2".to_vec());
+    engine.commit(transaction)?;
+    assert_eq!(engine.key_count()?, 3);
+
+    Ok(())
+}
+
+// Engines should keep count of their keys, across a reopen too
+#[test]
+fn key_count() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_key_count(&KvStore::open(temp_dir.path())?)?;
+    assert_eq!(KvStore::open(temp_dir.path())?.key_count()?, 3);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_key_count(&SledKvsEngine::open(temp_dir.path())?)?;
+    assert_eq!(SledKvsEngine::open(temp_dir.path())?.key_count()?, 3);
+
+    Ok(())
+}
+
+// Transactions should commit all their writes, or none of them on a conflict
+#[test]
+fn optimistic_transactions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_transactions(&KvStore::open(temp_dir.path())?)?;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_transactions(&SledKvsEngine::open(temp_dir.path())?)?;
+
+    Ok(())
+}
+
+fn check_concurrent_access<E: KvsEngine>(engine: E) -> Result<E> {
+    let writers = (0..4)
+        .map(|thread| {
+            let engine = engine.clone();
+            thread::spawn(move || -> Result<()> {
+                for round in 0..50 {
+                    for key in 0..20 {
+                        engine.set(format!("key{}-{}", thread, key), format!("{}", round))?;
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect::<Vec<_>>();
+
+    // increments racing each other only ever lose by conflicting, never silently
+    let counters = (0..4)
+        .map(|_| {
+            let engine = engine.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..25 {
+                    loop {
+                        let mut transaction = engine.begin();
+                        let count = transaction
+                            .get(&engine, b"counter")?
+                            .map(|count| String::from_utf8(count).unwrap().parse::<u32>().unwrap())
+                            .unwrap_or(0);
+                        transaction.set(b"counter".to_vec(), (count + 1).to_string().into_bytes());
+                        match engine.commit(transaction) {
+                            Ok(()) => break,
+                            Err(KvsError::TransactionConflict { .. }) => {}
+                            Err(e) => return Err(e),
+                        }
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for handle in writers.into_iter().chain(counters) {
+        handle.join().expect("thread panicked")?;
+    }
+
+    for thread in 0..4 {
+        for key in 0..20 {
+            assert_eq!(
+                engine.get(format!("key{}-{}", thread, key))?,
+                Some("49".to_owned())
+            );
+        }
+    }
+    assert_eq!(engine.get("counter".to_owned())?, Some("100".to_owned()));
+
+    Ok(engine)
+}
+
+// Clones of an engine should be usable from many threads at once
+#[test]
+fn concurrent_access() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = check_concurrent_access(KvStore::open(temp_dir.path())?)?;
+
+    // every clone has to go before the store is reopened
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3-19".to_owned())?, Some("49".to_owned()));
+    assert_eq!(store.get("counter".to_owned())?, Some("100".to_owned()));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_concurrent_access(SledKvsEngine::open(temp_dir.path())?)?;
+
+    Ok(())
+}
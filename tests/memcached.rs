@@ -0,0 +1,161 @@
+use kvs::{BackgroundServer, EngineType, KeyTtl, KvStore, KvsEngine, Protocol, Result};
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// The server should answer memcached clients when speaking memcached's protocol
+#[test]
+fn server_speaks_memcached() {
+    let temp_dir = TempDir::new().unwrap();
+    let server = BackgroundServer::start_with_protocol(
+        EngineType::Kvs,
+        temp_dir.path(),
+        Protocol::Memcached,
+    )
+    .unwrap();
+    let addr = server.local_addr();
+
+    let run = || -> Result<()> {
+        let stream = TcpStream::connect(addr)?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = stream;
+        // sends a request and reads back the given number of reply lines
+        let mut exchange = |request: &str, lines: usize| -> Result<String> {
+            writer.write_all(request.as_bytes())?;
+            let mut reply = String::new();
+            for _ in 0..lines {
+                reader.read_line(&mut reply)?;
+            }
+            Ok(reply)
+        };
+
+        assert_eq!(exchange("get key1\r\n", 1)?, "END\r\n");
+        assert_eq!(exchange("set key1 5 0 6\r\nvalue1\r\n", 1)?, "STORED\r\n");
+        assert_eq!(
+            exchange("get key1 missing\r\n", 3)?,
+            "VALUE key1 5 6\r\nvalue1\r\nEND\r\n"
+        );
+        assert_eq!(exchange("add key1 0 0 1\r\nx\r\n", 1)?, "NOT_STORED\r\n");
+        assert_eq!(
+            exchange("replace key2 0 0 1\r\nx\r\n", 1)?,
+            "NOT_STORED\r\n"
+        );
+        assert_eq!(exchange("add key2 7 0 2\r\n10\r\n", 1)?, "STORED\r\n");
+        assert_eq!(exchange("replace key2 7 0 2\r\n40\r\n", 1)?, "STORED\r\n");
+
+        // cas only stores over the version gets handed out
+        let gets = exchange("gets key1\r\n", 3)?;
+        let unique = gets
+            .lines()
+            .next()
+            .and_then(|line| line.split(' ').nth(4))
+            .unwrap()
+            .to_owned();
+        assert_eq!(
+            exchange(&format!("cas key1 1 0 3 {}\r\nnew\r\n", unique), 1)?,
+            "STORED\r\n"
+        );
+        assert_eq!(
+            exchange(&format!("cas key1 1 0 3 {}\r\nold\r\n", unique), 1)?,
+            "EXISTS\r\n"
+        );
+        assert_eq!(
+            exchange("cas missing 0 0 1 1\r\nx\r\n", 1)?,
+            "NOT_FOUND\r\n"
+        );
+        assert_eq!(
+            exchange("get key1\r\n", 3)?,
+            "VALUE key1 1 3\r\nnew\r\nEND\r\n"
+        );
+
+        // counters keep their flags
+        assert_eq!(exchange("incr key2 2\r\n", 1)?, "42\r\n");
+        assert_eq!(exchange("decr key2 50\r\n", 1)?, "0\r\n");
+        assert_eq!(exchange("incr missing 1\r\n", 1)?, "NOT_FOUND\r\n");
+        assert_eq!(
+            exchange("incr key1 1\r\n", 1)?,
+            "CLIENT_ERROR cannot increment or decrement non-numeric value\r\n"
+        );
+        assert_eq!(
+            exchange("get key2\r\n", 3)?,
+            "VALUE key2 7 1\r\n0\r\nEND\r\n"
+        );
+
+        // an item whose exptime is already behind it reads as missing
+        assert_eq!(exchange("set gone 0 -1 1\r\nx\r\n", 1)?, "STORED\r\n");
+        assert_eq!(exchange("get gone\r\n", 1)?, "END\r\n");
+        assert_eq!(exchange("delete gone\r\n", 1)?, "NOT_FOUND\r\n");
+        assert_eq!(exchange("set later 0 100 1\r\nx\r\n", 1)?, "STORED\r\n");
+        assert_eq!(
+            exchange("get later\r\n", 3)?,
+            "VALUE later 0 1\r\nx\r\nEND\r\n"
+        );
+
+        assert_eq!(exchange("delete key1\r\n", 1)?, "DELETED\r\n");
+        assert_eq!(exchange("delete key1\r\n", 1)?, "NOT_FOUND\r\n");
+
+        // noreply commands are answered with nothing, so the version is the next line
+        assert_eq!(
+            exchange("set quiet 0 0 1 noreply\r\nq\r\nversion\r\n", 1)?,
+            format!("VERSION {}\r\n", env!("CARGO_PKG_VERSION"))
+        );
+        assert_eq!(
+            exchange("get quiet\r\n", 3)?,
+            "VALUE quiet 0 1\r\nq\r\nEND\r\n"
+        );
+
+        assert_eq!(exchange("bogus\r\n", 1)?, "ERROR\r\n");
+        assert_eq!(
+            exchange("set key1 x 0 1\r\n", 1)?,
+            "CLIENT_ERROR bad command line format\r\n"
+        );
+
+        // more keys than flush_all removes in one batch
+        let many = (0..2500)
+            .map(|i| format!("set many{} 0 0 1 noreply\r\nm\r\n", i))
+            .collect::<String>();
+        assert_eq!(
+            exchange(&(many + "get many2499\r\n"), 3)?,
+            "VALUE many2499 0 1\r\nm\r\nEND\r\n"
+        );
+        assert_eq!(exchange("flush_all\r\n", 1)?, "OK\r\n");
+        assert_eq!(
+            exchange("get key2 later quiet many0 many1234 many2499\r\n", 1)?,
+            "END\r\n"
+        );
+
+        // an exptime is kept as the key's ttl, and counters keep it
+        assert_eq!(exchange("set timed 0 100 1\r\n1\r\n", 1)?, "STORED\r\n");
+        assert_eq!(exchange("incr timed 1\r\n", 1)?, "2\r\n");
+
+        // data running past its length can't be recovered from, so the connection is closed
+        assert_eq!(
+            exchange("set key1 0 0 1\r\ntoo long\r\n", 1)?,
+            "CLIENT_ERROR bad data chunk\r\n"
+        );
+        assert_eq!(exchange("", 1)?, "");
+
+        // so is a length too long to be skipped past
+        let stream = TcpStream::connect(addr)?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = stream;
+        writer.write_all(b"set key1 0 0 18446744073709551615\r\n")?;
+        let mut reply = String::new();
+        reader.read_line(&mut reply)?;
+        assert_eq!(reply, "CLIENT_ERROR bad data chunk\r\n");
+        assert_eq!(reader.read_line(&mut reply)?, 0);
+
+        Ok(())
+    };
+    let result = run();
+    server.stop().unwrap();
+
+    result.unwrap();
+
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    match store.ttl(b"timed").unwrap() {
+        KeyTtl::ExpiresIn(ttl) => assert!(ttl <= Duration::from_secs(100)),
+        ttl => panic!("unexpected ttl {:?}", ttl),
+    }
+}
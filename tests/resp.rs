@@ -0,0 +1,215 @@
+use kvs::resp::Value;
+use kvs::{BackgroundServer, EngineType, Protocol, Result};
+use std::io::{BufReader, Cursor, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn ok() -> Value {
+    Value::SimpleString(String::from("OK"))
+}
+
+// Every kind of value should decode back to what was encoded
+#[test]
+fn values_round_trip() -> Result<()> {
+    let values = vec![
+        ok(),
+        Value::Error(String::from("ERR syntax error")),
+        Value::Integer(-42),
+        Value::bulk(&b"binary\r\n\xff"[..]),
+        Value::bulk(""),
+        Value::BulkString(None),
+        Value::Array(None),
+        Value::Array(Some(vec![
+            Value::Integer(1),
+            Value::Array(Some(vec![Value::BulkString(None)])),
+        ])),
+    ];
+
+    let mut bytes = Vec::new();
+    for value in &values {
+        value.write_to(&mut bytes)?;
+    }
+    let mut reader = Cursor::new(bytes);
+    for value in values {
+        assert_eq!(Value::read_from(&mut reader)?, Some(value));
+    }
+    assert_eq!(Value::read_from(&mut reader)?, None);
+
+    Ok(())
+}
+
+#[test]
+fn encodes_wire_form() {
+    assert_eq!(
+        Value::command(vec!["GET", "key"]).encode(),
+        b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n".to_vec()
+    );
+    assert_eq!(Value::BulkString(None).encode(), b"$-1\r\n".to_vec());
+}
+
+// Broken input should be refused rather than read as some other value
+#[test]
+fn rejects_malformed_values() {
+    for bytes in [
+        &b"?what\r\n"[..],
+        b"+no crlf\n",
+        b":twelve\r\n",
+        b"$5\r\nab\r\n",
+        b"$2\r\nabcd\r\n",
+        b"*2\r\n:1\r\n",
+    ] {
+        assert!(Value::read_from(&mut Cursor::new(bytes)).is_err());
+    }
+
+    // a declared length is only read as far as the bytes actually sent
+    assert!(Value::read_from(&mut Cursor::new(b"$536870912\r\nab\r\n")).is_err());
+
+    // arrays nested past any sane reply are refused instead of recursed into
+    let nested = b"*1\r\n".repeat(100_000);
+    assert!(Value::read_from(&mut Cursor::new(nested)).is_err());
+}
+
+// The server should answer Redis commands when speaking RESP
+#[test]
+fn server_speaks_resp() {
+    let temp_dir = TempDir::new().unwrap();
+    let server =
+        BackgroundServer::start_with_protocol(EngineType::Kvs, temp_dir.path(), Protocol::Resp)
+            .unwrap();
+    let addr = server.local_addr();
+
+    let run = || -> Result<Vec<(Value, Value)>> {
+        let stream = TcpStream::connect(addr)?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = stream;
+        let mut exchange = |args: &[&str]| -> Result<Value> {
+            writer.write_all(&Value::command(args.iter().copied()).encode())?;
+            Ok(Value::read_from(&mut reader)?.expect("server closed the connection"))
+        };
+
+        let mut replies = vec![
+            (
+                exchange(&["PING"])?,
+                Value::SimpleString(String::from("PONG")),
+            ),
+            (exchange(&["ping", "hi"])?, Value::bulk("hi")),
+            (exchange(&["GET", "key1"])?, Value::BulkString(None)),
+            (exchange(&["SET", "key1", "value1"])?, ok()),
+            (exchange(&["GET", "key1"])?, Value::bulk("value1")),
+            (
+                exchange(&["SET", "key1", "x", "NX"])?,
+                Value::BulkString(None),
+            ),
+            (
+                exchange(&["SET", "key3", "value3", "XX"])?,
+                Value::BulkString(None),
+            ),
+            (exchange(&["SET", "key4", "value4", "EX", "100"])?, ok()),
+            (
+                exchange(&["SET", "key4", "x", "NX", "EX", "100"])?,
+                Value::BulkString(None),
+            ),
+            (
+                exchange(&["SET", "key4", "value4", "XX", "PX", "100000"])?,
+                ok(),
+            ),
+            (
+                exchange(&["SET", "missing", "x", "XX", "EX", "100"])?,
+                Value::BulkString(None),
+            ),
+            (
+                exchange(&["MSET", "key2", "value2", "key3", "value3"])?,
+                ok(),
+            ),
+            (
+                exchange(&["MGET", "key1", "missing", "key3"])?,
+                Value::Array(Some(vec![
+                    Value::bulk("value1"),
+                    Value::BulkString(None),
+                    Value::bulk("value3"),
+                ])),
+            ),
+            (
+                exchange(&["EXISTS", "key1", "missing", "key1"])?,
+                Value::Integer(2),
+            ),
+            (exchange(&["DBSIZE"])?, Value::Integer(4)),
+            (
+                exchange(&["DEL", "key1", "missing", "key2"])?,
+                Value::Integer(2),
+            ),
+            (exchange(&["DBSIZE"])?, Value::Integer(2)),
+            (
+                exchange(&["GET"])?,
+                Value::Error(String::from(
+                    "ERR wrong number of arguments for 'get' command",
+                )),
+            ),
+            (
+                exchange(&["SET", "key1", "value1", "EX", "0"])?,
+                Value::Error(String::from("ERR invalid expire time in 'set' command")),
+            ),
+            (
+                exchange(&["NOPE"])?,
+                Value::Error(String::from("ERR unknown command 'nope'")),
+            ),
+        ];
+
+        match exchange(&["INFO"])? {
+            Value::BulkString(Some(info)) => {
+                let info = String::from_utf8(info)?;
+                assert!(info.contains("# Server\r\n"));
+                assert!(info.contains("db0:keys=2\r\n"));
+            }
+            reply => panic!("unexpected INFO reply {:?}", reply),
+        }
+
+        // a conditional set keeps its expiry
+        replies.push((exchange(&["SET", "brief", "x", "NX", "PX", "200"])?, ok()));
+        replies.push((exchange(&["GET", "brief"])?, Value::bulk("x")));
+        thread::sleep(Duration::from_millis(300));
+        replies.push((exchange(&["GET", "brief"])?, Value::BulkString(None)));
+
+        // inline commands, as typed into telnet, work too
+        writer.write_all(b"GET key3\r\n")?;
+        replies.push((
+            Value::read_from(&mut reader)?.unwrap(),
+            Value::bulk("value3"),
+        ));
+
+        // pipelined commands are answered in order
+        writer.write_all(
+            &[
+                Value::command(vec!["SET", "piped", "1"]).encode(),
+                Value::command(vec!["GET", "piped"]).encode(),
+                Value::command(vec!["QUIT"]).encode(),
+            ]
+            .concat(),
+        )?;
+        replies.push((Value::read_from(&mut reader)?.unwrap(), ok()));
+        replies.push((Value::read_from(&mut reader)?.unwrap(), Value::bulk("1")));
+        replies.push((Value::read_from(&mut reader)?.unwrap(), ok()));
+        assert_eq!(Value::read_from(&mut reader)?, None);
+
+        // a command holding anything but bulk strings is refused and the connection closed
+        let stream = TcpStream::connect(addr)?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = stream;
+        writer.write_all(b"*1\r\n*1\r\n$4\r\nPING\r\n")?;
+        match Value::read_from(&mut reader)? {
+            Some(Value::Error(message)) => assert!(message.starts_with("ERR ")),
+            reply => panic!("unexpected reply to a nested command {:?}", reply),
+        }
+        assert_eq!(Value::read_from(&mut reader)?, None);
+
+        Ok(replies)
+    };
+    let result = run();
+    server.stop().unwrap();
+
+    for (reply, expected) in result.unwrap() {
+        assert_eq!(reply, expected);
+    }
+}